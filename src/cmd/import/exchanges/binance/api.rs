@@ -180,6 +180,7 @@ impl<'a> TryFrom<&'a BinanceTrade> for Trade<'a> {
             fee,
             rate: trade.price,
             exchange: Some("Binance".into()),
+            source_id: Some(trade.id.to_string()),
         })
     }
 }
@@ -56,6 +56,7 @@ impl<'a> TryFrom<CsvRecord> for Trade<'a> {
             fee,
             rate: value.price,
             exchange: Some("Binance".into()),
+            source_id: None,
         })
     }
 }
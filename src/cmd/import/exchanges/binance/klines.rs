@@ -0,0 +1,268 @@
+use crate::{
+    cmd::{
+        prices::{self, CoingeckoCache},
+        report::cgt::TimeZone,
+    },
+    currencies::{self, Currency},
+    money::FIAT,
+    trades::{self, Trade, TradeKind},
+};
+use argh::FromArgs;
+use chrono::NaiveDate;
+use color_eyre::eyre;
+use rust_decimal::Decimal;
+use std::{
+    collections::BTreeSet,
+    fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+const API_ENDPOINT: &str = "https://api.binance.com";
+
+/// Fetch the daily prices needed to value crypto to crypto trades from Binance klines, as a
+/// prices csv for `report --prices`
+#[derive(FromArgs, PartialEq, Debug)]
+#[argh(subcommand, name = "binance-prices")]
+pub struct BinancePricesCommand {
+    /// the csv files containing the transactions, e.g. `--txs binance.csv --txs kraken.csv`
+    #[argh(option)]
+    txs: Vec<PathBuf>,
+    /// the currency in which to fetch the prices, defaults to GBP
+    #[argh(option, default = "String::from(\"GBP\")")]
+    currency: String,
+    /// always fetch prices from Binance, instead of using prices cached in ~/.cache/taxc
+    #[argh(switch)]
+    no_cache: bool,
+    /// the time zone in which the days of trades are taken, as for `report --time-zone`: uk or a
+    /// fixed offset from UTC e.g. +01:00. Defaults to UTC.
+    #[argh(option, default = "TimeZone::default()")]
+    time_zone: TimeZone,
+}
+
+impl BinancePricesCommand {
+    pub fn exec(&self) -> color_eyre::Result<()> {
+        let currency = currencies::find(&self.currency)
+            .ok_or_else(|| eyre::eyre!("Unsupported currency {}", self.currency))?;
+        let trades = trades::read_csv_files(&self.txs)?;
+        let cache = if self.no_cache {
+            None
+        } else {
+            CoingeckoCache::default_dir().map(KlinesCache::new)
+        };
+        let records = backfill_prices(
+            &trades,
+            currency,
+            self.time_zone,
+            cache.as_ref(),
+            fetch_klines,
+        )?;
+        crate::utils::write_csv(records, std::io::stdout())
+    }
+}
+
+/// Returns the daily closing price, in the reporting currency, of the quote currency of each
+/// trade between two assets, e.g. BTC in GBP for a trade of ETH for BTC, on the local day of the
+/// trade in the time zone. Fiat quotes are skipped, as Binance doesn't list exchange rates.
+///
+/// Prices are read from the cache if present, otherwise fetched with `fetch` for the Binance
+/// symbol and day, and stored in the cache.
+pub fn backfill_prices<F>(
+    trades: &[Trade],
+    currency: &Currency,
+    time_zone: TimeZone,
+    cache: Option<&KlinesCache>,
+    fetch: F,
+) -> color_eyre::Result<Vec<prices::Record>>
+where
+    F: Fn(&str, NaiveDate) -> color_eyre::Result<String>,
+{
+    // the same quote currency and day as used to value the trade in the report
+    let days = trades
+        .iter()
+        .map(|trade| {
+            let date = time_zone.local_time(trade.date_time).date();
+            match trade.kind {
+                TradeKind::Buy => (trade.sell.currency().code, date),
+                TradeKind::Sell => (trade.buy.currency().code, date),
+            }
+        })
+        .filter(|(quote, _)| *quote != currency.code && !FIAT.contains(quote))
+        .collect::<BTreeSet<_>>();
+
+    days.into_iter()
+        .map(|(base, date)| {
+            let symbol = format!("{}{}", base, currency.code);
+            let rate = match cache.and_then(|cache| cache.read(&symbol, date)) {
+                Some(rate) => rate,
+                None => {
+                    let rate = parse_daily_close(&fetch(&symbol, date)?).ok_or_else(|| {
+                        eyre::eyre!("No {} kline from Binance for {}", symbol, date)
+                    })??;
+                    if let Some(cache) = cache {
+                        if let Err(err) = cache.write(&symbol, date, rate) {
+                            log::warn!("Failed to cache {} price for {}: {}", symbol, date, err)
+                        }
+                    }
+                    rate
+                }
+            };
+            Ok(prices::Record {
+                base_currency: base.to_string(),
+                quote_currency: currency.code.to_string(),
+                date_time: format!("{}T00:00:00Z", date),
+                rate,
+            })
+        })
+        .collect()
+}
+
+/// GET /api/v3/klines
+///
+/// [API Docs](https://github.com/binance/binance-spot-api-docs/blob/master/rest-api.md#klinecandlestick-data)
+///
+/// The daily kline of the symbol for the given day.
+fn fetch_klines(symbol: &str, date: NaiveDate) -> color_eyre::Result<String> {
+    log::info!("Fetching {} kline for {}", symbol, date);
+    let start = date.and_hms(0, 0, 0);
+    let response = ureq::get(&format!("{}/api/v3/klines", API_ENDPOINT))
+        .query("symbol", symbol)
+        .query("interval", "1d")
+        .query("startTime", &start.timestamp_millis().to_string())
+        .query("limit", "1")
+        .call()?;
+    Ok(response.into_string()?)
+}
+
+/// The closing price of the first kline in the response, if any. Each kline is an array of the
+/// open time followed by the open, high, low and close prices as strings.
+fn parse_daily_close(json: &str) -> Option<color_eyre::Result<Decimal>> {
+    let klines: Vec<Vec<serde_json::Value>> = match serde_json::from_str(json) {
+        Ok(klines) => klines,
+        Err(err) => return Some(Err(err.into())),
+    };
+    let kline = klines.first()?;
+    let close = kline
+        .get(4)
+        .and_then(|close| close.as_str())
+        .ok_or_else(|| eyre::eyre!("Invalid kline {:?}", kline))
+        .and_then(|close| Ok(Decimal::from_str(close)?));
+    Some(close)
+}
+
+/// Disk cache of daily closing prices from Binance. Past klines don't change, so entries never
+/// expire.
+pub struct KlinesCache {
+    dir: PathBuf,
+}
+
+impl KlinesCache {
+    pub fn new<P: AsRef<Path>>(dir: P) -> Self {
+        KlinesCache {
+            dir: dir.as_ref().to_path_buf(),
+        }
+    }
+
+    fn path(&self, symbol: &str, date: NaiveDate) -> PathBuf {
+        let file_name = format!("binance-{}-{}.txt", symbol, date);
+        self.dir.join(file_name.to_lowercase())
+    }
+
+    fn read(&self, symbol: &str, date: NaiveDate) -> Option<Decimal> {
+        let rate = fs::read_to_string(self.path(symbol, date)).ok()?;
+        Decimal::from_str(rate.trim()).ok()
+    }
+
+    fn write(&self, symbol: &str, date: NaiveDate, rate: Decimal) -> eyre::Result<()> {
+        fs::create_dir_all(&self.dir)?;
+        fs::write(self.path(symbol, date), rate.to_string())?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{currencies::GBP, money::amount, trades::fixtures::trade};
+    use rust_decimal_macros::dec;
+    use std::cell::RefCell;
+
+    fn swap<'a>(date: &str) -> Trade<'a> {
+        trade(
+            date,
+            TradeKind::Sell,
+            amount("ETH", dec!(1)),
+            amount("BTC", dec!(0.0255)),
+        )
+    }
+
+    const KLINES: &str = r#"[
+        [1590969600000, "7400.00000000", "7600.00000000", "7350.00000000", "7500.50000000",
+         "12.5", 1591055999999, "93756.25", 100, "6.2", "46503.1", "0"]
+    ]"#;
+
+    #[test]
+    fn backfills_price_of_quote_currency_from_klines() {
+        let fetched = RefCell::new(Vec::new());
+        let fetch = |symbol: &str, date| {
+            fetched.borrow_mut().push(format!("{} {}", symbol, date));
+            Ok(KLINES.to_string())
+        };
+        let trades = vec![swap("2020-06-01"), swap("2020-06-01")];
+
+        let records = backfill_prices(&trades, GBP, TimeZone::default(), None, fetch).unwrap();
+
+        assert_eq!(*fetched.borrow(), vec!["BTCGBP 2020-06-01".to_string()]);
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].base_currency, "BTC");
+        assert_eq!(records[0].quote_currency, "GBP");
+        assert_eq!(records[0].date_time, "2020-06-01T00:00:00Z");
+        assert_eq!(records[0].rate, dec!(7500.50));
+    }
+
+    #[test]
+    fn backfills_local_day_and_skips_fiat_quotes() {
+        let fetched = RefCell::new(Vec::new());
+        let fetch = |symbol: &str, date| {
+            fetched.borrow_mut().push(format!("{} {}", symbol, date));
+            Ok(KLINES.to_string())
+        };
+        let late = Trade {
+            date_time: NaiveDate::from_ymd(2020, 6, 1).and_hms(23, 30, 0),
+            ..swap("2020-06-01")
+        };
+        let eur = trade(
+            "2020-06-01",
+            TradeKind::Sell,
+            amount("ETH", dec!(1)),
+            amount("EUR", dec!(200)),
+        );
+        let time_zone = "+01:00".parse().unwrap();
+
+        let records = backfill_prices(&[late, eur], GBP, time_zone, None, fetch).unwrap();
+
+        assert_eq!(*fetched.borrow(), vec!["BTCGBP 2020-06-02".to_string()]);
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].date_time, "2020-06-02T00:00:00Z");
+    }
+
+    #[test]
+    fn missing_kline_is_an_error() {
+        let trades = vec![swap("2020-06-01")];
+
+        let err = backfill_prices(&trades, GBP, TimeZone::default(), None, |_, _| {
+            Ok("[]".into())
+        })
+        .unwrap_err();
+
+        assert_eq!(
+            err.to_string(),
+            "No BTCGBP kline from Binance for 2020-06-01"
+        );
+    }
+
+    #[test]
+    fn parses_close_price() {
+        assert_eq!(parse_daily_close(KLINES).unwrap().unwrap(), dec!(7500.5));
+    }
+}
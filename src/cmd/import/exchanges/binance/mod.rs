@@ -1,4 +1,5 @@
 mod api;
 mod csv;
+mod klines;
 
-pub use self::{api::BinanceApiCommand, csv::CsvRecord};
+pub use self::{api::BinanceApiCommand, csv::CsvRecord, klines::BinancePricesCommand};
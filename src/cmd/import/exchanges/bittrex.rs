@@ -59,6 +59,7 @@ impl<'a> TryFrom<Record> for Trade<'a> {
             fee,
             rate: value.limit,
             exchange: Some("Bittrex".into()),
+            source_id: None,
             kind,
         })
     }
@@ -3,7 +3,7 @@ use serde::Deserialize;
 use std::convert::TryFrom;
 
 use crate::{
-    money::amount,
+    money::{amount, currencies},
     trades::{Trade, TradeKind},
 };
 use rust_decimal::Decimal;
@@ -11,12 +11,12 @@ use rust_decimal::Decimal;
 // trade id,product,side,created at,size,size unit,price,fee,total,price/fee/total unit
 // 155157,ETH-GBP,SELL,2018-11-20T21:39:45.667Z,5.41307455,ETH,101.86,1.654127320989,549.721646342011,GBP
 
+/// A trade from a Coinbase Pro trades export, or a fill from a Coinbase Advanced Trade export
 #[derive(Debug, Deserialize, Clone)]
 #[allow(non_snake_case)]
 pub struct Record {
     #[serde(rename = "trade id")]
     trade_id: String,
-    product: String,
     side: String,
     #[serde(rename = "created at")]
     created_at: String,
@@ -30,6 +30,10 @@ pub struct Record {
     unit: String,
 }
 
+// Coinbase Advanced Trade (formerly Coinbase Pro) fills exports have an additional leading column
+// portfolio,trade id,product,side,created at,size,size unit,price,fee,total,price/fee/total unit
+// default,8a3b1c2d,ETH-BTC,BUY,2023-01-05T12:34:56.789Z,0.5,ETH,0.07,0.0000875,-0.0350875,BTC
+
 impl<'a> TryFrom<Record> for Trade<'a> {
     type Error = super::ExchangeError;
 
@@ -38,17 +42,21 @@ impl<'a> TryFrom<Record> for Trade<'a> {
         let date_time =
             NaiveDateTime::parse_from_str(value.created_at.as_ref(), "%Y-%m-%dT%H:%M:%S%.fZ")?;
 
-        let mut market_parts = value.product.split('-');
-        let base_currency = market_parts.next().expect("base currency");
-        let quote_currency = market_parts.next().expect("quote currency");
-
-        let base_amount = amount(base_currency, value.size);
-        let quote_amount = amount(quote_currency, value.total);
+        // the product is only informational, the units are authoritative for the currencies
+        for code in &[&value.size_unit, &value.unit] {
+            if currencies::find(code).is_none() {
+                log::error!("Unsupported Coinbase currency {}", code);
+                return Err("Unsupported Coinbase currency".into());
+            }
+        }
+        let base_amount = amount(value.size_unit.as_ref(), value.size);
+        // the total is negative for buys
+        let quote_amount = amount(value.unit.as_ref(), value.total.abs());
 
         let (kind, sell, buy) = match value.side.as_ref() {
-            "BUY" => (TradeKind::Buy, quote_amount * -1, base_amount),
+            "BUY" => (TradeKind::Buy, quote_amount, base_amount),
             "SELL" => (TradeKind::Sell, base_amount, quote_amount),
-            _ => panic!("Invalid order_type {}", value.side),
+            _ => return Err(super::ExchangeError::InvalidRecord("Invalid fill side")),
         };
         let fee = amount(value.unit.as_ref(), value.fee);
 
@@ -60,6 +68,115 @@ impl<'a> TryFrom<Record> for Trade<'a> {
             fee,
             rate: value.price,
             exchange: Some("Coinbase Pro".into()),
+            source_id: Some(value.trade_id),
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal_macros::dec;
+
+    const FILLS: &str = "\
+portfolio,trade id,product,side,created at,size,size unit,price,fee,total,price/fee/total unit
+default,1001,BTC-GBP,BUY,2021-03-01T10:15:30.123Z,0.1,BTC,40000,20,-4020,GBP
+default,1002,ETH-BTC,SELL,2021-03-02T11:00:00Z,2,ETH,0.03,0.00015,0.05985,BTC
+";
+
+    fn import() -> Vec<Trade<'static>> {
+        let mut rdr = csv::Reader::from_reader(FILLS.as_bytes());
+        rdr.deserialize::<Record>()
+            .map(|record| Trade::try_from(record.unwrap()).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn btc_gbp_fill() {
+        let trades = import();
+
+        let trade = &trades[0];
+        assert_eq!(trade.kind, TradeKind::Buy);
+        assert_eq!(trade.date_time.to_string(), "2021-03-01 10:15:30.123");
+        assert_eq!(trade.buy.currency().code, "BTC");
+        assert_eq!(*trade.buy.amount(), dec!(0.1));
+        assert_eq!(trade.sell.currency().code, "GBP");
+        assert_eq!(*trade.sell.amount(), dec!(4020));
+        assert_eq!(trade.fee.currency().code, "GBP");
+        assert_eq!(*trade.fee.amount(), dec!(20));
+        assert_eq!(trade.rate, dec!(40000));
+    }
+
+    #[test]
+    fn unknown_unit_is_an_error() {
+        let fills = "\
+portfolio,trade id,product,side,created at,size,size unit,price,fee,total,price/fee/total unit
+default,1003,XYZ-GBP,BUY,2021-03-03T09:00:00Z,10,XYZ,1,0.01,-10.01,GBP
+";
+        let mut rdr = csv::Reader::from_reader(fills.as_bytes());
+        let record = rdr.deserialize::<Record>().next().unwrap().unwrap();
+
+        assert!(Trade::try_from(record).is_err());
+    }
+
+    #[test]
+    fn eth_btc_fill() {
+        let trades = import();
+
+        let trade = &trades[1];
+        assert_eq!(trade.kind, TradeKind::Sell);
+        assert_eq!(trade.sell.currency().code, "ETH");
+        assert_eq!(*trade.sell.amount(), dec!(2));
+        assert_eq!(trade.buy.currency().code, "BTC");
+        assert_eq!(*trade.buy.amount(), dec!(0.05985));
+        assert_eq!(trade.fee.currency().code, "BTC");
+        assert_eq!(*trade.fee.amount(), dec!(0.00015));
+        assert_eq!(trade.rate, dec!(0.03));
+    }
+
+    #[test]
+    fn trades_export_without_portfolio() {
+        let trades = "\
+trade id,product,side,created at,size,size unit,price,fee,total,price/fee/total unit
+155157,ETH-GBP,SELL,2018-11-20T21:39:45.667Z,5.41307455,ETH,101.86,1.654127320989,549.721646342011,GBP
+";
+        let mut rdr = csv::Reader::from_reader(trades.as_bytes());
+        let trade = Trade::try_from(rdr.deserialize::<Record>().next().unwrap().unwrap()).unwrap();
+
+        assert_eq!(trade.kind, TradeKind::Sell);
+        assert_eq!(trade.sell.currency().code, "ETH");
+        assert_eq!(*trade.sell.amount(), dec!(5.41307455));
+        assert_eq!(trade.buy.currency().code, "GBP");
+        assert_eq!(*trade.buy.amount(), dec!(549.72));
+        assert_eq!(trade.source_id.as_deref(), Some("155157"));
+    }
+
+    #[test]
+    fn trade_id_is_traced_to_disposal() {
+        use crate::{
+            cmd::{prices::Prices, report::cgt},
+            trades::{self, TradeRecord},
+        };
+        let fills = "\
+portfolio,trade id,product,side,created at,size,size unit,price,fee,total,price/fee/total unit
+default,1001,BTC-GBP,BUY,2021-03-01T10:15:30.123Z,0.1,BTC,40000,20,-4020,GBP
+default,1003,BTC-GBP,SELL,2021-03-05T09:00:00Z,0.1,BTC,45000,22.5,4477.5,GBP
+";
+        let mut rdr = csv::Reader::from_reader(fills.as_bytes());
+        let records = rdr
+            .deserialize::<Record>()
+            .map(|record| TradeRecord::from(&Trade::try_from(record.unwrap()).unwrap()))
+            .collect::<Vec<_>>();
+        let mut imported = Vec::new();
+        crate::utils::write_csv(records, &mut imported).unwrap();
+
+        let trades = trades::read_csv(imported.as_slice()).unwrap();
+        let prices = Prices::default();
+        let report = cgt::calculate(trades, &prices, &cgt::Config::default()).unwrap();
+        let mut output = Vec::new();
+        report.gains(None).write_json(&mut output).unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&output).unwrap();
+
+        assert_eq!(json[0]["source_id"], "1003");
+    }
+}
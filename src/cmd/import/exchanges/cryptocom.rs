@@ -0,0 +1,232 @@
+use chrono::NaiveDateTime;
+use rust_decimal::Decimal;
+use serde::Deserialize;
+
+use super::ExchangeError;
+use crate::{
+    money::{amount, currencies, zero},
+    trades::{Trade, TradeKind},
+};
+
+// Timestamp (UTC),Transaction Description,Currency,Amount,To Currency,To Amount,Native Currency,Native Amount,Native Amount (in USD),Transaction Kind
+// 2021-03-01 10:00:00,Buy BTC,BTC,0.01,,,GBP,400.00,550.00,crypto_purchase
+
+/// Exchange name for rewards, so they can be told apart from purchases in the imported trades.
+pub const REWARDS_EXCHANGE: &str = "Crypto.com Rewards";
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Record {
+    #[serde(rename = "Timestamp (UTC)")]
+    timestamp: String,
+    #[serde(rename = "Currency")]
+    currency: String,
+    #[serde(rename = "Amount")]
+    amount: Decimal,
+    #[serde(rename = "To Currency")]
+    to_currency: String,
+    #[serde(rename = "To Amount")]
+    to_amount: Option<Decimal>,
+    #[serde(rename = "Native Currency")]
+    native_currency: String,
+    #[serde(rename = "Native Amount")]
+    native_amount: Decimal,
+    #[serde(rename = "Transaction Kind")]
+    kind: String,
+}
+
+/// How a Crypto.com transaction kind is imported
+enum Transaction {
+    /// Crypto bought with the native (fiat) currency e.g. with a card
+    Purchase,
+    /// One currency exchanged for another, both given in the record
+    Exchange,
+    /// Crypto received as a reward, acquired at its native market value
+    Reward,
+    /// Deposits, withdrawals and internal transfers which are not disposals or acquisitions
+    Transfer,
+}
+
+impl Transaction {
+    fn from_kind(kind: &str) -> Result<Self, ExchangeError> {
+        match kind {
+            "crypto_purchase" => Ok(Self::Purchase),
+            "viban_purchase" | "crypto_exchange" | "crypto_viban_exchange" => Ok(Self::Exchange),
+            "crypto_earn_interest_paid"
+            | "crypto_earn_extra_interest_paid"
+            | "mco_stake_reward"
+            | "referral_bonus"
+            | "referral_card_cashback"
+            | "reimbursement"
+            | "supercharger_reward_to_app_credited" => Ok(Self::Reward),
+            "crypto_deposit"
+            | "crypto_withdrawal"
+            | "crypto_transfer"
+            | "viban_deposit"
+            | "viban_withdrawal"
+            | "crypto_earn_program_created"
+            | "crypto_earn_program_withdrawn"
+            | "lockup_lock"
+            | "lockup_unlock" => Ok(Self::Transfer),
+            kind => {
+                log::error!("Unsupported Crypto.com transaction kind {}", kind);
+                Err(ExchangeError::InvalidRecord(
+                    "Unsupported Crypto.com transaction kind",
+                ))
+            }
+        }
+    }
+}
+
+/// Converts the purchases, exchanges and rewards of a Crypto.com app export to trades.
+///
+/// Rewards are imported as a purchase at the native value, with the exchange set to
+/// [`REWARDS_EXCHANGE`].
+pub fn transactions_to_trades<'a>(records: Vec<Record>) -> Result<Vec<Trade<'a>>, ExchangeError> {
+    let mut trades = Vec::new();
+    for record in records {
+        let trade = match Transaction::from_kind(&record.kind)? {
+            Transaction::Purchase => native_purchase(&record, "Crypto.com")?,
+            Transaction::Reward => native_purchase(&record, REWARDS_EXCHANGE)?,
+            Transaction::Exchange => exchange(&record)?,
+            Transaction::Transfer => continue,
+        };
+        trades.push(trade)
+    }
+    trades.sort_by(|tx1, tx2| tx1.date_time.cmp(&tx2.date_time));
+    Ok(trades)
+}
+
+fn parse_date_time(record: &Record) -> Result<NaiveDateTime, ExchangeError> {
+    Ok(NaiveDateTime::parse_from_str(
+        &record.timestamp,
+        "%Y-%m-%d %H:%M:%S",
+    )?)
+}
+
+fn check_currency(code: &str) -> Result<(), ExchangeError> {
+    if currencies::find(code).is_none() {
+        log::error!("Unsupported Crypto.com currency {}", code);
+        return Err("Unsupported Crypto.com currency".into());
+    }
+    Ok(())
+}
+
+fn native_purchase<'a>(record: &Record, exchange: &str) -> Result<Trade<'a>, ExchangeError> {
+    if record.native_currency != "GBP" {
+        return Err("Crypto.com native currency must be GBP".into());
+    }
+    check_currency(&record.currency)?;
+    let buy = amount(&record.currency, record.amount);
+    let sell = amount(&record.native_currency, record.native_amount);
+    Ok(Trade {
+        date_time: parse_date_time(record)?,
+        kind: TradeKind::Buy,
+        rate: *sell.amount() / *buy.amount(),
+        fee: zero(sell.currency()),
+        buy,
+        sell,
+        exchange: Some(exchange.into()),
+        source_id: None,
+    })
+}
+
+fn exchange<'a>(record: &Record) -> Result<Trade<'a>, ExchangeError> {
+    let to_amount = record.to_amount.ok_or(ExchangeError::InvalidRecord(
+        "Crypto.com exchange without To Amount",
+    ))?;
+    check_currency(&record.currency)?;
+    check_currency(&record.to_currency)?;
+    let sell = amount(&record.currency, record.amount.abs());
+    let buy = amount(&record.to_currency, to_amount);
+    // fiat purchases are quoted in fiat, other exchanges are a sale of the original currency
+    let (kind, rate) = if record.currency == record.native_currency {
+        (TradeKind::Buy, *sell.amount() / *buy.amount())
+    } else {
+        (TradeKind::Sell, *buy.amount() / *sell.amount())
+    };
+    Ok(Trade {
+        date_time: parse_date_time(record)?,
+        kind,
+        rate,
+        fee: zero(sell.currency()),
+        buy,
+        sell,
+        exchange: Some("Crypto.com".into()),
+        source_id: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal_macros::dec;
+
+    const HEADER: &str = "Timestamp (UTC),Transaction Description,Currency,Amount,To Currency,To Amount,Native Currency,Native Amount,Native Amount (in USD),Transaction Kind\n";
+
+    fn import(rows: &str) -> Result<Vec<Trade<'static>>, ExchangeError> {
+        let csv = format!("{}{}", HEADER, rows);
+        let mut rdr = csv::Reader::from_reader(csv.as_bytes());
+        let records = rdr
+            .deserialize()
+            .collect::<Result<Vec<Record>, _>>()
+            .unwrap();
+        transactions_to_trades(records)
+    }
+
+    #[test]
+    fn purchase_is_bought_with_native_currency() {
+        let trades = import(
+            "2021-03-01 09:00:00,Deposit GBP,GBP,500,,,GBP,500,690,viban_deposit\n\
+             2021-03-01 10:00:00,Buy BTC,BTC,0.01,,,GBP,400.00,550.00,crypto_purchase\n",
+        )
+        .unwrap();
+
+        assert_eq!(trades.len(), 1, "Deposit should be ignored");
+        let trade = &trades[0];
+        assert_eq!(trade.kind, TradeKind::Buy);
+        assert_eq!(trade.buy.currency().code, "BTC");
+        assert_eq!(*trade.buy.amount(), dec!(0.01));
+        assert_eq!(trade.sell.currency().code, "GBP");
+        assert_eq!(*trade.sell.amount(), dec!(400));
+        assert_eq!(trade.rate, dec!(40000));
+        assert_eq!(trade.exchange.as_deref(), Some("Crypto.com"));
+    }
+
+    #[test]
+    fn exchange_sells_original_currency() {
+        let trades = import(
+            "2021-03-02 10:00:00,ETH -> BTC,ETH,-1.5,BTC,0.06,GBP,1500.00,2070.00,crypto_exchange\n",
+        )
+        .unwrap();
+
+        let trade = &trades[0];
+        assert_eq!(trade.kind, TradeKind::Sell);
+        assert_eq!(trade.sell.currency().code, "ETH");
+        assert_eq!(*trade.sell.amount(), dec!(1.5));
+        assert_eq!(trade.buy.currency().code, "BTC");
+        assert_eq!(*trade.buy.amount(), dec!(0.06));
+        assert_eq!(trade.rate, dec!(0.04));
+    }
+
+    #[test]
+    fn crypto_earn_reward_is_acquired_at_native_value() {
+        let trades = import(
+            "2021-03-03 10:00:00,Crypto Earn,ETH,0.01,,,GBP,10.00,13.80,crypto_earn_interest_paid\n",
+        )
+        .unwrap();
+
+        let trade = &trades[0];
+        assert_eq!(trade.kind, TradeKind::Buy);
+        assert_eq!(trade.buy.currency().code, "ETH");
+        assert_eq!(*trade.buy.amount(), dec!(0.01));
+        assert_eq!(*trade.sell.amount(), dec!(10));
+        assert_eq!(trade.exchange.as_deref(), Some(REWARDS_EXCHANGE));
+    }
+
+    #[test]
+    fn unknown_kind_is_an_error() {
+        let result = import("2021-03-04 10:00:00,Mystery,BTC,0.01,,,GBP,400.00,550.00,mystery\n");
+
+        assert!(result.is_err());
+    }
+}
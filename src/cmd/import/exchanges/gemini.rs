@@ -0,0 +1,206 @@
+use chrono::NaiveDateTime;
+use rust_decimal::{prelude::Zero, Decimal};
+use serde::Deserialize;
+use std::{collections::HashMap, convert::TryFrom, str::FromStr};
+
+use super::ExchangeError;
+use crate::{
+    money::{amount, currencies},
+    trades::{Trade, TradeKind},
+};
+
+// Date,Time (UTC),Type,Symbol,Specification,Liquidity Indicator,Trading Fee Rate (bps),GBP Amount GBP,Fee (GBP) GBP,GBP Balance GBP,BTC Amount BTC,Fee (BTC) BTC,BTC Balance BTC,Trade ID,Order ID
+// 2021-02-01,10:00:00.123,Buy,BTCGBP,Flash Buy,Taker,100,(£1000.00),(£10.00),£0.00,0.05 BTC,,0.05 BTC,123456789,987654321
+
+/// A row of the Gemini transaction history export.
+///
+/// The export has a set of amount, fee and balance columns for each currency, so the row is kept
+/// as a map of header to value.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(transparent)]
+pub struct Record(HashMap<String, String>);
+
+impl Record {
+    fn get(&self, column: &str) -> Result<&str, ExchangeError> {
+        self.0
+            .get(column)
+            .map(|value| value.trim())
+            .ok_or(ExchangeError::InvalidRecord("Missing Gemini column"))
+    }
+
+    fn is_trade(&self) -> bool {
+        matches!(self.get("Type"), Ok("Buy") | Ok("Sell"))
+    }
+
+    /// The non zero amounts of the currency columns e.g. `BTC Amount BTC`, with their fees.
+    fn currency_amounts(&self) -> Result<Vec<(String, Decimal, Decimal)>, ExchangeError> {
+        let mut amounts = Vec::new();
+        for (column, value) in self.0.iter() {
+            let code = match column.split(" Amount ").collect::<Vec<_>>().as_slice() {
+                [code, unit] if code == unit => code.to_string(),
+                _ => continue,
+            };
+            let value = parse_amount(value)?;
+            if value.is_zero() {
+                continue;
+            }
+            let fee = self
+                .0
+                .iter()
+                .find(|(column, _)| column.ends_with(&format!("Fee ({}) {}", code, code)))
+                .map(|(_, fee)| parse_amount(fee))
+                .transpose()?
+                .unwrap_or_default();
+            amounts.push((code, value, fee));
+        }
+        Ok(amounts)
+    }
+}
+
+/// Parses a formatted Gemini amount e.g. `(£1,000.00)` or `0.05 BTC`, where parentheses denote a
+/// negative amount.
+fn parse_amount(value: &str) -> Result<Decimal, ExchangeError> {
+    let value = value.trim();
+    let (negative, value) = if value.starts_with('(') && value.ends_with(')') {
+        (true, &value[1..value.len() - 1])
+    } else {
+        (false, value)
+    };
+    let digits = value
+        .chars()
+        .filter(|c| c.is_ascii_digit() || *c == '.' || *c == '-')
+        .collect::<String>();
+    if digits.is_empty() {
+        return Ok(Decimal::zero());
+    }
+    let amount = Decimal::from_str(&digits)?;
+    Ok(if negative { -amount } else { amount })
+}
+
+/// Converts the buy and sell rows of a Gemini transaction history to trades, ignoring deposits,
+/// withdrawals and the totals row.
+pub fn history_to_trades<'a>(records: Vec<Record>) -> Result<Vec<Trade<'a>>, ExchangeError> {
+    let mut trades = records
+        .into_iter()
+        .filter(Record::is_trade)
+        .map(Trade::try_from)
+        .collect::<Result<Vec<_>, _>>()?;
+    trades.sort_by(|tx1, tx2| tx1.date_time.cmp(&tx2.date_time));
+    Ok(trades)
+}
+
+impl<'a> TryFrom<Record> for Trade<'a> {
+    type Error = ExchangeError;
+
+    fn try_from(value: Record) -> Result<Trade<'a>, Self::Error> {
+        let date_time = format!("{} {}", value.get("Date")?, value.get("Time (UTC)")?);
+        let date_time = NaiveDateTime::parse_from_str(&date_time, "%Y-%m-%d %H:%M:%S%.f")?;
+
+        let amounts = value.currency_amounts()?;
+        let (sold, bought) = match amounts.as_slice() {
+            [a, b] if a.1 < Decimal::zero() && b.1 > Decimal::zero() => (a, b),
+            [a, b] if b.1 < Decimal::zero() && a.1 > Decimal::zero() => (b, a),
+            _ => {
+                return Err(ExchangeError::InvalidRecord(
+                    "Expected Gemini trade to have one negative and one positive amount",
+                ))
+            }
+        };
+        for (code, _, _) in &[sold, bought] {
+            if currencies::find(code).is_none() {
+                log::error!("Unsupported Gemini currency {}", code);
+                return Err("Unsupported Gemini currency".into());
+            }
+        }
+
+        let sell = amount(&sold.0, sold.1.abs());
+        let buy = amount(&bought.0, bought.1);
+
+        // fees are charged in a single currency
+        let fee = match (sold.2.is_zero(), bought.2.is_zero()) {
+            (_, true) => amount(&sold.0, sold.2.abs()),
+            (true, false) => amount(&bought.0, bought.2.abs()),
+            (false, false) => return Err("Gemini trade with fees in both currencies".into()),
+        };
+
+        let (kind, rate) = match value.get("Type")? {
+            "Buy" => (TradeKind::Buy, *sell.amount() / *buy.amount()),
+            "Sell" => (TradeKind::Sell, *buy.amount() / *sell.amount()),
+            _ => return Err("Invalid Gemini trade type".into()),
+        };
+
+        Ok(Trade {
+            date_time,
+            kind,
+            buy,
+            sell,
+            fee,
+            rate,
+            exchange: Some("Gemini".into()),
+            source_id: None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal_macros::dec;
+
+    const HISTORY: &str = "\
+Date,Time (UTC),Type,Symbol,Specification,Liquidity Indicator,Trading Fee Rate (bps),GBP Amount GBP,Fee (GBP) GBP,GBP Balance GBP,BTC Amount BTC,Fee (BTC) BTC,BTC Balance BTC,ETH Amount ETH,Fee (ETH) ETH,ETH Balance ETH,Trade ID,Order ID
+2021-02-01,09:00:00.000,Credit,GBP,Deposit,,,\"£2,000.00\",,\"£2,000.00\",,,,,,,,
+2021-02-01,10:00:00.123,Buy,BTCGBP,Flash Buy,Taker,100,\"(£1,000.00)\",(£10.00),£990.00,0.05 BTC,,0.05 BTC,,,,123456789,987654321
+2021-02-02,11:30:00.000,Sell,ETHBTC,Limit,Maker,25,,,£990.00,0.04 BTC,(0.00001 BTC),0.08999 BTC,(1.0 ETH),,0.0 ETH,123456790,987654322
+,,,,,,,\"£990.00\",,,,,,,,,,
+";
+
+    fn import() -> Vec<Trade<'static>> {
+        let mut rdr = csv::Reader::from_reader(HISTORY.as_bytes());
+        let records = rdr
+            .deserialize()
+            .collect::<Result<Vec<Record>, _>>()
+            .unwrap();
+        history_to_trades(records).unwrap()
+    }
+
+    #[test]
+    fn parses_formatted_amounts() {
+        assert_eq!(parse_amount("(£1,000.00)").unwrap(), dec!(-1000));
+        assert_eq!(parse_amount("0.05 BTC").unwrap(), dec!(0.05));
+        assert_eq!(parse_amount("").unwrap(), dec!(0));
+    }
+
+    #[test]
+    fn buy_with_fiat_fee() {
+        let trades = import();
+
+        assert_eq!(trades.len(), 2, "Deposit and totals should be ignored");
+
+        let trade = &trades[0];
+        assert_eq!(trade.kind, TradeKind::Buy);
+        assert_eq!(trade.date_time.to_string(), "2021-02-01 10:00:00.123");
+        assert_eq!(trade.buy.currency().code, "BTC");
+        assert_eq!(*trade.buy.amount(), dec!(0.05));
+        assert_eq!(trade.sell.currency().code, "GBP");
+        assert_eq!(*trade.sell.amount(), dec!(1000));
+        assert_eq!(trade.fee.currency().code, "GBP");
+        assert_eq!(*trade.fee.amount(), dec!(10));
+        assert_eq!(trade.rate, dec!(20000));
+    }
+
+    #[test]
+    fn sell_with_fee_in_bought_currency() {
+        let trades = import();
+
+        let trade = &trades[1];
+        assert_eq!(trade.kind, TradeKind::Sell);
+        assert_eq!(trade.sell.currency().code, "ETH");
+        assert_eq!(*trade.sell.amount(), dec!(1));
+        assert_eq!(trade.buy.currency().code, "BTC");
+        assert_eq!(*trade.buy.amount(), dec!(0.04));
+        assert_eq!(trade.fee.currency().code, "BTC");
+        assert_eq!(*trade.fee.amount(), dec!(0.00001));
+        assert_eq!(trade.rate, dec!(0.04));
+    }
+}
@@ -0,0 +1,279 @@
+use chrono::{DateTime, NaiveDateTime};
+use rust_decimal::Decimal;
+use std::{collections::HashMap, str::FromStr};
+
+use super::ExchangeError;
+use crate::{
+    money::{amount, currencies, zero, FIAT},
+    trades::{Trade, TradeKind},
+};
+
+/// A row of a csv file with arbitrary headers
+pub type Record = HashMap<String, String>;
+
+/// The logical fields of a trade which can be mapped to csv headers
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub enum Field {
+    Date,
+    SoldAsset,
+    SoldQty,
+    BoughtAsset,
+    BoughtQty,
+    FeeAsset,
+    FeeQty,
+}
+
+impl Field {
+    const REQUIRED: [Field; 5] = [
+        Field::Date,
+        Field::SoldAsset,
+        Field::SoldQty,
+        Field::BoughtAsset,
+        Field::BoughtQty,
+    ];
+
+    fn name(&self) -> &'static str {
+        match self {
+            Field::Date => "date",
+            Field::SoldAsset => "sold_asset",
+            Field::SoldQty => "sold_qty",
+            Field::BoughtAsset => "bought_asset",
+            Field::BoughtQty => "bought_qty",
+            Field::FeeAsset => "fee_asset",
+            Field::FeeQty => "fee_qty",
+        }
+    }
+}
+
+impl FromStr for Field {
+    type Err = ExchangeError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "date" => Ok(Field::Date),
+            "sold_asset" => Ok(Field::SoldAsset),
+            "sold_qty" => Ok(Field::SoldQty),
+            "bought_asset" => Ok(Field::BoughtAsset),
+            "bought_qty" => Ok(Field::BoughtQty),
+            "fee_asset" => Ok(Field::FeeAsset),
+            "fee_qty" => Ok(Field::FeeQty),
+            f => Err(ExchangeError::InvalidMapping(format!(
+                "Unknown field '{}'",
+                f
+            ))),
+        }
+    }
+}
+
+/// Maps the logical fields of a trade to the headers of a csv file
+#[derive(Clone, Debug)]
+pub struct ColumnMapping {
+    headers: HashMap<Field, String>,
+}
+
+impl ColumnMapping {
+    /// Parses mappings of the form `field=Header`, checking that all the required fields are
+    /// mapped. The fee fields must either both be mapped or both be omitted.
+    pub fn parse<S: AsRef<str>>(mappings: &[S]) -> Result<Self, ExchangeError> {
+        let mut headers = HashMap::new();
+        for mapping in mappings {
+            let mapping = mapping.as_ref();
+            let mut parts = mapping.splitn(2, '=');
+            let (field, header) = match (parts.next(), parts.next()) {
+                (Some(field), Some(header)) if !header.is_empty() => (field, header),
+                _ => {
+                    return Err(ExchangeError::InvalidMapping(format!(
+                        "Expected field=Header, got '{}'",
+                        mapping
+                    )))
+                }
+            };
+            headers.insert(field.trim().parse()?, header.to_string());
+        }
+        let missing = Field::REQUIRED
+            .iter()
+            .filter(|field| !headers.contains_key(*field))
+            .map(Field::name)
+            .collect::<Vec<_>>();
+        if !missing.is_empty() {
+            return Err(ExchangeError::InvalidMapping(format!(
+                "Missing mapping for required fields: {}",
+                missing.join(", ")
+            )));
+        }
+        if headers.contains_key(&Field::FeeAsset) != headers.contains_key(&Field::FeeQty) {
+            return Err(ExchangeError::InvalidMapping(
+                "Both fee_asset and fee_qty must be mapped".into(),
+            ));
+        }
+        Ok(ColumnMapping { headers })
+    }
+
+    fn get<'r>(&self, record: &'r Record, field: Field) -> Result<Option<&'r str>, ExchangeError> {
+        match self.headers.get(&field) {
+            Some(header) => record
+                .get(header)
+                .map(|value| Some(value.trim()))
+                .ok_or_else(|| {
+                    ExchangeError::InvalidMapping(format!(
+                        "Column '{}' mapped to {} not found",
+                        header,
+                        field.name()
+                    ))
+                }),
+            None => Ok(None),
+        }
+    }
+
+    fn required<'r>(&self, record: &'r Record, field: Field) -> Result<&'r str, ExchangeError> {
+        self.get(record, field)?.ok_or_else(|| {
+            ExchangeError::InvalidMapping(format!("Field {} is not mapped", field.name()))
+        })
+    }
+}
+
+fn parse_date_time(s: &str) -> Result<NaiveDateTime, ExchangeError> {
+    if let Ok(date_time) = DateTime::parse_from_rfc3339(s) {
+        return Ok(date_time.naive_utc());
+    }
+    Ok(NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M:%S%.f")?)
+}
+
+fn parse_amount<'a>(asset: &str, qty: &str) -> Result<crate::Money<'a>, ExchangeError> {
+    if currencies::find(asset).is_none() {
+        log::error!("Unsupported currency {}", asset);
+        return Err("Unsupported currency".into());
+    }
+    let qty = Decimal::from_str(&qty.replace(',', ""))?;
+    Ok(amount(asset, qty.abs()))
+}
+
+/// Converts the rows of a csv file to trades using the given column mapping.
+///
+/// Trades where fiat is sold are imported as buys, all others as sells.
+pub fn records_to_trades<'a>(
+    records: Vec<Record>,
+    mapping: &ColumnMapping,
+) -> Result<Vec<Trade<'a>>, ExchangeError> {
+    let mut trades = records
+        .iter()
+        .map(|record| record_to_trade(record, mapping))
+        .collect::<Result<Vec<_>, _>>()?;
+    trades.sort_by(|tx1, tx2| tx1.date_time.cmp(&tx2.date_time));
+    Ok(trades)
+}
+
+fn record_to_trade<'a>(
+    record: &Record,
+    mapping: &ColumnMapping,
+) -> Result<Trade<'a>, ExchangeError> {
+    let date_time = parse_date_time(mapping.required(record, Field::Date)?)?;
+    let sell = parse_amount(
+        mapping.required(record, Field::SoldAsset)?,
+        mapping.required(record, Field::SoldQty)?,
+    )?;
+    let buy = parse_amount(
+        mapping.required(record, Field::BoughtAsset)?,
+        mapping.required(record, Field::BoughtQty)?,
+    )?;
+    let fee = match (
+        mapping.get(record, Field::FeeAsset)?,
+        mapping.get(record, Field::FeeQty)?,
+    ) {
+        (Some(asset), Some(qty)) if !asset.is_empty() && !qty.is_empty() => {
+            parse_amount(asset, qty)?
+        }
+        _ => zero(sell.currency()),
+    };
+    let (kind, rate) = if FIAT.contains(&sell.currency().code) {
+        (TradeKind::Buy, *sell.amount() / *buy.amount())
+    } else {
+        (TradeKind::Sell, *buy.amount() / *sell.amount())
+    };
+    Ok(Trade {
+        date_time,
+        kind,
+        buy,
+        sell,
+        fee,
+        rate,
+        exchange: None,
+        source_id: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal_macros::dec;
+
+    const CSV: &str = "\
+When,Paid With,Paid,Received In,Received,Commission,Commission Currency
+2021-04-02 12:00:00,ETH,\"1.5\",BTC,0.06,0.0001,BTC
+2021-04-01T10:00:00Z,GBP,\"1,000.00\",BTC,0.025,,
+";
+
+    const MAPPING: [&str; 7] = [
+        "date=When",
+        "sold_asset=Paid With",
+        "sold_qty=Paid",
+        "bought_asset=Received In",
+        "bought_qty=Received",
+        "fee_asset=Commission Currency",
+        "fee_qty=Commission",
+    ];
+
+    fn import(mapping: &ColumnMapping) -> Result<Vec<Trade<'static>>, ExchangeError> {
+        let mut rdr = csv::Reader::from_reader(CSV.as_bytes());
+        let records = rdr
+            .deserialize()
+            .collect::<Result<Vec<Record>, _>>()
+            .unwrap();
+        records_to_trades(records, mapping)
+    }
+
+    #[test]
+    fn imports_custom_headers() {
+        let mapping = ColumnMapping::parse(&MAPPING).unwrap();
+        let trades = import(&mapping).unwrap();
+
+        assert_eq!(trades.len(), 2);
+
+        let buy = &trades[0];
+        assert_eq!(buy.kind, TradeKind::Buy);
+        assert_eq!(buy.date_time.to_string(), "2021-04-01 10:00:00");
+        assert_eq!(buy.sell.currency().code, "GBP");
+        assert_eq!(*buy.sell.amount(), dec!(1000));
+        assert_eq!(*buy.buy.amount(), dec!(0.025));
+        assert_eq!(buy.fee.currency().code, "GBP");
+        assert_eq!(*buy.fee.amount(), dec!(0));
+        assert_eq!(buy.rate, dec!(40000));
+
+        let sell = &trades[1];
+        assert_eq!(sell.kind, TradeKind::Sell);
+        assert_eq!(sell.sell.currency().code, "ETH");
+        assert_eq!(sell.buy.currency().code, "BTC");
+        assert_eq!(sell.fee.currency().code, "BTC");
+        assert_eq!(*sell.fee.amount(), dec!(0.0001));
+        assert_eq!(sell.rate, dec!(0.04));
+    }
+
+    #[test]
+    fn missing_required_fields_are_reported() {
+        let err = ColumnMapping::parse(&["date=When", "sold_asset=Paid With"]).unwrap_err();
+
+        assert_eq!(
+            err.to_string(),
+            "Missing mapping for required fields: sold_qty, bought_asset, bought_qty"
+        );
+    }
+
+    #[test]
+    fn unknown_header_is_an_error() {
+        let mut mapping = MAPPING.to_vec();
+        mapping[0] = "date=Timestamp";
+        let mapping = ColumnMapping::parse(&mapping).unwrap();
+
+        assert!(import(&mapping).is_err());
+    }
+}
@@ -0,0 +1,196 @@
+use chrono::NaiveDateTime;
+use rust_decimal::{prelude::Zero, Decimal};
+use serde::Deserialize;
+use std::collections::HashMap;
+
+use super::ExchangeError;
+use crate::{
+    money::{amount, currencies},
+    trades::{Trade, TradeKind},
+};
+
+// "txid","refid","time","type","subtype","aclass","asset","amount","fee","balance"
+// "L7RLII-OKJSV-5UXHAN","TJKLXX-PGMUI-4NTLXU","2021-01-01 10:00:00.1234","trade","","currency","ZGBP",-1000.0000,2.6000,0.0000
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct Record {
+    refid: String,
+    time: String,
+    #[serde(rename = "type")]
+    ledger_type: String,
+    asset: String,
+    amount: Decimal,
+    fee: Decimal,
+}
+
+/// Kraken prefixes some legacy asset codes with X (crypto) or Z (fiat), and calls BTC XBT
+fn normalize_asset(asset: &str) -> &str {
+    match asset {
+        "XXBT" | "XBT" => "BTC",
+        "XETH" => "ETH",
+        "XETC" => "ETC",
+        "XXRP" => "XRP",
+        "XREP" => "REP",
+        "ZGBP" => "GBP",
+        "ZEUR" => "EUR",
+        "ZUSD" => "USD",
+        a => a,
+    }
+}
+
+/// Currencies which are the quote currency when paired with a currency later in the list, or
+/// with any currency not in the list.
+const QUOTE_PRIORITY: &[&str] = &["GBP", "EUR", "USD", "USDC", "BTC", "ETH"];
+
+fn quote_priority(code: &str) -> usize {
+    QUOTE_PRIORITY
+        .iter()
+        .position(|c| *c == code)
+        .unwrap_or(QUOTE_PRIORITY.len())
+}
+
+/// Each Kraken trade appears in the ledger as two entries sharing the same refid: a negative
+/// amount for the asset sold and a positive amount for the asset bought. Entries which are not
+/// trades, such as deposits and withdrawals, are ignored.
+pub fn ledger_to_trades<'a>(records: Vec<Record>) -> Result<Vec<Trade<'a>>, ExchangeError> {
+    let mut refids = Vec::new();
+    let mut entries: HashMap<String, Vec<Record>> = HashMap::new();
+    for record in records.into_iter().filter(|r| r.ledger_type == "trade") {
+        if !entries.contains_key(&record.refid) {
+            refids.push(record.refid.clone());
+        }
+        entries
+            .entry(record.refid.clone())
+            .or_default()
+            .push(record);
+    }
+
+    let mut trades = refids
+        .iter()
+        .map(|refid| {
+            let trade_entries = entries.remove(refid).expect("refid was inserted above");
+            ledger_entries_to_trade(trade_entries)
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    trades.sort_by(|tx1, tx2| tx1.date_time.cmp(&tx2.date_time));
+    Ok(trades)
+}
+
+fn ledger_entries_to_trade<'a>(entries: Vec<Record>) -> Result<Trade<'a>, ExchangeError> {
+    if entries.len() != 2 {
+        return Err("A Kraken trade should have exactly two ledger entries".into());
+    }
+    let (sold, bought) = if entries[0].amount < Decimal::zero() {
+        (&entries[0], &entries[1])
+    } else {
+        (&entries[1], &entries[0])
+    };
+    if sold.amount >= Decimal::zero() || bought.amount <= Decimal::zero() {
+        return Err("A Kraken trade should have one negative and one positive entry".into());
+    }
+
+    let sell_code = normalize_asset(&sold.asset);
+    let buy_code = normalize_asset(&bought.asset);
+    for code in &[sell_code, buy_code] {
+        if currencies::find(code).is_none() {
+            log::error!("Unsupported Kraken asset {}", code);
+            return Err("Unsupported Kraken asset".into());
+        }
+    }
+
+    let date_time = NaiveDateTime::parse_from_str(&sold.time, "%Y-%m-%d %H:%M:%S%.f")?;
+
+    let sell = amount(sell_code, sold.amount.abs());
+    let buy = amount(buy_code, bought.amount);
+
+    let fee = match (sold.fee.is_zero(), bought.fee.is_zero()) {
+        (_, true) => amount(sell_code, sold.fee),
+        (true, false) => amount(buy_code, bought.fee),
+        (false, false) => return Err("Kraken trade has fees in both assets".into()),
+    };
+
+    let (kind, rate) = if quote_priority(sell_code) <= quote_priority(buy_code) {
+        (TradeKind::Buy, sold.amount.abs() / bought.amount)
+    } else {
+        (TradeKind::Sell, bought.amount / sold.amount.abs())
+    };
+
+    Ok(Trade {
+        date_time,
+        kind,
+        buy,
+        sell,
+        fee,
+        rate,
+        exchange: Some("Kraken".into()),
+        source_id: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal_macros::dec;
+
+    const LEDGER: &str = r#""txid","refid","time","type","subtype","aclass","asset","amount","fee","balance"
+"L1","D1","2021-01-01 09:00:00","deposit","","currency","ZGBP",1000.0000,0.0000,1000.0000
+"L2","T1","2021-01-01 10:00:00.1234","trade","","currency","ZGBP",-1000.0000,2.6000,-2.6000
+"L3","T1","2021-01-01 10:00:00.1234","trade","","currency","XXBT",0.0500000000,0.0000000000,0.0500000000
+"L4","T2","2021-01-02 10:00:00","trade","","currency","XXBT",-0.0200000000,0.0000400000,0.0299600000
+"L5","T2","2021-01-02 10:00:00","trade","","currency","XETH",0.5000000000,0.0000000000,0.5000000000
+"L6","T3","2021-01-03 10:00:00","trade","","currency","XETH",-0.2500000000,0.0000000000,0.2500000000
+"L7","T3","2021-01-03 10:00:00","trade","","currency","ZGBP",300.0000,0.7800,299.2200
+"#;
+
+    fn import() -> Vec<Trade<'static>> {
+        let mut rdr = csv::Reader::from_reader(LEDGER.as_bytes());
+        let records = rdr
+            .deserialize()
+            .collect::<Result<Vec<Record>, _>>()
+            .unwrap();
+        ledger_to_trades(records).unwrap()
+    }
+
+    #[test]
+    fn pairs_ledger_entries_into_trades() {
+        let trades = import();
+
+        assert_eq!(trades.len(), 3, "Deposit should be ignored");
+
+        let buy = &trades[0];
+        assert_eq!(buy.kind, TradeKind::Buy);
+        assert_eq!(buy.date_time.to_string(), "2021-01-01 10:00:00.123400");
+        assert_eq!(buy.buy.currency().code, "BTC");
+        assert_eq!(*buy.buy.amount(), dec!(0.05));
+        assert_eq!(buy.sell.currency().code, "GBP");
+        assert_eq!(*buy.sell.amount(), dec!(1000));
+        assert_eq!(buy.fee.currency().code, "GBP");
+        assert_eq!(*buy.fee.amount(), dec!(2.6));
+        assert_eq!(buy.rate, dec!(20000));
+    }
+
+    #[test]
+    fn crypto_to_crypto_trade_is_quoted_in_btc() {
+        let trades = import();
+
+        let trade = &trades[1];
+        assert_eq!(trade.kind, TradeKind::Buy);
+        assert_eq!(trade.buy.currency().code, "ETH");
+        assert_eq!(trade.sell.currency().code, "BTC");
+        assert_eq!(*trade.fee.amount(), dec!(0.00004));
+        assert_eq!(trade.fee.currency().code, "BTC");
+        assert_eq!(trade.rate, dec!(0.04));
+    }
+
+    #[test]
+    fn sale_for_fiat_is_a_sell() {
+        let trades = import();
+
+        let trade = &trades[2];
+        assert_eq!(trade.kind, TradeKind::Sell);
+        assert_eq!(trade.sell.currency().code, "ETH");
+        assert_eq!(*trade.buy.amount(), dec!(300));
+        assert_eq!(trade.buy.currency().code, "GBP");
+        assert_eq!(trade.rate, dec!(1200));
+    }
+}
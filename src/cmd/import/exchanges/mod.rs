@@ -1,6 +1,10 @@
 pub mod binance;
 pub mod bittrex;
 pub mod coinbase;
+pub mod cryptocom;
+pub mod gemini;
+pub mod generic;
+pub mod kraken;
 pub mod poloniex;
 pub mod uphold;
 
@@ -10,6 +14,8 @@ pub enum ExchangeError {
     DateParse(chrono::format::ParseError),
     InvalidRecord(&'static str),
     DecimalError(rust_decimal::Error),
+    #[from(ignore)]
+    InvalidMapping(String),
 }
 
 impl std::error::Error for ExchangeError {}
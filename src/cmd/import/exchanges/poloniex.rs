@@ -69,6 +69,7 @@ impl<'a> TryFrom<Record> for Trade<'a> {
             fee,
             rate: value.price,
             exchange: Some("Poloniex".into()),
+            source_id: None,
         })
     }
 }
@@ -66,6 +66,7 @@ impl<'a> TryFrom<Record> for Trade<'a> {
             fee,
             rate: value.rate,
             exchange: Some("Uphold".into()),
+            source_id: None,
             kind,
         })
     }
@@ -1,12 +1,25 @@
 mod exchanges;
 
 use crate::{
-    cmd::import::exchanges::{binance::BinanceApiCommand, ExchangeError},
+    cmd::import::exchanges::{
+        binance::{BinanceApiCommand, BinancePricesCommand},
+        ExchangeError,
+    },
     trades::{Trade, TradeRecord},
 };
 use argh::FromArgs;
+use chrono::{DateTime, NaiveDateTime};
+use color_eyre::eyre;
 use serde::de::DeserializeOwned;
-use std::{convert::TryInto, fs::File, io, path::PathBuf};
+use std::{
+    convert::TryInto,
+    env,
+    fs::{self, File},
+    io::{self, BufRead, BufReader, Read, Write},
+    path::PathBuf,
+    process,
+    sync::atomic::{AtomicUsize, Ordering},
+};
 
 /// Import trades from a csv file
 #[derive(FromArgs, PartialEq, Debug)]
@@ -58,12 +71,14 @@ impl ImportApiCommand {
 #[argh(subcommand)]
 pub enum ImportApiSubCommand {
     Binance(BinanceApiCommand),
+    BinancePrices(BinancePricesCommand),
 }
 
 impl ImportApiSubCommand {
     pub fn exec(&self) -> color_eyre::Result<()> {
         match self {
             Self::Binance(binance) => binance.exec(),
+            Self::BinancePrices(prices) => prices.exec(),
         }
     }
 }
@@ -75,41 +90,71 @@ pub struct ImportExchangeCsvCommand {
     /// the exchange to import csv from
     #[argh(positional)]
     exchange: Exchange,
-    /// the csv file containing trades to import
+    /// the csv files containing trades to import, e.g. several exports of the same account
     #[argh(positional)]
-    file: PathBuf,
+    files: Vec<PathBuf>,
     /// combines trades on the same pair on the same day into a single trade
     #[argh(switch, short = 'g')]
     group_by_day: bool,
+    /// the field delimiter of the csv file, defaults to ','
+    #[argh(option, default = "','")]
+    delimiter: char,
+    /// the number of preamble lines to skip before the csv header
+    #[argh(option, default = "0")]
+    skip_lines: usize,
+    /// maps a trade field to a csv header for the generic importer e.g. `--map date=Date`.
+    /// Fields: date, sold_asset, sold_qty, bought_asset, bought_qty, fee_asset, fee_qty
+    #[argh(option)]
+    map: Vec<String>,
+    /// convert and write the trades as they are read, sorting large files in chunks on disk to
+    /// limit memory use. Not supported with --group-by-day, multi-row exports or several files.
+    #[argh(switch)]
+    stream: bool,
+    /// remove trades already imported from an earlier file, for exports which overlap
+    #[argh(switch)]
+    dedup: bool,
 }
 
 impl ImportExchangeCsvCommand {
     pub fn exec(&self) -> color_eyre::Result<()> {
-        match self.exchange {
-            Exchange::Uphold => self.import_csv::<exchanges::uphold::Record, _>(),
-            Exchange::Poloniex => self.import_csv::<exchanges::poloniex::Record, _>(),
-            Exchange::Bittrex => self.import_csv::<exchanges::bittrex::Record, _>(),
-            Exchange::Binance => self.import_csv::<exchanges::binance::CsvRecord, _>(),
-            Exchange::Coinbase => self.import_csv::<exchanges::coinbase::Record, _>(),
-        }
-    }
-
-    fn import_csv<'a, CsvRecord, E>(&self) -> color_eyre::Result<()>
-    where
-        CsvRecord: Clone + DeserializeOwned + TryInto<Trade<'a>, Error = E>,
-        E: std::error::Error + 'static + Send + Sync,
-    {
-        let file = File::open(&self.file)?;
-        let mut rdr = csv::Reader::from_reader(file);
-        let result: Result<Vec<CsvRecord>, _> = rdr.deserialize().collect();
-        let result = result?;
-        log::info!("Read {} csv records", result.len());
-        let mut trades = result
+        if !self.delimiter.is_ascii() {
+            return Err(eyre::eyre!(
+                "Delimiter must be a single ASCII character, got {}",
+                self.delimiter
+            ));
+        }
+        let options = CsvOptions {
+            delimiter: self.delimiter as u8,
+            skip_lines: self.skip_lines,
+        };
+        if !self.map.is_empty() && self.exchange != Exchange::Generic {
+            return Err(eyre::eyre!("--map requires the generic importer"));
+        }
+        if self.files.is_empty() {
+            return Err(eyre::eyre!("At least one csv file is required"));
+        }
+
+        if self.stream {
+            return match self.files.as_slice() {
+                [file] => self.stream(File::open(file)?, &options),
+                _ => Err(eyre::eyre!("--stream only supports a single file")),
+            };
+        }
+
+        let batches = self
+            .files
             .iter()
-            .cloned()
-            .map(|record: CsvRecord| TryInto::try_into(record).map_err(Into::into))
-            .collect::<color_eyre::Result<Vec<Trade>>>()?;
-        trades.sort_by(|tx1, tx2| tx1.date_time.cmp(&tx2.date_time));
+            .map(|file| self.read_trades(File::open(file)?, &options))
+            .collect::<color_eyre::Result<Vec<_>>>()?;
+        let trades = if self.dedup {
+            let (trades, removed) = crate::trades::dedup(batches);
+            log::info!("Removed {} duplicate trades", removed);
+            trades
+        } else {
+            let mut trades = batches.into_iter().flatten().collect::<Vec<_>>();
+            trades.sort_by(|tx1, tx2| tx1.date_time.cmp(&tx2.date_time));
+            trades
+        };
 
         let trades = if self.group_by_day {
             crate::trades::group_trades_by_day(&trades)
@@ -120,6 +165,283 @@ impl ImportExchangeCsvCommand {
         let trade_records = trades.iter().map(|t| TradeRecord::from(t)).collect();
         crate::utils::write_csv(trade_records, io::stdout())
     }
+
+    /// Reads the trades of an export of the exchange, sorted by date
+    fn read_trades<'a>(
+        &self,
+        file: File,
+        options: &CsvOptions,
+    ) -> color_eyre::Result<Vec<Trade<'a>>> {
+        let trades = match self.exchange {
+            Exchange::Uphold => csv_to_trades::<exchanges::uphold::Record, _, _>(file, options)?,
+            Exchange::Poloniex => {
+                csv_to_trades::<exchanges::poloniex::Record, _, _>(file, options)?
+            }
+            Exchange::Bittrex => csv_to_trades::<exchanges::bittrex::Record, _, _>(file, options)?,
+            Exchange::Binance => {
+                csv_to_trades::<exchanges::binance::CsvRecord, _, _>(file, options)?
+            }
+            Exchange::Coinbase => {
+                csv_to_trades::<exchanges::coinbase::Record, _, _>(file, options)?
+            }
+            Exchange::CryptoCom => {
+                let records = read_csv_records(file, options)?;
+                exchanges::cryptocom::transactions_to_trades(records)?
+            }
+            Exchange::Gemini => {
+                let records = read_csv_records(file, options)?;
+                exchanges::gemini::history_to_trades(records)?
+            }
+            Exchange::Generic => {
+                let mapping = exchanges::generic::ColumnMapping::parse(&self.map)?;
+                let records = read_csv_records(file, options)?;
+                exchanges::generic::records_to_trades(records, &mapping)?
+            }
+            Exchange::Kraken => {
+                let records = read_csv_records(file, options)?;
+                exchanges::kraken::ledger_to_trades(records)?
+            }
+        };
+        Ok(trades)
+    }
+
+    fn stream(&self, file: File, options: &CsvOptions) -> color_eyre::Result<()> {
+        if self.group_by_day {
+            return Err(eyre::eyre!("--stream can't be used with --group-by-day"));
+        }
+        let out = io::stdout();
+        let chunk_size = STREAM_CHUNK_SIZE;
+        match self.exchange {
+            Exchange::Uphold => stream_csv_to_trades::<exchanges::uphold::Record, _, _, _>(
+                file, options, chunk_size, out,
+            ),
+            Exchange::Poloniex => stream_csv_to_trades::<exchanges::poloniex::Record, _, _, _>(
+                file, options, chunk_size, out,
+            ),
+            Exchange::Bittrex => stream_csv_to_trades::<exchanges::bittrex::Record, _, _, _>(
+                file, options, chunk_size, out,
+            ),
+            Exchange::Binance => stream_csv_to_trades::<exchanges::binance::CsvRecord, _, _, _>(
+                file, options, chunk_size, out,
+            ),
+            Exchange::Coinbase => stream_csv_to_trades::<exchanges::coinbase::Record, _, _, _>(
+                file, options, chunk_size, out,
+            ),
+            ref exchange => Err(eyre::eyre!(
+                "--stream is not supported for {:?} exports",
+                exchange
+            )),
+        }
+    }
+}
+
+/// Options for reading an exchange csv export
+#[derive(Clone, Debug)]
+pub struct CsvOptions {
+    /// The field delimiter
+    pub delimiter: u8,
+    /// The number of lines preceding the header row which should be ignored
+    pub skip_lines: usize,
+}
+
+impl Default for CsvOptions {
+    fn default() -> Self {
+        CsvOptions {
+            delimiter: b',',
+            skip_lines: 0,
+        }
+    }
+}
+
+/// Creates a csv reader for an exchange export, positioned at the header row
+fn csv_reader<R: Read>(
+    reader: R,
+    options: &CsvOptions,
+) -> color_eyre::Result<csv::Reader<BufReader<R>>> {
+    let mut reader = BufReader::new(reader);
+    for _ in 0..options.skip_lines {
+        let mut line = String::new();
+        reader.read_line(&mut line)?;
+    }
+    Ok(csv::ReaderBuilder::new()
+        .delimiter(options.delimiter)
+        .from_reader(reader))
+}
+
+/// Reads all the records of an exchange csv export
+fn read_csv_records<CsvRecord, R>(
+    reader: R,
+    options: &CsvOptions,
+) -> color_eyre::Result<Vec<CsvRecord>>
+where
+    CsvRecord: DeserializeOwned,
+    R: Read,
+{
+    let mut rdr = csv_reader(reader, options)?;
+    let result: Result<Vec<CsvRecord>, _> = rdr.deserialize().collect();
+    let result = result?;
+    log::info!("Read {} csv records", result.len());
+    Ok(result)
+}
+
+/// Reads exchange csv records and converts them to trades, sorted by date
+fn csv_to_trades<'a, CsvRecord, E, R>(
+    reader: R,
+    options: &CsvOptions,
+) -> color_eyre::Result<Vec<Trade<'a>>>
+where
+    CsvRecord: DeserializeOwned + TryInto<Trade<'a>, Error = E>,
+    E: std::error::Error + 'static + Send + Sync,
+    R: Read,
+{
+    let records = read_csv_records::<CsvRecord, _>(reader, options)?;
+    let mut trades = records
+        .into_iter()
+        .map(|record: CsvRecord| TryInto::try_into(record).map_err(Into::into))
+        .collect::<color_eyre::Result<Vec<Trade>>>()?;
+    trades.sort_by(|tx1, tx2| tx1.date_time.cmp(&tx2.date_time));
+    Ok(trades)
+}
+
+/// The number of trades sorted in memory at once when streaming an import
+const STREAM_CHUNK_SIZE: usize = 100_000;
+
+/// Converts exchange csv records to trades as they are read, writing them sorted by date.
+///
+/// Files with more than `chunk_size` trades are sorted a chunk at a time, each written to a
+/// temporary file, and the chunks then merged, so only one chunk is held in memory. The output
+/// is the same as that of [`csv_to_trades`].
+fn stream_csv_to_trades<'a, CsvRecord, E, R, W>(
+    reader: R,
+    options: &CsvOptions,
+    chunk_size: usize,
+    writer: W,
+) -> color_eyre::Result<()>
+where
+    CsvRecord: DeserializeOwned + TryInto<Trade<'a>, Error = E>,
+    E: std::error::Error + 'static + Send + Sync,
+    R: Read,
+    W: Write,
+{
+    let (mut chunks, mut chunk) =
+        read_sorted_chunks::<CsvRecord, _, _>(reader, options, chunk_size)?;
+
+    let mut wtr = csv::Writer::from_writer(writer);
+    if chunks.is_empty() {
+        chunk.sort_by(|tx1, tx2| tx1.date_time.cmp(&tx2.date_time));
+        for trade in chunk.iter() {
+            wtr.serialize(TradeRecord::from(trade))?;
+        }
+    } else {
+        if !chunk.is_empty() {
+            chunks.push(SortedChunk::write(&mut chunk)?);
+        }
+        merge_chunks(&chunks, &mut wtr)?;
+    }
+    wtr.flush()?;
+    Ok(())
+}
+
+/// Reads the trades, writing each `chunk_size` of them to a sorted chunk file. Returns the chunk
+/// files and the remaining trades, which are less than a chunk.
+fn read_sorted_chunks<'a, CsvRecord, E, R>(
+    reader: R,
+    options: &CsvOptions,
+    chunk_size: usize,
+) -> color_eyre::Result<(Vec<SortedChunk>, Vec<Trade<'a>>)>
+where
+    CsvRecord: DeserializeOwned + TryInto<Trade<'a>, Error = E>,
+    E: std::error::Error + 'static + Send + Sync,
+    R: Read,
+{
+    let mut rdr = csv_reader(reader, options)?;
+    let mut chunk: Vec<Trade> = Vec::new();
+    let mut chunks = Vec::new();
+    let mut count = 0;
+    for record in rdr.deserialize::<CsvRecord>() {
+        chunk.push(record?.try_into()?);
+        count += 1;
+        if chunk.len() == chunk_size {
+            chunks.push(SortedChunk::write(&mut chunk)?);
+        }
+    }
+    log::info!("Read {} csv records", count);
+    Ok((chunks, chunk))
+}
+
+/// A chunk of trades sorted by date, in a temporary file which is removed when dropped
+struct SortedChunk {
+    path: PathBuf,
+}
+
+impl SortedChunk {
+    /// Sorts the trades and moves them to a new chunk file
+    fn write(trades: &mut Vec<Trade>) -> color_eyre::Result<Self> {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        let index = COUNT.fetch_add(1, Ordering::SeqCst);
+        let path = env::temp_dir().join(format!("taxc-import-{}-{}.csv", process::id(), index));
+        let chunk = SortedChunk { path };
+        trades.sort_by(|tx1, tx2| tx1.date_time.cmp(&tx2.date_time));
+        let records = trades.drain(..).map(|t| TradeRecord::from(&t)).collect();
+        crate::utils::write_csv(records, File::create(&chunk.path)?)?;
+        Ok(chunk)
+    }
+
+    fn records(&self) -> color_eyre::Result<impl Iterator<Item = csv::Result<TradeRecord>>> {
+        Ok(csv::Reader::from_path(&self.path)?.into_deserialize())
+    }
+}
+
+impl Drop for SortedChunk {
+    fn drop(&mut self) {
+        if let Err(err) = fs::remove_file(&self.path) {
+            log::warn!("Failed to remove {}: {}", self.path.display(), err)
+        }
+    }
+}
+
+/// Writes the records of all the chunks in date order. Records with the same date are written
+/// in the order of their chunks, as a stable sort of all the trades would.
+fn merge_chunks<W: Write>(
+    chunks: &[SortedChunk],
+    wtr: &mut csv::Writer<W>,
+) -> color_eyre::Result<()> {
+    let next = |records: &mut dyn Iterator<Item = csv::Result<TradeRecord>>| {
+        records
+            .next()
+            .map(
+                |record| -> color_eyre::Result<(NaiveDateTime, TradeRecord)> {
+                    let record = record?;
+                    let date_time = DateTime::parse_from_rfc3339(&record.date_time)?.naive_utc();
+                    Ok((date_time, record))
+                },
+            )
+            .transpose()
+    };
+    let mut readers = chunks
+        .iter()
+        .map(SortedChunk::records)
+        .collect::<color_eyre::Result<Vec<_>>>()?;
+    let mut heads = readers
+        .iter_mut()
+        .map(|records| next(records))
+        .collect::<color_eyre::Result<Vec<_>>>()?;
+    loop {
+        let earliest = heads
+            .iter()
+            .enumerate()
+            .filter_map(|(i, head)| head.as_ref().map(|(date_time, _)| (i, date_time)))
+            .min_by_key(|(i, date_time)| (*date_time, *i))
+            .map(|(i, _)| i);
+        match earliest {
+            Some(i) => {
+                let (_, record) = heads[i].take().expect("Earliest chunk has a record");
+                wtr.serialize(record)?;
+                heads[i] = next(&mut readers[i])?;
+            }
+            None => return Ok(()),
+        }
+    }
 }
 
 /// Import trades from a csv file for the given exchange
@@ -128,6 +450,10 @@ pub enum Exchange {
     Binance,
     Bittrex,
     Coinbase,
+    CryptoCom,
+    Gemini,
+    Generic,
+    Kraken,
     Poloniex,
     Uphold,
 }
@@ -140,9 +466,149 @@ impl std::str::FromStr for Exchange {
             "binance" => Ok(Self::Binance),
             "bittrex" => Ok(Self::Bittrex),
             "coinbase" => Ok(Self::Coinbase),
+            "cryptocom" => Ok(Self::CryptoCom),
+            "gemini" => Ok(Self::Gemini),
+            "generic" => Ok(Self::Generic),
+            "kraken" => Ok(Self::Kraken),
             "poloniex" => Ok(Self::Poloniex),
             "uphold" => Ok(Self::Uphold),
             e => Err(ExchangeError::UnsupportedExchange(e.into())),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal_macros::dec;
+
+    #[test]
+    fn reads_semicolon_delimited_csv() {
+        let csv = "\
+Date(UTC);Market;Type;Price;Amount;Total;Fee;Fee Coin
+2020-01-02 12:00:00;ETHBTC;SELL;0.02;5;0.1;0.0001;BTC
+2020-01-01 12:00:00;ETHBTC;BUY;0.02;10;0.2;0.01;ETH
+";
+        let options = CsvOptions {
+            delimiter: b';',
+            ..Default::default()
+        };
+        let trades =
+            csv_to_trades::<exchanges::binance::CsvRecord, _, _>(csv.as_bytes(), &options).unwrap();
+
+        assert_eq!(trades.len(), 2);
+        assert_eq!(trades[0].date_time.to_string(), "2020-01-01 12:00:00");
+        assert_eq!(trades[0].buy.currency().code, "ETH");
+        assert_eq!(*trades[0].buy.amount(), dec!(10));
+        assert_eq!(trades[0].sell.currency().code, "BTC");
+        assert_eq!(*trades[0].sell.amount(), dec!(0.2));
+        assert_eq!(*trades[1].sell.amount(), dec!(5));
+        assert_eq!(*trades[1].fee.amount(), dec!(0.0001));
+    }
+
+    #[test]
+    fn skips_preamble_lines() {
+        let csv = "\
+Binance trade history export
+Generated 2020-02-01
+Date(UTC),Market,Type,Price,Amount,Total,Fee,Fee Coin
+2020-01-01 12:00:00,ETHBTC,BUY,0.02,10,0.2,0.01,ETH
+";
+        let options = CsvOptions {
+            skip_lines: 2,
+            ..Default::default()
+        };
+        let trades =
+            csv_to_trades::<exchanges::binance::CsvRecord, _, _>(csv.as_bytes(), &options).unwrap();
+
+        assert_eq!(trades.len(), 1);
+        assert_eq!(*trades[0].buy.amount(), dec!(10));
+    }
+
+    fn binance_csv(trades: u32) -> String {
+        // trades on the same minute are spread over different chunks, to check they keep their
+        // original order
+        let mut csv = String::from("Date(UTC),Market,Type,Price,Amount,Total,Fee,Fee Coin\n");
+        for i in 0..trades {
+            let minute = (i * 7919) % 1000;
+            csv.push_str(&format!(
+                "2020-01-01 {:02}:{:02}:00,ETHBTC,BUY,0.02,{},{},0.01,ETH\n",
+                minute / 60,
+                minute % 60,
+                i + 1,
+                (i + 1) as f64 * 0.02
+            ));
+        }
+        csv
+    }
+
+    #[test]
+    fn streamed_import_matches_in_memory_import() {
+        let csv = binance_csv(2500);
+        let options = CsvOptions::default();
+
+        let trades =
+            csv_to_trades::<exchanges::binance::CsvRecord, _, _>(csv.as_bytes(), &options).unwrap();
+        let mut in_memory = Vec::new();
+        let records = trades.iter().map(TradeRecord::from).collect();
+        crate::utils::write_csv(records, &mut in_memory).unwrap();
+
+        let mut streamed = Vec::new();
+        stream_csv_to_trades::<exchanges::binance::CsvRecord, _, _, _>(
+            csv.as_bytes(),
+            &options,
+            700,
+            &mut streamed,
+        )
+        .unwrap();
+
+        assert_eq!(
+            String::from_utf8(streamed).unwrap(),
+            String::from_utf8(in_memory).unwrap()
+        );
+    }
+
+    #[test]
+    fn trades_beyond_the_chunk_size_are_sorted_in_chunk_files() {
+        let csv = binance_csv(2500);
+        let options = CsvOptions::default();
+
+        let (chunks, rest) = read_sorted_chunks::<exchanges::binance::CsvRecord, _, _>(
+            csv.as_bytes(),
+            &options,
+            700,
+        )
+        .unwrap();
+
+        assert_eq!(chunks.len(), 3, "Only the full chunks are written");
+        assert_eq!(rest.len(), 400, "Less than a chunk is held in memory");
+        let paths = chunks
+            .iter()
+            .map(|chunk| chunk.path.clone())
+            .collect::<Vec<_>>();
+        for chunk in &chunks {
+            let dates = chunk
+                .records()
+                .unwrap()
+                .map(|record| record.unwrap().date_time)
+                .collect::<Vec<_>>();
+            assert_eq!(dates.len(), 700);
+            assert!(dates.windows(2).all(|pair| pair[0] <= pair[1]), "Sorted");
+        }
+
+        let mut merged = csv::Writer::from_writer(Vec::new());
+        merge_chunks(&chunks, &mut merged).unwrap();
+        let merged = String::from_utf8(merged.into_inner().unwrap()).unwrap();
+        assert_eq!(
+            merged.lines().count(),
+            2100 + 1,
+            "All the chunks are merged"
+        );
+
+        drop(chunks);
+        assert!(
+            paths.iter().all(|path| !path.exists()),
+            "Chunk files removed"
+        );
+    }
+}
@@ -1,3 +1,6 @@
 pub mod import;
+pub mod pools;
 pub mod prices;
 pub mod report;
+pub mod summary;
+pub mod validate;
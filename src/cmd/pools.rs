@@ -0,0 +1,325 @@
+use crate::{
+    cmd::{
+        prices::{CurrencyPair, Prices},
+        report::cgt,
+    },
+    money::fixed_amount,
+};
+use argh::FromArgs;
+use chrono::NaiveDate;
+use prettytable::{cell, row, Table};
+use serde::Serialize;
+use std::io;
+
+/// Inspect the Section 104 pools after the trades of the report
+#[derive(FromArgs, PartialEq, Debug)]
+#[argh(subcommand, name = "pools")]
+pub struct PoolsCommand {
+    #[argh(subcommand)]
+    sub: PoolsSubCommand,
+}
+
+impl PoolsCommand {
+    pub fn exec(&self, report: &cgt::TaxReport, prices: &Prices) -> color_eyre::Result<()> {
+        match self.sub {
+            PoolsSubCommand::Snapshot(ref snapshot) => snapshot.exec(report),
+            PoolsSubCommand::CostBasis(ref cost_basis) => cost_basis.exec(report),
+            PoolsSubCommand::Unrealised(ref unrealised) => unrealised.exec(report, prices),
+        }
+    }
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+#[argh(subcommand)]
+pub enum PoolsSubCommand {
+    Snapshot(SnapshotCommand),
+    CostBasis(CostBasisCommand),
+    Unrealised(UnrealisedCommand),
+}
+
+/// Print the pool balances at the end of a tax year
+#[derive(FromArgs, PartialEq, Debug)]
+#[argh(subcommand, name = "snapshot")]
+pub struct SnapshotCommand {
+    /// the tax year at the end of which to show the pools e.g. 2021 for 2020/21
+    #[argh(option)]
+    year: i32,
+    /// print the pools as csv instead of a table
+    #[argh(switch)]
+    csv: bool,
+}
+
+#[derive(Serialize)]
+struct PoolRecord {
+    asset: String,
+    quantity: String,
+    cost: String,
+    cost_basis: String,
+    /// The end of the tax year, so the csv can be used as the opening pools of a later report
+    acquisition_date: String,
+}
+
+impl SnapshotCommand {
+    pub fn exec(&self, report: &cgt::TaxReport) -> color_eyre::Result<()> {
+        let records = snapshot_records(report, self.year);
+
+        if self.csv {
+            return crate::utils::write_csv(records, io::stdout());
+        }
+        let mut table = Table::new();
+        table.set_titles(row!["Asset", "Quantity", "Cost", "Average Cost"]);
+        for record in records {
+            table.add_row(row![
+                record.asset,
+                r->record.quantity,
+                r->record.cost,
+                r->record.cost_basis
+            ]);
+        }
+        table.printstd();
+        Ok(())
+    }
+}
+
+/// The pools at the end of the tax year. The quantities and costs are at full precision, so a
+/// report from the snapshot matches one from the full history.
+fn snapshot_records(report: &cgt::TaxReport, year: i32) -> Vec<PoolRecord> {
+    report
+        .pools_at_end_of(year)
+        .iter()
+        .map(|pool| PoolRecord {
+            asset: pool.currency().code.to_string(),
+            quantity: pool.total().amount().to_string(),
+            cost: pool.costs().amount().to_string(),
+            cost_basis: format!("{:.2}", pool.cost_basis()),
+            acquisition_date: NaiveDate::from_ymd(year, 4, 5).to_string(),
+        })
+        .collect()
+}
+
+/// Print the average cost of an asset in its pool after all the trades
+#[derive(FromArgs, PartialEq, Debug)]
+#[argh(subcommand, name = "cost-basis")]
+pub struct CostBasisCommand {
+    /// the asset e.g. BTC
+    #[argh(positional)]
+    asset: String,
+}
+
+impl CostBasisCommand {
+    pub fn exec(&self, report: &cgt::TaxReport) -> color_eyre::Result<()> {
+        let asset = self.asset.to_uppercase();
+        match report.average_cost_basis(&asset) {
+            Some(cost_basis) => println!("{} {:.2}", asset, cost_basis),
+            None => println!("No {} held", asset),
+        }
+        Ok(())
+    }
+}
+
+/// Print the unrealised gains of the pools after all the trades, at the latest prices
+#[derive(FromArgs, PartialEq, Debug)]
+#[argh(subcommand, name = "unrealised")]
+pub struct UnrealisedCommand {
+    /// print the gains as csv instead of a table
+    #[argh(switch)]
+    csv: bool,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+struct UnrealisedRecord {
+    asset: String,
+    quantity: String,
+    cost: String,
+    market_value: String,
+    unrealised_gain: String,
+}
+
+impl UnrealisedCommand {
+    pub fn exec(&self, report: &cgt::TaxReport, prices: &Prices) -> color_eyre::Result<()> {
+        let mut pools = report.pools.values().cloned().collect::<Vec<_>>();
+        pools.sort_by_key(|pool| pool.currency().code);
+        let records = unrealised_gains(&pools, prices);
+
+        if self.csv {
+            return crate::utils::write_csv(records, io::stdout());
+        }
+        let mut table = Table::new();
+        table.set_titles(row![
+            "Asset",
+            "Quantity",
+            "Cost",
+            "Market Value",
+            "Unrealised Gain"
+        ]);
+        for record in records {
+            table.add_row(row![
+                record.asset,
+                r->record.quantity,
+                r->record.cost,
+                r->record.market_value,
+                r->record.unrealised_gain
+            ]);
+        }
+        table.printstd();
+        Ok(())
+    }
+}
+
+/// Values the non empty pools at the latest price of their asset in the reporting currency of their
+/// costs, skipping those without one.
+fn unrealised_gains(pools: &[cgt::Pool], prices: &Prices) -> Vec<UnrealisedRecord> {
+    pools
+        .iter()
+        .filter(|pool| pool.total().is_positive())
+        .filter_map(|pool| {
+            let pair = CurrencyPair {
+                base: pool.currency(),
+                quote: pool.costs().currency(),
+            };
+            let price = match prices.latest(&pair) {
+                Some(price) => price,
+                None => {
+                    log::warn!("No price for {}, skipping", pair);
+                    return None;
+                }
+            };
+            let market_value = *pool.total().amount() * price.rate;
+            Some(UnrealisedRecord {
+                asset: pool.currency().code.to_string(),
+                quantity: fixed_amount(pool.total()),
+                cost: fixed_amount(pool.costs()),
+                market_value: format!("{:.2}", market_value),
+                unrealised_gain: format!("{:.2}", market_value - *pool.costs().amount()),
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        money::amount,
+        trades::{
+            fixtures::{buy, sell, trade},
+            Trade, TradeKind,
+        },
+    };
+    use rust_decimal_macros::dec;
+
+    #[test]
+    fn report_from_snapshot_matches_full_history() {
+        let trades = vec![
+            buy("2019-06-01", "BTC", dec!(3), dec!(10000)),
+            buy("2019-12-01", "BTC", dec!(0.7), dec!(8000)),
+            sell("2020-03-20", "BTC", dec!(1), dec!(6000)),
+            buy("2020-04-10", "BTC", dec!(0.5), dec!(3500)),
+            sell("2020-06-01", "BTC", dec!(1.5), dec!(13500)),
+            buy("2020-06-20", "BTC", dec!(0.4), dec!(4000)),
+        ];
+        let prices = Prices::default();
+        let disposals = |report: &cgt::TaxReport| {
+            let mut output = Vec::new();
+            report
+                .gains(Some(2021))
+                .write_disposals_csv(&mut output)
+                .unwrap();
+            String::from_utf8(output).unwrap()
+        };
+        let full = cgt::calculate(trades.clone(), &prices, &cgt::Config::default()).unwrap();
+
+        let mut snapshot = Vec::new();
+        crate::utils::write_csv(snapshot_records(&full, 2020), &mut snapshot).unwrap();
+        let config = cgt::Config {
+            opening_pools: cgt::OpeningPool::read_csv(snapshot.as_slice()).unwrap(),
+            snapshot_date: Some(NaiveDate::from_ymd(2020, 4, 5)),
+            ..cgt::Config::default()
+        };
+        // only the trades from 30 days before the snapshot are needed
+        let incremental = cgt::calculate(trades[2..].to_vec(), &prices, &config).unwrap();
+
+        assert_eq!(disposals(&incremental), disposals(&full));
+        assert_eq!(
+            incremental.pools["BTC"].costs().amount().round_dp(10),
+            full.pools["BTC"].costs().amount().round_dp(10),
+            "The costs of the snapshot are not rounded to pence"
+        );
+        assert_eq!(
+            incremental.pools["BTC"].total().amount(),
+            full.pools["BTC"].total().amount()
+        );
+    }
+
+    #[test]
+    fn pools_are_valued_at_latest_price() {
+        let prices = Prices::read_csv(
+            "base_currency,quote_currency,date_time,rate\n\
+             BTC,GBP,2021-01-01T00:00:00Z,20000\n\
+             BTC,GBP,2021-03-01T00:00:00Z,35000\n\
+             ETH,GBP,2021-03-01T00:00:00Z,1000\n"
+                .as_bytes(),
+        )
+        .unwrap();
+        let trades = vec![
+            buy("2020-06-01", "BTC", dec!(0.5), dec!(5000)),
+            buy("2020-06-01", "ETH", dec!(10), dec!(2000)),
+            buy("2020-07-01", "DOT", dec!(100), dec!(400)),
+        ];
+        let report = cgt::calculate(trades, &prices, &cgt::Config::default()).unwrap();
+        let mut pools = report.pools.values().cloned().collect::<Vec<_>>();
+        pools.sort_by_key(|pool| pool.currency().code);
+
+        let records = unrealised_gains(&pools, &prices);
+
+        let gains = records
+            .iter()
+            .map(|r| {
+                (
+                    r.asset.as_str(),
+                    r.market_value.as_str(),
+                    r.unrealised_gain.as_str(),
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            gains,
+            vec![
+                ("BTC", "17500.00", "12500.00"),
+                ("ETH", "10000.00", "8000.00")
+            ],
+            "DOT has no price so should be skipped"
+        );
+    }
+
+    #[test]
+    fn pools_are_valued_in_reporting_currency() {
+        let prices = Prices::read_csv(
+            "base_currency,quote_currency,date_time,rate\n\
+             BTC,GBP,2021-03-01T00:00:00Z,35000\n\
+             BTC,EUR,2021-03-01T00:00:00Z,40000\n"
+                .as_bytes(),
+        )
+        .unwrap();
+        let trades = vec![Trade {
+            fee: amount("EUR", dec!(0)),
+            ..trade(
+                "2020-06-01",
+                TradeKind::Buy,
+                amount("EUR", dec!(6000)),
+                amount("BTC", dec!(0.5)),
+            )
+        }];
+        let config = cgt::Config {
+            reporting_currency: crate::currencies::EUR,
+            ..cgt::Config::default()
+        };
+        let report = cgt::calculate(trades, &prices, &config).unwrap();
+        let pools = report.pools.values().cloned().collect::<Vec<_>>();
+
+        let records = unrealised_gains(&pools, &prices);
+
+        assert_eq!(records[0].market_value, "20000.00");
+        assert_eq!(records[0].unrealised_gain, "14000.00");
+    }
+}
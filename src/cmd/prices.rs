@@ -1,6 +1,17 @@
-use std::{collections::HashMap, fmt, io::Read};
+use std::{
+    collections::HashMap,
+    fmt, fs,
+    io::Read,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
 
-use crate::currencies::{self, Currency, BTC, ETH, GBP, USDC};
+use crate::{
+    currencies::{self, Currency},
+    money::FIAT,
+    trades::Trade,
+};
 use chrono::{DateTime, NaiveDate, NaiveDateTime};
 use color_eyre::eyre;
 use rust_decimal::Decimal;
@@ -31,51 +42,128 @@ pub struct Price<'a> {
     pub pair: CurrencyPair<'a>,
     pub date_time: NaiveDateTime,
     pub rate: Decimal,
+    /// The policy by which the price was found for a day without one, if it was
+    pub gap: Option<PriceGapPolicy>,
 }
 
 #[derive(Default)]
 pub struct Prices<'a> {
     prices: HashMap<CurrencyPair<'a>, Vec<Price<'a>>>,
+    gap_policy: PriceGapPolicy,
 }
 
+/// How a price is found for a date without one, e.g. a weekend missing from a prices file.
+/// Prices are only carried or interpolated over gaps of up to [`MAX_PRICE_GAP_DAYS`].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PriceGapPolicy {
+    /// There is no price, so the report fails listing the assets without prices
+    Error,
+    /// The last known price before the date
+    Carry,
+    /// Linearly interpolated between the known prices either side of the date
+    Interpolate,
+}
+
+/// The most days between known prices over which a price is carried or interpolated, so a
+/// missing stretch of prices is an error rather than valued at a stale price
+pub const MAX_PRICE_GAP_DAYS: i64 = 7;
+
+impl Default for PriceGapPolicy {
+    fn default() -> Self {
+        PriceGapPolicy::Error
+    }
+}
+
+impl FromStr for PriceGapPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "error" => Ok(PriceGapPolicy::Error),
+            "carry" => Ok(PriceGapPolicy::Carry),
+            "interpolate" => Ok(PriceGapPolicy::Interpolate),
+            p => Err(format!("Unsupported price gap policy {}", p)),
+        }
+    }
+}
+
+/// A source of the daily prices of currency pairs
+pub trait PriceLookup<'a> {
+    /// The price of the pair on the day, if known
+    fn price(&self, pair: CurrencyPair<'a>, at: NaiveDate) -> Option<Price<'a>>;
+}
+
+impl<'a> PriceLookup<'a> for Prices<'a> {
+    fn price(&self, pair: CurrencyPair<'a>, at: NaiveDate) -> Option<Price<'a>> {
+        self.get(pair, at)
+    }
+}
+
+/// A row of a prices csv file
 #[derive(Debug, Serialize, Deserialize)]
-struct Record {
-    base_currency: String,
-    quote_currency: String,
-    date_time: String,
-    rate: Decimal,
+pub(crate) struct Record {
+    pub(crate) base_currency: String,
+    pub(crate) quote_currency: String,
+    pub(crate) date_time: String,
+    pub(crate) rate: Decimal,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct CoingeckoPrices {
     prices: Vec<CoingeckoPrice>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct CoingeckoPrice {
     timestamp: i64,
     price: Decimal,
 }
 
 impl<'a> Prices<'a> {
-    /// Initializes the prices database from the coingecko api
-    pub fn from_coingecko_api(quote_currency: &Currency) -> eyre::Result<Prices<'a>> {
-        let mut prices = HashMap::new();
+    /// Initializes the prices database from the coingecko api with the prices of the assets in
+    /// the quote currency, reading from and writing to the cache if supplied.
+    pub fn from_coingecko_api(
+        quote_currency: &'a Currency,
+        assets: &[&'a Currency],
+        ids: &CoingeckoIds,
+        cache: Option<&CoingeckoCache>,
+    ) -> eyre::Result<Prices<'a>> {
+        Self::from_coingecko(
+            COINGECKO_API,
+            &Retry::default(),
+            quote_currency,
+            assets,
+            ids,
+            cache,
+        )
+    }
 
-        let mut fetch_prices = |coin, base| -> eyre::Result<()> {
-            let url = format!(
-                "https://api.coingecko.com/api/v3/coins/{}/market_chart",
-                coin
-            );
-            let response = ureq::get(&url)
-                .query("vs_currency", quote_currency.code)
-                .query("interval", "daily")
-                .query("days", "max")
-                .call()?;
+    fn from_coingecko(
+        base_url: &str,
+        retry: &Retry,
+        quote_currency: &'a Currency,
+        assets: &[&'a Currency],
+        ids: &CoingeckoIds,
+        cache: Option<&CoingeckoCache>,
+    ) -> eyre::Result<Prices<'a>> {
+        let mut prices = HashMap::new();
+        // only checked when fetching, so cached prices can be used offline
+        let mut vs_currency_checked = false;
 
-            let coingecko_prices: CoingeckoPrices = response.into_json()?;
+        let mut fetch_prices = |coin: &str, base| -> eyre::Result<()> {
+            let coingecko_prices =
+                fetch_daily_prices(coin, quote_currency.code, cache, |coin, vs_currency| {
+                    if !vs_currency_checked {
+                        ensure_supported_vs_currency(base_url, vs_currency)?;
+                        vs_currency_checked = true;
+                    }
+                    fetch_coingecko_prices(base_url, coin, vs_currency, retry)
+                })?;
             log::info!("{} {} prices fetched", coingecko_prices.prices.len(), coin);
-            let pair = CurrencyPair { base, quote: GBP };
+            let pair = CurrencyPair {
+                base,
+                quote: quote_currency,
+            };
             let pair_prices = coingecko_prices
                 .prices
                 .iter()
@@ -85,6 +173,7 @@ impl<'a> Prices<'a> {
                         pair: pair.clone(),
                         date_time: NaiveDateTime::from_timestamp(unix_time_secs, 0).into(),
                         rate: price.price,
+                        gap: None,
                     }
                 })
                 .collect();
@@ -92,11 +181,20 @@ impl<'a> Prices<'a> {
             Ok(())
         };
 
-        fetch_prices("bitcoin", BTC)?;
-        fetch_prices("ethereum", ETH)?;
-        fetch_prices("usd-coin", USDC)?;
+        let assets = assets
+            .iter()
+            .filter(|asset| **asset != quote_currency)
+            .cloned()
+            .collect::<Vec<_>>();
+        let coins = ids.resolve(&assets, || fetch_coins_list(base_url))?;
+        for (asset, coin) in coins {
+            fetch_prices(&coin, asset)?;
+        }
 
-        Ok(Prices { prices })
+        Ok(Prices {
+            prices,
+            gap_policy: PriceGapPolicy::default(),
+        })
     }
 
     /// Initialize the prices database from the supplied CSV file
@@ -118,23 +216,455 @@ impl<'a> Prices<'a> {
                 pair: pair.clone(),
                 date_time,
                 rate: record.rate,
+                gap: None,
             };
             let pair_prices = prices.entry(pair).or_insert_with(Vec::new);
             pair_prices.push(price);
         }
 
-        Ok(Prices { prices })
+        Ok(Prices {
+            prices,
+            gap_policy: PriceGapPolicy::default(),
+        })
     }
 
-    /// gets daily price if exists
+    /// gets daily price if exists, otherwise via intermediate currencies e.g. USDC/USD then
+    /// USD/GBP for USDC/GBP.
+    ///
+    /// The shortest route with a price for every leg is used. Routes of the same length are tried
+    /// in order of their intermediate currencies, so the price doesn't depend on the order the
+    /// prices were read in.
     pub fn get(&self, pair: CurrencyPair<'a>, at: NaiveDate) -> Option<Price<'a>> {
-        self.prices.get(&pair).and_then(|prices| {
-            prices
+        if let Some(price) = self.get_direct(&pair, at) {
+            return Some(price);
+        }
+        let route = self.route(&pair, at)?;
+        log::debug!(
+            "Price for {} via {}",
+            pair,
+            route
+                .iter()
+                .map(|leg| leg.pair.to_string())
+                .collect::<Vec<_>>()
+                .join(" and ")
+        );
+        Some(Price {
+            pair,
+            date_time: route[0].date_time,
+            rate: route
                 .iter()
-                .find(|price| price.date_time.date() == at)
-                .cloned()
+                .fold(Decimal::from(1), |rate, leg| rate * leg.rate),
+            gap: route.iter().find_map(|leg| leg.gap),
         })
     }
+
+    /// Finds the prices of the legs of the shortest route from the base to the quote currency of
+    /// the pair, searching one leg further at a time.
+    fn route(&self, pair: &CurrencyPair<'a>, at: NaiveDate) -> Option<Vec<Price<'a>>> {
+        let mut visited = vec![pair.base];
+        let mut routes = vec![Vec::<Price<'a>>::new()];
+        while !routes.is_empty() {
+            let mut next = Vec::<Vec<Price<'a>>>::new();
+            for route in routes {
+                let from = route.last().map_or(pair.base, |leg| leg.pair.quote);
+                let mut legs = self
+                    .prices
+                    .keys()
+                    .filter(|leg| leg.base == from && !visited.contains(&leg.quote))
+                    .collect::<Vec<_>>();
+                legs.sort_by_key(|leg| leg.quote.code);
+                for leg in legs {
+                    let reached = |route: &Vec<Price<'a>>| {
+                        route.last().map(|last| last.pair.quote) == Some(leg.quote)
+                    };
+                    if next.iter().any(reached) {
+                        continue;
+                    }
+                    if let Some(price) = self.get_direct(leg, at) {
+                        let mut route = route.clone();
+                        route.push(price);
+                        if leg.quote == pair.quote {
+                            return Some(route);
+                        }
+                        next.push(route);
+                    }
+                }
+            }
+            visited.extend(
+                next.iter()
+                    .filter_map(|route| route.last())
+                    .map(|leg| leg.pair.quote),
+            );
+            routes = next;
+        }
+        None
+    }
+
+    /// The most recent price of the pair
+    pub fn latest(&self, pair: &CurrencyPair<'a>) -> Option<Price<'a>> {
+        self.prices
+            .get(pair)
+            .and_then(|prices| prices.iter().max_by_key(|price| price.date_time).cloned())
+    }
+
+    /// Sets how prices are found for dates without one
+    pub fn with_gap_policy(self, gap_policy: PriceGapPolicy) -> Self {
+        Prices { gap_policy, ..self }
+    }
+
+    fn get_direct(&self, pair: &CurrencyPair<'a>, at: NaiveDate) -> Option<Price<'a>> {
+        let prices = self.prices.get(pair)?;
+        if let Some(price) = prices.iter().find(|price| price.date_time.date() == at) {
+            return Some(price.clone());
+        }
+        let before = || {
+            prices
+                .iter()
+                .filter(|price| price.date_time.date() < at)
+                .max_by_key(|price| price.date_time)
+        };
+        let within_max_gap =
+            |from: NaiveDate, to: NaiveDate| (to - from).num_days() <= MAX_PRICE_GAP_DAYS;
+        match self.gap_policy {
+            PriceGapPolicy::Error => None,
+            PriceGapPolicy::Carry => before()
+                .filter(|before| within_max_gap(before.date_time.date(), at))
+                .map(|before| Price {
+                    gap: Some(PriceGapPolicy::Carry),
+                    ..before.clone()
+                }),
+            PriceGapPolicy::Interpolate => {
+                let before = before()?;
+                let after = prices
+                    .iter()
+                    .filter(|price| price.date_time.date() > at)
+                    .min_by_key(|price| price.date_time)?;
+                if !within_max_gap(before.date_time.date(), after.date_time.date()) {
+                    return None;
+                }
+                let days = (after.date_time.date() - before.date_time.date()).num_days();
+                let elapsed = (at - before.date_time.date()).num_days();
+                let rate = before.rate
+                    + (after.rate - before.rate) * Decimal::from(elapsed) / Decimal::from(days);
+                Some(Price {
+                    pair: pair.clone(),
+                    date_time: at.and_hms(0, 0, 0),
+                    rate,
+                    gap: Some(PriceGapPolicy::Interpolate),
+                })
+            }
+        }
+    }
+}
+
+/// The assets of the trades which are priced with Coingecko, i.e. all but fiat, in order
+pub fn coingecko_assets<'a>(trades: &[Trade<'a>]) -> Vec<&'a Currency> {
+    let mut assets = trades
+        .iter()
+        .flat_map(|trade| vec![trade.buy.currency(), trade.sell.currency()])
+        .filter(|asset| !FIAT.contains(&asset.code))
+        .collect::<Vec<_>>();
+    assets.sort_by_key(|asset| asset.code);
+    assets.dedup_by_key(|asset| asset.code);
+    assets
+}
+
+/// The fiat currencies of the trades other than the reporting currency, in order. Their exchange
+/// rates aren't available from Coingecko, so must be in a prices file.
+pub fn unpriced_fiat<'a>(trades: &[Trade<'a>], currency: &Currency) -> Vec<&'a Currency> {
+    let mut fiat = trades
+        .iter()
+        .flat_map(|trade| vec![trade.buy.currency(), trade.sell.currency()])
+        .filter(|asset| FIAT.contains(&asset.code) && *asset != currency)
+        .collect::<Vec<_>>();
+    fiat.sort_by_key(|asset| asset.code);
+    fiat.dedup_by_key(|asset| asset.code);
+    fiat
+}
+
+const COINGECKO_API: &str = "https://api.coingecko.com/api/v3";
+
+/// The Coingecko ids of common assets, so their prices are fetched without listing all the coins
+const COINGECKO_IDS: [(&str, &str); 8] = [
+    ("ATOM", "cosmos"),
+    ("BTC", "bitcoin"),
+    ("DOT", "polkadot"),
+    ("ETC", "ethereum-classic"),
+    ("ETH", "ethereum"),
+    ("USDC", "usd-coin"),
+    ("USDT", "tether"),
+    ("XRP", "ripple"),
+];
+
+/// A coin listed by Coingecko
+#[derive(Debug, Deserialize)]
+pub struct CoingeckoCoin {
+    id: String,
+    symbol: String,
+}
+
+/// A row of a csv file of Coingecko ids
+#[derive(Debug, Deserialize)]
+struct CoingeckoIdRecord {
+    asset: String,
+    coingecko_id: String,
+}
+
+/// Resolves the tickers of assets to the ids of their coins on Coingecko.
+///
+/// Ids given by the user take precedence, then those of common assets. Other tickers are looked
+/// up in the list of all coins, failing if no coin or more than one has the ticker as its
+/// symbol, rather than fetching the prices of the wrong coin.
+#[derive(Debug, Default)]
+pub struct CoingeckoIds {
+    ids: HashMap<String, String>,
+}
+
+impl CoingeckoIds {
+    /// Reads the ids of assets from a csv file with the columns asset,coingecko_id
+    pub fn read_csv<R: Read>(reader: R) -> color_eyre::Result<Self> {
+        let mut rdr = csv::Reader::from_reader(reader);
+        let ids = rdr
+            .deserialize::<CoingeckoIdRecord>()
+            .map(|record| record.map(|r| (r.asset.to_uppercase(), r.coingecko_id)))
+            .collect::<Result<HashMap<_, _>, _>>()?;
+        Ok(CoingeckoIds { ids })
+    }
+
+    /// Returns the Coingecko id of each asset, only listing the coins if an asset isn't known
+    fn resolve<'a, F>(
+        &self,
+        assets: &[&'a Currency],
+        list_coins: F,
+    ) -> eyre::Result<Vec<(&'a Currency, String)>>
+    where
+        F: FnOnce() -> eyre::Result<Vec<CoingeckoCoin>>,
+    {
+        let known = |asset: &Currency| {
+            self.ids.get(asset.code).cloned().or_else(|| {
+                COINGECKO_IDS
+                    .iter()
+                    .find(|(code, _)| *code == asset.code)
+                    .map(|(_, id)| id.to_string())
+            })
+        };
+        let mut list_coins = Some(list_coins);
+        let mut coins = Vec::new();
+        let mut resolved = Vec::new();
+        let mut errors = Vec::new();
+        for asset in assets {
+            if let Some(id) = known(asset) {
+                resolved.push((*asset, id));
+                continue;
+            }
+            if let Some(list_coins) = list_coins.take() {
+                coins = list_coins()?;
+            }
+            let ids = coins
+                .iter()
+                .filter(|coin| coin.symbol.eq_ignore_ascii_case(asset.code))
+                .map(|coin| coin.id.as_str())
+                .collect::<Vec<_>>();
+            match ids.as_slice() {
+                [id] => resolved.push((*asset, id.to_string())),
+                [] => errors.push(format!("No Coingecko coin has the symbol {}", asset.code)),
+                ids => errors.push(format!(
+                    "More than one Coingecko coin has the symbol {}: {}",
+                    asset.code,
+                    ids.join(", ")
+                )),
+            }
+        }
+        if !errors.is_empty() {
+            return Err(eyre::eyre!(
+                "{}\nGive the id of each of these assets in a --coingecko-ids file",
+                errors.join("\n")
+            ));
+        }
+        Ok(resolved)
+    }
+}
+
+/// Fails if Coingecko doesn't quote prices in the currency
+fn ensure_supported_vs_currency(base_url: &str, vs_currency: &str) -> eyre::Result<()> {
+    let url = format!("{}/simple/supported_vs_currencies", base_url);
+    let supported: Vec<String> = ureq::get(&url).call()?.into_json()?;
+    if supported
+        .iter()
+        .any(|currency| currency.eq_ignore_ascii_case(vs_currency))
+    {
+        Ok(())
+    } else {
+        Err(eyre::eyre!("Coingecko has no prices in {}", vs_currency))
+    }
+}
+
+/// Fetches the id and symbol of every coin listed by Coingecko
+fn fetch_coins_list(base_url: &str) -> eyre::Result<Vec<CoingeckoCoin>> {
+    let url = format!("{}/coins/list", base_url);
+    Ok(ureq::get(&url).call()?.into_json()?)
+}
+
+/// The range of daily prices fetched from coingecko
+const DAYS: &str = "max";
+
+/// How failed requests for transient errors are retried
+struct Retry {
+    max_retries: u32,
+    base_delay: Duration,
+}
+
+impl Default for Retry {
+    fn default() -> Self {
+        Retry {
+            max_retries: 3,
+            base_delay: Duration::from_secs(2),
+        }
+    }
+}
+
+impl Retry {
+    /// Exponential backoff with up to 50% jitter
+    fn backoff(&self, attempt: u32) -> Duration {
+        let delay = self.base_delay * 2u32.pow(attempt);
+        let nanos = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.subsec_nanos())
+            .unwrap_or_default();
+        delay + delay.mul_f64(f64::from(nanos % 500) / 1000.0)
+    }
+}
+
+/// Fetches the daily prices of the coin, retrying rate limited (429) and server (5xx) errors.
+/// A `Retry-After` header on a 429 response is respected, otherwise the request is retried with
+/// exponential backoff. Other errors fail immediately.
+fn fetch_coingecko_prices(
+    base_url: &str,
+    coin: &str,
+    vs_currency: &str,
+    retry: &Retry,
+) -> eyre::Result<CoingeckoPrices> {
+    let url = format!("{}/coins/{}/market_chart", base_url, coin);
+    let mut attempt = 0;
+    loop {
+        let result = ureq::get(&url)
+            .query("vs_currency", vs_currency)
+            .query("interval", "daily")
+            .query("days", DAYS)
+            .call();
+        let delay = match result {
+            Ok(response) => return Ok(response.into_json()?),
+            Err(ureq::Error::Status(429, response)) => response
+                .header("Retry-After")
+                .and_then(|secs| secs.parse().ok())
+                .map(Duration::from_secs)
+                .unwrap_or_else(|| retry.backoff(attempt)),
+            Err(ureq::Error::Status(status, _)) if status >= 500 => retry.backoff(attempt),
+            Err(ureq::Error::Status(status, response)) => {
+                return Err(eyre::eyre!(
+                    "Coingecko request for {} {} prices failed with status {}: {}",
+                    coin,
+                    vs_currency,
+                    status,
+                    response.into_string().unwrap_or_default()
+                ))
+            }
+            Err(err) => return Err(err.into()),
+        };
+        if attempt >= retry.max_retries {
+            return Err(eyre::eyre!(
+                "Coingecko request for {} {} prices failed after {} retries",
+                coin,
+                vs_currency,
+                retry.max_retries
+            ));
+        }
+        attempt += 1;
+        log::warn!(
+            "Coingecko request for {} failed, retrying in {:?}",
+            coin,
+            delay
+        );
+        std::thread::sleep(delay);
+    }
+}
+
+/// Returns the daily prices of the coin, from the cache if present and not expired, otherwise
+/// using `fetch` and storing the result in the cache.
+fn fetch_daily_prices<F>(
+    coin: &str,
+    vs_currency: &str,
+    cache: Option<&CoingeckoCache>,
+    fetch: F,
+) -> eyre::Result<CoingeckoPrices>
+where
+    F: FnOnce(&str, &str) -> eyre::Result<CoingeckoPrices>,
+{
+    if let Some(cached) = cache.and_then(|cache| cache.read(coin, vs_currency)) {
+        log::info!("Using cached {} {} prices", coin, vs_currency);
+        return Ok(cached);
+    }
+    let prices = fetch(coin, vs_currency)?;
+    if let Some(cache) = cache {
+        if let Err(err) = cache.write(coin, vs_currency, &prices) {
+            log::warn!("Failed to cache {} {} prices: {}", coin, vs_currency, err)
+        }
+    }
+    Ok(prices)
+}
+
+/// Disk cache of coingecko responses, to avoid hitting the rate limits on repeated runs.
+///
+/// Entries are keyed by the coin id, quote currency and date range, and expire after the `ttl`.
+pub struct CoingeckoCache {
+    dir: PathBuf,
+    ttl: Duration,
+}
+
+impl CoingeckoCache {
+    pub fn new<P: AsRef<Path>>(dir: P, ttl: Duration) -> Self {
+        CoingeckoCache {
+            dir: dir.as_ref().to_path_buf(),
+            ttl,
+        }
+    }
+
+    /// `$XDG_CACHE_HOME/taxc`, falling back to `~/.cache/taxc`
+    pub fn default_dir() -> Option<PathBuf> {
+        std::env::var_os("XDG_CACHE_HOME")
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache")))
+            .map(|dir| dir.join("taxc"))
+    }
+
+    fn path(&self, coin: &str, vs_currency: &str) -> PathBuf {
+        let file_name = format!("coingecko-{}-{}-{}.json", coin, vs_currency, DAYS);
+        self.dir.join(file_name.to_lowercase())
+    }
+
+    fn read(&self, coin: &str, vs_currency: &str) -> Option<CoingeckoPrices> {
+        let path = self.path(coin, vs_currency);
+        let modified = fs::metadata(&path).and_then(|m| m.modified()).ok()?;
+        match modified.elapsed() {
+            Ok(age) if age <= self.ttl => (),
+            _ => return None,
+        }
+        let file = fs::File::open(&path).ok()?;
+        match serde_json::from_reader(file) {
+            Ok(prices) => Some(prices),
+            Err(err) => {
+                log::warn!("Ignoring invalid cache file {}: {}", path.display(), err);
+                None
+            }
+        }
+    }
+
+    fn write(&self, coin: &str, vs_currency: &str, prices: &CoingeckoPrices) -> eyre::Result<()> {
+        fs::create_dir_all(&self.dir)?;
+        let file = fs::File::create(self.path(coin, vs_currency))?;
+        serde_json::to_writer(file, prices)?;
+        Ok(())
+    }
 }
 
 fn parse_date(s: &str) -> NaiveDateTime {
@@ -142,3 +672,440 @@ fn parse_date(s: &str) -> NaiveDateTime {
         .expect(format!("Invalid date_time {}", s).as_ref())
         .naive_utc()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        currencies::{BTC, DOT, EUR, GBP, OMG, REP, USDC},
+        money::amount,
+        trades::{fixtures::trade, TradeKind},
+    };
+    use rust_decimal_macros::dec;
+    use std::io::Write;
+
+    fn temp_cache_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("taxc-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn prices() -> CoingeckoPrices {
+        CoingeckoPrices {
+            prices: vec![CoingeckoPrice {
+                timestamp: 1_609_459_200_000,
+                price: dec!(21_345.67),
+            }],
+        }
+    }
+
+    /// Serves the given raw http responses in order, one per connection
+    fn mock_server(responses: Vec<String>) -> String {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            for response in responses {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut buf = [0; 4096];
+                let _ = stream.read(&mut buf).unwrap();
+                stream.write_all(response.as_bytes()).unwrap();
+            }
+        });
+        format!("http://{}", addr)
+    }
+
+    fn http_response(status: &str, headers: &str, body: &str) -> String {
+        format!(
+            "HTTP/1.1 {}\r\n{}Content-Length: {}\r\n\r\n{}",
+            status,
+            headers,
+            body.len(),
+            body
+        )
+    }
+
+    fn no_delay() -> Retry {
+        Retry {
+            max_retries: 3,
+            base_delay: Duration::from_millis(0),
+        }
+    }
+
+    #[test]
+    fn fiat_other_than_reporting_currency_is_unpriced() {
+        let trades = vec![
+            trade(
+                "2020-06-01",
+                TradeKind::Buy,
+                amount("GBP", dec!(5000)),
+                amount("BTC", dec!(0.5)),
+            ),
+            trade(
+                "2020-07-01",
+                TradeKind::Sell,
+                amount("BTC", dec!(0.5)),
+                amount("EUR", dec!(6000)),
+            ),
+        ];
+
+        let fiat = unpriced_fiat(&trades, GBP)
+            .iter()
+            .map(|currency| currency.code)
+            .collect::<Vec<_>>();
+
+        assert_eq!(fiat, vec!["EUR"]);
+        assert!(unpriced_fiat(&trades[..1], GBP).is_empty());
+    }
+
+    #[test]
+    fn retries_after_rate_limit() {
+        let url = mock_server(vec![
+            http_response("429 Too Many Requests", "Retry-After: 0\r\n", ""),
+            http_response(
+                "200 OK",
+                "Content-Type: application/json\r\n",
+                r#"{"prices":[[1609459200000,21345.67]]}"#,
+            ),
+        ]);
+
+        let prices = fetch_coingecko_prices(&url, "bitcoin", "gbp", &no_delay()).unwrap();
+
+        assert_eq!(prices.prices.len(), 1);
+        assert_eq!(prices.prices[0].price, dec!(21345.67));
+    }
+
+    #[test]
+    fn unknown_coin_fails_without_retry() {
+        let url = mock_server(vec![http_response(
+            "404 Not Found",
+            "",
+            r#"{"error":"Could not find coin with the given id"}"#,
+        )]);
+
+        let err = fetch_coingecko_prices(&url, "notacoin", "gbp", &no_delay()).unwrap_err();
+
+        assert!(err.to_string().contains("status 404"), "{}", err);
+    }
+
+    const PRICES: &str = "\
+base_currency,quote_currency,date_time,rate
+BTC,GBP,2021-01-01T00:00:00Z,21000
+USDC,USD,2021-01-01T00:00:00Z,0.99
+USD,GBP,2021-01-01T00:00:00Z,0.75
+";
+
+    fn date() -> NaiveDate {
+        NaiveDate::from_ymd(2021, 1, 1)
+    }
+
+    #[test]
+    fn direct_price() {
+        let prices = Prices::read_csv(PRICES.as_bytes()).unwrap();
+        let pair = CurrencyPair {
+            base: BTC,
+            quote: GBP,
+        };
+
+        let price = prices.get(pair.clone(), date()).unwrap();
+
+        assert!(price.pair == pair);
+        assert_eq!(price.rate, dec!(21000));
+    }
+
+    #[test]
+    fn price_via_intermediate_currency() {
+        let prices = Prices::read_csv(PRICES.as_bytes()).unwrap();
+        let pair = CurrencyPair {
+            base: USDC,
+            quote: GBP,
+        };
+
+        let price = prices.get(pair.clone(), date()).unwrap();
+
+        assert!(price.pair == pair);
+        assert_eq!(price.rate, dec!(0.7425));
+    }
+
+    #[test]
+    fn competing_routes_are_chosen_in_order_of_intermediate_currency() {
+        let usd = "\
+USDC,USD,2021-01-01T00:00:00Z,0.99
+USD,GBP,2021-01-01T00:00:00Z,0.75
+";
+        let eur = "\
+USDC,EUR,2021-01-01T00:00:00Z,0.8
+EUR,GBP,2021-01-01T00:00:00Z,0.9
+";
+        let pair = CurrencyPair {
+            base: USDC,
+            quote: GBP,
+        };
+        let header = "base_currency,quote_currency,date_time,rate\n";
+
+        for csv in &[
+            format!("{}{}{}", header, usd, eur),
+            format!("{}{}{}", header, eur, usd),
+        ] {
+            let prices = Prices::read_csv(csv.as_bytes()).unwrap();
+            let price = prices.get(pair.clone(), date()).unwrap();
+
+            assert_eq!(price.rate, dec!(0.72), "Via EUR before USD");
+        }
+    }
+
+    #[test]
+    fn price_via_several_intermediate_currencies() {
+        let csv = "\
+base_currency,quote_currency,date_time,rate
+DOT,USDC,2021-01-01T00:00:00Z,10
+USDC,USD,2021-01-01T00:00:00Z,0.99
+USD,GBP,2021-01-01T00:00:00Z,0.75
+USD,EUR,2021-01-02T00:00:00Z,0.8
+";
+        let prices = Prices::read_csv(csv.as_bytes()).unwrap();
+        let pair = CurrencyPair {
+            base: DOT,
+            quote: GBP,
+        };
+
+        assert_eq!(prices.get(pair, date()).unwrap().rate, dec!(7.425));
+        let no_leg_on_the_date = CurrencyPair {
+            base: DOT,
+            quote: EUR,
+        };
+        assert!(prices.get(no_leg_on_the_date, date()).is_none());
+    }
+
+    #[test]
+    fn no_price_without_final_leg_to_quote() {
+        let prices = Prices::read_csv(PRICES.as_bytes()).unwrap();
+        let pair = CurrencyPair {
+            base: USDC,
+            quote: currencies::EUR,
+        };
+
+        assert!(prices.get(pair, date()).is_none());
+    }
+
+    #[test]
+    fn second_fetch_reads_from_cache() {
+        let dir = temp_cache_dir("cache-hit");
+        let cache = CoingeckoCache::new(&dir, Duration::from_secs(3600));
+
+        let fetched =
+            fetch_daily_prices("bitcoin", "GBP", Some(&cache), |_, _| Ok(prices())).unwrap();
+        let cached = fetch_daily_prices("bitcoin", "GBP", Some(&cache), |_, _| {
+            panic!("Should have read prices from the cache")
+        })
+        .unwrap();
+
+        assert_eq!(cached.prices.len(), 1);
+        assert_eq!(cached.prices[0].timestamp, fetched.prices[0].timestamp);
+        assert_eq!(cached.prices[0].price, dec!(21_345.67));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn expired_cache_is_refetched() {
+        let dir = temp_cache_dir("cache-expired");
+        let cache = CoingeckoCache::new(&dir, Duration::from_secs(0));
+        fetch_daily_prices("bitcoin", "GBP", Some(&cache), |_, _| Ok(prices())).unwrap();
+        std::thread::sleep(Duration::from_millis(10));
+
+        let mut fetched = false;
+        fetch_daily_prices("bitcoin", "GBP", Some(&cache), |_, _| {
+            fetched = true;
+            Ok(prices())
+        })
+        .unwrap();
+
+        assert!(fetched);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    fn coins() -> eyre::Result<Vec<CoingeckoCoin>> {
+        Ok(vec![
+            CoingeckoCoin {
+                id: "omisego".into(),
+                symbol: "omg".into(),
+            },
+            CoingeckoCoin {
+                id: "augur".into(),
+                symbol: "rep".into(),
+            },
+            CoingeckoCoin {
+                id: "republic-token".into(),
+                symbol: "rep".into(),
+            },
+        ])
+    }
+
+    #[test]
+    fn tickers_resolve_to_coingecko_ids() {
+        let ids = CoingeckoIds::default();
+
+        let resolved = ids.resolve(&[BTC, DOT, OMG], coins).unwrap();
+
+        let resolved = resolved
+            .iter()
+            .map(|(asset, id)| (asset.code, id.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            resolved,
+            vec![("BTC", "bitcoin"), ("DOT", "polkadot"), ("OMG", "omisego")]
+        );
+        let known = CoingeckoIds::default()
+            .resolve(&[BTC, DOT], || {
+                panic!("Known tickers shouldn't list the coins")
+            })
+            .unwrap();
+        assert_eq!(known.len(), 2);
+    }
+
+    #[test]
+    fn ambiguous_ticker_requires_an_id() {
+        let err = CoingeckoIds::default().resolve(&[REP], coins).unwrap_err();
+
+        assert!(
+            err.to_string()
+                .contains("More than one Coingecko coin has the symbol REP: augur, republic-token"),
+            "{}",
+            err
+        );
+
+        let ids = CoingeckoIds::read_csv("asset,coingecko_id\nrep,augur\n".as_bytes()).unwrap();
+        let resolved = ids.resolve(&[REP, USDC], coins).unwrap();
+
+        assert_eq!(resolved[0].1, "augur");
+        assert_eq!(resolved[1].1, "usd-coin");
+    }
+
+    #[test]
+    fn prices_are_fetched_in_the_reporting_currency() {
+        let url = mock_server(vec![
+            http_response(
+                "200 OK",
+                "Content-Type: application/json\r\n",
+                r#"["btc","eur","gbp","usd"]"#,
+            ),
+            http_response(
+                "200 OK",
+                "Content-Type: application/json\r\n",
+                r#"{"prices":[[1609459200000,23876.54]]}"#,
+            ),
+        ]);
+
+        let prices = Prices::from_coingecko(
+            &url,
+            &no_delay(),
+            EUR,
+            &[BTC],
+            &CoingeckoIds::default(),
+            None,
+        )
+        .unwrap();
+
+        let pair = CurrencyPair {
+            base: BTC,
+            quote: EUR,
+        };
+        let price = prices.get(pair.clone(), date()).unwrap();
+        assert!(price.pair == pair);
+        assert_eq!(price.rate, dec!(23876.54));
+    }
+
+    #[test]
+    fn unsupported_vs_currency_is_an_error() {
+        let url = mock_server(vec![http_response(
+            "200 OK",
+            "Content-Type: application/json\r\n",
+            r#"["btc","eur","usd"]"#,
+        )]);
+
+        let err = Prices::from_coingecko(
+            &url,
+            &no_delay(),
+            GBP,
+            &[BTC],
+            &CoingeckoIds::default(),
+            None,
+        )
+        .err()
+        .unwrap();
+
+        assert_eq!(err.to_string(), "Coingecko has no prices in GBP");
+    }
+
+    #[test]
+    fn gaps_in_prices_follow_the_policy() {
+        let prices = || {
+            Prices::read_csv(
+                "base_currency,quote_currency,date_time,rate\n\
+                 BTC,GBP,2021-01-01T00:00:00Z,100\n\
+                 BTC,GBP,2021-01-04T00:00:00Z,130\n"
+                    .as_bytes(),
+            )
+            .unwrap()
+        };
+        let rate = |prices: Prices<'static>, day| {
+            let pair = CurrencyPair {
+                base: BTC,
+                quote: GBP,
+            };
+            prices
+                .get(pair, NaiveDate::from_ymd(2021, 1, day))
+                .map(|price| price.rate)
+        };
+
+        assert_eq!(rate(prices(), 2), None);
+        assert_eq!(
+            rate(prices().with_gap_policy(PriceGapPolicy::Error), 4),
+            Some(dec!(130))
+        );
+
+        let carry = || prices().with_gap_policy(PriceGapPolicy::Carry);
+        assert_eq!(rate(carry(), 2), Some(dec!(100)));
+        assert_eq!(rate(carry(), 5), Some(dec!(130)));
+
+        let interpolate = || prices().with_gap_policy(PriceGapPolicy::Interpolate);
+        assert_eq!(rate(interpolate(), 2), Some(dec!(110)));
+        assert_eq!(rate(interpolate(), 3), Some(dec!(120)));
+        assert_eq!(
+            rate(interpolate(), 5),
+            None,
+            "No later price to interpolate to"
+        );
+    }
+
+    #[test]
+    fn prices_are_not_carried_or_interpolated_over_long_gaps() {
+        let prices = |gap_policy| {
+            Prices::read_csv(
+                "base_currency,quote_currency,date_time,rate\n\
+                 BTC,GBP,2021-01-01T00:00:00Z,100\n\
+                 BTC,GBP,2021-01-20T00:00:00Z,290\n"
+                    .as_bytes(),
+            )
+            .unwrap()
+            .with_gap_policy(gap_policy)
+        };
+        let get = |prices: &Prices<'static>, day| {
+            let pair = CurrencyPair {
+                base: BTC,
+                quote: GBP,
+            };
+            prices
+                .get(pair, NaiveDate::from_ymd(2021, 1, day))
+                .map(|price| (price.rate, price.gap))
+        };
+
+        let carry = prices(PriceGapPolicy::Carry);
+        assert_eq!(get(&carry, 1), Some((dec!(100), None)));
+        assert_eq!(
+            get(&carry, 8),
+            Some((dec!(100), Some(PriceGapPolicy::Carry)))
+        );
+        assert_eq!(get(&carry, 9), None, "Stale after 7 days");
+        assert_eq!(get(&prices(PriceGapPolicy::Interpolate), 2), None);
+    }
+}
@@ -1,14 +1,21 @@
+use super::uk;
 use crate::{
-    cmd::prices::{CurrencyPair, Price, Prices},
+    cmd::prices::{CurrencyPair, Price, PriceGapPolicy, PriceLookup, Prices},
     currencies::{Currency, GBP},
-    money::display_amount,
+    money::{display_amount, fixed_amount, FIAT, STABLECOINS},
     trades::{Trade, TradeKey, TradeKind, TradeRecord},
     Money,
 };
-use chrono::{Datelike, Duration, NaiveDate, NaiveDateTime};
+use chrono::{Datelike, Duration, FixedOffset, NaiveDate, NaiveDateTime};
+use color_eyre::eyre;
 use rust_decimal::Decimal;
-use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, fmt, io::Write};
+use serde::{Deserialize, Serialize, Serializer};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    io::{Read, Write},
+    str::FromStr,
+};
 
 pub type Year = i32;
 
@@ -29,6 +36,9 @@ pub struct TaxReport<'a> {
     pub trades: Vec<Trade<'a>>,
     pub years: HashMap<Year, TaxYear<'a>>,
     pub pools: HashMap<String, Pool<'a>>,
+    /// The currency in which all values and gains are calculated
+    pub currency: &'a Currency,
+    opening_pools: Vec<Pool<'a>>,
 }
 
 impl<'a> TaxReport<'a> {
@@ -36,6 +46,8 @@ impl<'a> TaxReport<'a> {
         trades: Vec<Trade<'a>>,
         gains: Vec<TaxEvent<'a>>,
         pools: HashMap<String, Pool<'a>>,
+        opening_pools: Vec<Pool<'a>>,
+        currency: &'a Currency,
     ) -> Self {
         let mut tax_years = HashMap::new();
         for gain in gains.iter() {
@@ -47,26 +59,226 @@ impl<'a> TaxReport<'a> {
             trades: trades.to_vec(),
             years: tax_years,
             pools,
+            currency,
+            opening_pools,
         }
     }
 
-    pub(crate) fn gains(&self, year: Option<Year>) -> Gains {
-        let mut gains = year
-            .and_then(|y| self.years.get(&y).map(|ty| ty.events.clone()))
-            .unwrap_or(
-                self.years
-                    .iter()
-                    .flat_map(|(_, y)| y.events.clone())
-                    .collect::<Vec<_>>(),
-            );
-        gains.sort_by(|g1, g2| g1.trade.date_time.cmp(&g2.trade.date_time));
-        Gains { year, gains }
+    pub(crate) fn gains(&self, year: Option<Year>) -> Gains<'a> {
+        let gains = match year {
+            Some(y) => self
+                .years
+                .get(&y)
+                .map(|ty| ty.events.clone())
+                .unwrap_or_default(),
+            None => self
+                .years
+                .iter()
+                .flat_map(|(_, y)| y.events.clone())
+                .collect::<Vec<_>>(),
+        };
+        self.sorted_gains(year, gains)
+    }
+
+    /// The events on the days from `from` to `to` inclusive, in local time, regardless of the tax
+    /// year. Either end of the range may be left open.
+    pub(crate) fn gains_between(
+        &self,
+        from: Option<NaiveDate>,
+        to: Option<NaiveDate>,
+    ) -> Gains<'a> {
+        let gains = self
+            .years
+            .values()
+            .flat_map(|ty| ty.events.iter())
+            .filter(|event| from.map_or(true, |from| event.date >= from))
+            .filter(|event| to.map_or(true, |to| event.date <= to))
+            .cloned()
+            .collect::<Vec<_>>();
+        self.sorted_gains(None, gains)
+    }
+
+    fn sorted_gains(&self, year: Option<Year>, mut gains: Vec<TaxEvent<'a>>) -> Gains<'a> {
+        gains.sort_by(|g1, g2| {
+            g1.trade
+                .date_time
+                .cmp(&g2.trade.date_time)
+                .then_with(|| g1.trade.id().cmp(&g2.trade.id()))
+        });
+        Gains {
+            year,
+            gains,
+            currency: self.currency,
+        }
+    }
+
+    /// Calculates the taxable gain for the given tax year, after setting off losses of the same
+    /// year, the annual exempt amount and any losses carried forward from earlier years.
+    ///
+    /// Losses from earlier years in this report are added to those `brought_forward` from before
+    /// the report. Returns the taxable gain and the losses remaining to be carried forward.
+    pub(crate) fn net_gain_after_losses(
+        &self,
+        year: Year,
+        brought_forward: Money<'a>,
+    ) -> (Money<'a>, Money<'a>) {
+        let mut earlier_years = self
+            .years
+            .keys()
+            .filter(|y| **y < year)
+            .cloned()
+            .collect::<Vec<_>>();
+        earlier_years.sort();
+
+        let mut losses = brought_forward;
+        for earlier_year in earlier_years {
+            let net_gain = self.gains(Some(earlier_year)).total_gain();
+            let (_, remaining_losses) = apply_losses(earlier_year, net_gain, losses);
+            losses = remaining_losses;
+        }
+        apply_losses(year, self.gains(Some(year)).total_gain(), losses)
+    }
+
+    /// The tax years in which there were any disposals, in order
+    pub(crate) fn disposal_years(&self) -> Vec<Year> {
+        let mut years = self
+            .years
+            .values()
+            .filter(|ty| ty.events.iter().any(TaxEvent::is_disposal))
+            .map(|ty| ty.year)
+            .collect::<Vec<_>>();
+        years.sort();
+        years
+    }
+
+    /// The pools as they stood at the end of the given tax year, sorted by currency.
+    pub fn pools_at_end_of(&self, year: Year) -> Vec<Pool<'a>> {
+        let mut years = self
+            .years
+            .values()
+            .filter(|ty| ty.year <= year)
+            .collect::<Vec<_>>();
+        years.sort_by_key(|ty| ty.year);
+
+        let mut pools = self
+            .opening_pools
+            .iter()
+            .map(|pool| (pool.key(), pool.clone()))
+            .collect::<HashMap<_, _>>();
+        for event in years.iter().flat_map(|ty| ty.events.iter()) {
+            for pool in event.buy_pool.iter().chain(event.sell_pool.iter()) {
+                pools.insert(pool.key(), pool.clone());
+            }
+        }
+        let mut pools = pools.into_iter().collect::<Vec<_>>();
+        pools.sort_by(|(key1, _), (key2, _)| key1.cmp(key2));
+        pools.into_iter().map(|(_, pool)| pool).collect()
+    }
+
+    /// The average cost of one unit of the asset in its pool after all the trades, or `None` if
+    /// none of the asset is held.
+    pub fn average_cost_basis(&self, asset: &str) -> Option<Decimal> {
+        self.pools
+            .get(asset)
+            .filter(|pool| pool.total.is_positive())
+            .map(Pool::cost_basis)
+    }
+
+    /// The warnings of all the disposals in the report, in date order
+    pub fn warnings(&self) -> Vec<Warning> {
+        self.gains(None).warnings()
+    }
+
+    /// Writes a stable, sorted plain text representation of the disposals and the final pool
+    /// balances, suitable for diffing the output of different versions.
+    pub fn write_canonical<W>(&self, year: Option<Year>, mut writer: W) -> color_eyre::Result<()>
+    where
+        W: Write,
+    {
+        let gains = self.gains(year);
+        for disposal in gains.disposals() {
+            writeln!(
+                writer,
+                "disposal {} id={} tax_year={} asset={} quantity={} proceeds={} allowable_costs={} fees={} gain={}",
+                disposal.trade.date_time.format("%Y-%m-%dT%H:%M:%S"),
+                disposal.trade.id(),
+                disposal.tax_year,
+                disposal.trade.sell.currency().code,
+                fixed_amount(&disposal.trade.sell),
+                fixed_amount(disposal.proceeds()),
+                fixed_amount(disposal.allowable_costs()),
+                fixed_amount(disposal.fee()),
+                fixed_amount(&disposal.gain()),
+            )?;
+        }
+        let mut pools = self.pools.iter().collect::<Vec<_>>();
+        pools.sort_by_key(|(key, _)| *key);
+        for (key, pool) in pools {
+            writeln!(
+                writer,
+                "pool {} quantity={} costs={}",
+                key,
+                fixed_amount(&pool.total),
+                fixed_amount(&pool.costs),
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// An issue with a disposal which may make the report incorrect, with the context to find it
+#[derive(Clone, Debug, PartialEq, derive_more::Display)]
+#[display(fmt = "{} {} ({}): {}", date_time, asset, trade_id, message)]
+pub struct Warning {
+    pub date_time: NaiveDateTime,
+    pub asset: String,
+    pub trade_id: String,
+    pub message: String,
+}
+
+impl Warning {
+    fn of_disposal<'e>(disposal: &'e TaxEvent) -> impl Iterator<Item = Warning> + 'e {
+        disposal.warnings.iter().map(move |message| Warning {
+            date_time: disposal.trade.date_time,
+            asset: disposal.trade.sell.currency().code.to_string(),
+            trade_id: disposal.trade.id(),
+            message: message.clone(),
+        })
     }
 }
 
+/// Sets off losses carried forward against the net gain of a year, following HMRC ordering: the
+/// losses are only used to reduce the gain down to the annual exempt amount. The gain and losses
+/// must be in GBP, like the annual exempt amount.
+///
+/// Returns the taxable gain and the losses remaining to be carried forward.
+fn apply_losses<'a>(year: Year, net_gain: Money<'a>, losses: Money<'a>) -> (Money<'a>, Money<'a>) {
+    debug_assert!(
+        net_gain.currency() == GBP && losses.currency() == GBP,
+        "The annual exempt amount is only known in GBP"
+    );
+    let zero = Money::from_major(0, GBP);
+    if net_gain <= zero {
+        // a net loss for the year is added to the losses carried forward
+        return (zero.clone(), losses + (zero - net_gain));
+    }
+    let annual_exempt_amount = uk::annual_exempt_amount(year);
+    if net_gain <= annual_exempt_amount {
+        return (zero, losses);
+    }
+    let excess = net_gain - annual_exempt_amount;
+    let used_losses = if losses < excess {
+        losses.clone()
+    } else {
+        excess.clone()
+    };
+    (excess - used_losses.clone(), losses - used_losses)
+}
+
 pub struct Gains<'a> {
     pub year: Option<Year>,
     pub gains: Vec<TaxEvent<'a>>,
+    pub currency: &'a Currency,
 }
 
 impl<'a> IntoIterator for Gains<'a> {
@@ -79,42 +291,178 @@ impl<'a> IntoIterator for Gains<'a> {
 }
 
 impl<'a> Gains<'a> {
+    /// Events which dispose of an asset, excluding plain acquisitions with the reporting currency
+    fn disposals(&self) -> impl Iterator<Item = &TaxEvent<'a>> {
+        self.gains.iter().filter(|g| g.is_disposal())
+    }
+
     pub(crate) fn len(&self) -> usize {
-        self.gains.len()
+        self.disposals().count()
     }
 
     pub(crate) fn total_proceeds(&self) -> Money<'a> {
-        self.gains.iter().fold(Money::from_major(0, GBP), |acc, g| {
-            acc + g.proceeds().clone()
-        })
+        self.disposals()
+            .fold(Money::from_major(0, self.currency), |acc, g| {
+                acc + g.proceeds().clone()
+            })
+    }
+
+    /// Total acquisition costs of the disposals, including the fees of the acquisitions
+    pub(crate) fn total_acquisition_costs(&self) -> Money<'a> {
+        self.disposals()
+            .fold(Money::from_major(0, self.currency), |acc, g| {
+                acc + g.allowable_costs().clone()
+            })
     }
 
+    /// Total allowable costs of the disposals: the acquisition costs plus the fees of disposing
     pub(crate) fn total_allowable_costs(&self) -> Money<'a> {
-        self.gains.iter().fold(Money::from_major(0, GBP), |acc, g| {
-            acc + g.allowable_costs().clone()
-        })
+        self.total_acquisition_costs() + self.total_fees()
+    }
+
+    /// Total incidental costs (fees) of the disposals
+    pub(crate) fn total_fees(&self) -> Money<'a> {
+        self.disposals()
+            .fold(Money::from_major(0, self.currency), |acc, g| {
+                acc + g.fee().clone()
+            })
     }
 
     pub(crate) fn total_gain(&self) -> Money<'a> {
-        self.gains
+        self.disposals()
+            .fold(Money::from_major(0, self.currency), |acc, g| acc + g.gain())
+    }
+
+    /// Total of the disposals made at a gain, without setting off any losses
+    pub(crate) fn total_gains_only(&self) -> Money<'a> {
+        let zero = Money::from_major(0, self.currency);
+        self.disposals()
+            .map(|g| g.gain())
+            .filter(|gain| *gain > zero)
+            .fold(zero.clone(), |acc, gain| acc + gain)
+    }
+
+    /// Whether any of the disposals were made on or after the given local day
+    pub(crate) fn any_disposed_from(&self, date: NaiveDate) -> bool {
+        self.disposals().any(|g| g.date >= date)
+    }
+
+    /// Writes the disposals with how they were matched with acquisitions as a json array
+    pub(crate) fn write_json<W: Write>(&self, writer: W) -> color_eyre::Result<()> {
+        let records = self
+            .disposals()
+            .map(DisposalRecord::from)
+            .collect::<Vec<_>>();
+        serde_json::to_writer_pretty(writer, &records)?;
+        Ok(())
+    }
+
+    /// Writes the disposals as csv, one row per disposal
+    pub(crate) fn write_disposals_csv<W: Write>(&self, writer: W) -> color_eyre::Result<()> {
+        DisposalRecord::write_csv(self.disposals().map(DisposalRecord::from), writer)
+    }
+
+    /// Total of the disposals made at a loss, as a positive amount
+    pub(crate) fn total_losses_only(&self) -> Money<'a> {
+        let zero = Money::from_major(0, self.currency);
+        self.disposals()
+            .map(|g| g.gain())
+            .filter(|gain| *gain < zero)
+            .fold(zero.clone(), |acc, gain| acc - gain)
+    }
+
+    /// Fails listing every disposal with warnings, e.g. where there was not enough of the asset
+    /// in the pool because of a gap in the history of acquisitions
+    pub(crate) fn ensure_no_warnings(&self) -> color_eyre::Result<()> {
+        let warnings = self
+            .warnings()
             .iter()
-            .fold(Money::from_major(0, GBP), |acc, g| acc + g.gain())
+            .map(|warning| warning.to_string())
+            .collect::<Vec<_>>();
+        if warnings.is_empty() {
+            return Ok(());
+        }
+        Err(eyre::eyre!(
+            "Found {} warnings:\n{}",
+            warnings.len(),
+            warnings.join("\n")
+        ))
+    }
+
+    /// The warnings of the disposals, in date order
+    pub(crate) fn warnings(&self) -> Vec<Warning> {
+        self.disposals().flat_map(Warning::of_disposal).collect()
+    }
+
+    /// Totals of the disposals of each asset, largest gain or loss first
+    pub(crate) fn by_asset(&self) -> Vec<AssetGains<'a>> {
+        let mut assets: HashMap<&str, AssetGains<'a>> = HashMap::new();
+        for disposal in self.disposals() {
+            let asset = disposal.trade.sell.currency();
+            let totals = assets.entry(asset.code).or_insert_with(|| AssetGains {
+                asset,
+                disposals: 0,
+                proceeds: Money::from_major(0, self.currency),
+                costs: Money::from_major(0, self.currency),
+                gain: Money::from_major(0, self.currency),
+                has_warnings: false,
+            });
+            totals.disposals += 1;
+            totals.proceeds += disposal.proceeds().clone();
+            totals.costs += disposal.allowable_costs().clone() + disposal.fee().clone();
+            totals.gain += disposal.gain();
+            totals.has_warnings |= !disposal.warnings().is_empty();
+        }
+        let mut assets = assets
+            .into_iter()
+            .map(|(_, totals)| totals)
+            .collect::<Vec<_>>();
+        assets.sort_by(|a1, a2| {
+            a2.gain
+                .amount()
+                .abs()
+                .cmp(&a1.gain.amount().abs())
+                .then_with(|| a1.asset.code.cmp(a2.asset.code))
+        });
+        assets
     }
 }
 
+/// The totals of the disposals of a single asset
+pub struct AssetGains<'a> {
+    pub asset: &'a Currency,
+    pub disposals: usize,
+    pub proceeds: Money<'a>,
+    /// The allowable costs including fees
+    pub costs: Money<'a>,
+    pub gain: Money<'a>,
+    /// Whether any of the disposals has warnings which may make it incorrect
+    pub has_warnings: bool,
+}
+
 #[derive(Clone)]
 pub struct TaxEvent<'a> {
     trade: Trade<'a>,
     tax_year: Year,
+    /// The day of the trade in local time
+    date: NaiveDate,
     buy_value: Money<'a>,
     sell_value: Money<'a>,
     fee_value: Money<'a>,
     price: Price<'a>,
     allowable_costs: Money<'a>,
+    matching: Vec<MatchingComponent<'a>>,
+    warnings: Vec<String>,
     buy_pool: Option<Pool<'a>>,
     sell_pool: Option<Pool<'a>>,
 }
 impl<'a> TaxEvent<'a> {
+    /// Whether the trade disposes of an asset, i.e. it sells something other than the reporting
+    /// currency, or a stablecoin when they are treated as fiat
+    pub fn is_disposal(&self) -> bool {
+        self.sell_pool.is_some()
+    }
+
     pub fn proceeds(&self) -> &Money<'a> {
         &self.sell_value // todo: fees
     }
@@ -127,6 +475,16 @@ impl<'a> TaxEvent<'a> {
         &self.fee_value
     }
 
+    /// How the disposed amount was matched with acquisitions, empty if not a disposal
+    pub fn matching(&self) -> &[MatchingComponent<'a>] {
+        &self.matching
+    }
+
+    /// Issues found while calculating the event which may make it incorrect
+    pub fn warnings(&self) -> &[String] {
+        &self.warnings
+    }
+
     pub fn gain(&self) -> Money<'a> {
         self.sell_value.clone() - self.allowable_costs.clone() - self.fee().clone()
     }
@@ -146,6 +504,157 @@ impl<'a> TaxEvent<'a> {
     }
 }
 
+/// The rule by which part of a disposal was matched with an acquisition
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MatchingRule {
+    /// Acquired on the same day as the disposal
+    SameDay,
+    /// Acquired within 30 days after the disposal
+    BedAndBreakfast,
+    /// The Section 104 pool
+    Pool,
+}
+
+/// Part of a disposal matched with an acquisition, or the pool, and its allowable cost
+#[derive(Clone, Debug, Serialize)]
+pub struct MatchingComponent<'a> {
+    pub rule: MatchingRule,
+    #[serde(serialize_with = "serialize_amount")]
+    pub quantity: Money<'a>,
+    #[serde(serialize_with = "serialize_amount")]
+    pub cost: Money<'a>,
+    /// The date of the matched acquisition, none for the pool
+    #[serde(serialize_with = "serialize_date")]
+    pub matched_date: Option<NaiveDate>,
+    /// The id of the matched acquisition, none for the pool
+    pub matched_id: Option<String>,
+}
+
+fn serialize_amount<S: Serializer>(amount: &Money, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&fixed_amount(amount))
+}
+
+fn serialize_date<S: Serializer>(
+    date: &Option<NaiveDate>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    match date {
+        Some(date) => serializer.serialize_str(&date.to_string()),
+        None => serializer.serialize_none(),
+    }
+}
+
+/// A disposal with its matching audit trail, for the json output
+#[derive(Serialize)]
+struct DisposalRecord<'e, 'a> {
+    id: String,
+    date_time: String,
+    tax_year: Year,
+    /// The exchange of the disposal, empty if unknown
+    account: String,
+    /// The id of the trade in the exchange's export, if known
+    source_id: Option<String>,
+    asset: &'a str,
+    #[serde(serialize_with = "serialize_amount")]
+    quantity: &'e Money<'a>,
+    #[serde(serialize_with = "serialize_amount")]
+    proceeds: &'e Money<'a>,
+    #[serde(serialize_with = "serialize_amount")]
+    allowable_costs: &'e Money<'a>,
+    #[serde(serialize_with = "serialize_amount")]
+    fees: &'e Money<'a>,
+    #[serde(serialize_with = "serialize_amount")]
+    gain: Money<'a>,
+    matching: &'e [MatchingComponent<'a>],
+    warnings: &'e [String],
+}
+
+impl<'e, 'a> From<&'e TaxEvent<'a>> for DisposalRecord<'e, 'a> {
+    fn from(disposal: &'e TaxEvent<'a>) -> Self {
+        DisposalRecord {
+            id: disposal.trade.id(),
+            date_time: disposal
+                .trade
+                .date_time
+                .format("%Y-%m-%dT%H:%M:%S")
+                .to_string(),
+            tax_year: disposal.tax_year,
+            account: disposal.trade.exchange.clone().unwrap_or_default(),
+            source_id: disposal.trade.source_id.clone(),
+            asset: disposal.trade.sell.currency().code,
+            quantity: &disposal.trade.sell,
+            proceeds: disposal.proceeds(),
+            allowable_costs: disposal.allowable_costs(),
+            fees: disposal.fee(),
+            gain: disposal.gain(),
+            matching: disposal.matching(),
+            warnings: disposal.warnings(),
+        }
+    }
+}
+
+impl<'e, 'a> DisposalRecord<'e, 'a> {
+    /// Writes the disposals as flat csv rows, summarising how each was matched
+    fn write_csv<I, W>(records: I, writer: W) -> color_eyre::Result<()>
+    where
+        I: IntoIterator<Item = DisposalRecord<'e, 'a>>,
+        W: Write,
+    {
+        let mut wtr = csv::Writer::from_writer(writer);
+        for record in records {
+            let matching_rule_summary = record
+                .matching
+                .iter()
+                .map(|component| {
+                    let rule = match component.rule {
+                        MatchingRule::SameDay => "same_day",
+                        MatchingRule::BedAndBreakfast => "bed_and_breakfast",
+                        MatchingRule::Pool => "pool",
+                    };
+                    format!("{} {}", rule, quantity(&component.quantity))
+                })
+                .collect::<Vec<_>>()
+                .join("; ");
+            wtr.serialize(DisposalCsvRecord {
+                date: record.date_time[..10].to_string(),
+                account: record.account,
+                source_id: record.source_id.unwrap_or_default(),
+                asset: record.asset.to_string(),
+                quantity: quantity(record.quantity),
+                proceeds: fixed_amount(record.proceeds),
+                allowable_cost: fixed_amount(record.allowable_costs),
+                fees: fixed_amount(record.fees),
+                gain: fixed_amount(&record.gain),
+                matching_rule_summary,
+                warnings: record.warnings.join("; "),
+            })?;
+        }
+        wtr.flush()?;
+        Ok(())
+    }
+}
+
+/// Quantity rounded to 8 dp without trailing zeros
+fn quantity(amount: &Money) -> String {
+    amount.amount().round_dp(8).normalize().to_string()
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct DisposalCsvRecord {
+    date: String,
+    account: String,
+    source_id: String,
+    asset: String,
+    quantity: String,
+    proceeds: String,
+    allowable_cost: String,
+    fees: String,
+    gain: String,
+    matching_rule_summary: String,
+    warnings: String,
+}
+
 #[derive(Serialize, Deserialize)]
 struct TaxEventRecord {
     date_time: String,
@@ -157,8 +666,8 @@ struct TaxEventRecord {
     sell_amt: String,
     price: String,
     rate: String,
-    buy_gbp: String,
-    sell_gbp: String,
+    buy_value: String,
+    sell_value: String,
     fee: String,
     allowable_cost: String,
     gain: String,
@@ -179,8 +688,8 @@ impl<'a> From<TaxEvent<'a>> for TaxEventRecord {
             sell_amt: display_amount(&tax_event.trade.sell),
             price: tax_event.price.pair.to_string(),
             rate: tax_event.price.rate.to_string(),
-            buy_gbp: display_amount(&tax_event.buy_value),
-            sell_gbp: display_amount(&tax_event.sell_value),
+            buy_value: display_amount(&tax_event.buy_value),
+            sell_value: display_amount(&tax_event.sell_value),
             fee: display_amount(tax_event.fee()),
             allowable_cost: display_amount(tax_event.allowable_costs()),
             gain: display_amount(&tax_event.gain()),
@@ -209,16 +718,32 @@ pub struct Pool<'a> {
     currency: &'a Currency,
     total: Money<'a>,
     costs: Money<'a>,
+    account: Option<String>,
 }
 impl<'a> Pool<'a> {
-    fn new(currency: &'a Currency) -> Self {
+    fn new(currency: &'a Currency, cost_currency: &'a Currency) -> Self {
         Pool {
             currency,
             total: Money::from_major(0, currency),
-            costs: Money::from_major(0, GBP),
+            costs: Money::from_major(0, cost_currency),
+            account: None,
         }
     }
 
+    /// The key of the pool in [`TaxReport::pools`]: the asset, prefixed with the account when
+    /// pooling by account e.g. `Kraken:BTC`
+    pub fn key(&self) -> String {
+        match self.account {
+            Some(ref account) => format!("{}:{}", account, self.currency.code),
+            None => self.currency.code.to_string(),
+        }
+    }
+
+    /// The account of the pool when pooling by account
+    pub fn account(&self) -> Option<&str> {
+        self.account.as_deref()
+    }
+
     fn buy(&mut self, buy: &Money<'a>, costs: &Money<'a>) {
         self.total = self.total.clone() + buy.clone();
         self.costs = self.costs.clone() + costs.clone();
@@ -230,13 +755,17 @@ impl<'a> Pool<'a> {
         log::debug!("Pool: {:?}", self);
     }
 
-    fn sell(&mut self, sell: Money<'a>) -> Money<'a> {
-        let (costs, new_total, new_costs) = if sell > self.total {
+    /// Removes the amount from the pool, returning its proportion of the pool costs.
+    ///
+    /// If a `dust_threshold` is given and the quantity left in the pool is below it, the pool is
+    /// emptied and the remaining costs are included in those of the disposal.
+    fn sell(&mut self, sell: Money<'a>, dust_threshold: Option<Decimal>) -> Money<'a> {
+        let (mut costs, mut new_total, mut new_costs) = if sell > self.total {
             // selling more than is in the pool
             (
                 self.costs.clone(),
                 Money::from_major(0, &self.currency),
-                Money::from_major(0, GBP),
+                Money::from_major(0, self.costs.currency()),
             )
         } else {
             let perc = sell.amount() / self.total.amount();
@@ -245,6 +774,20 @@ impl<'a> Pool<'a> {
             let new_costs = self.costs.clone() - costs.clone();
             (costs, new_total, new_costs)
         };
+        if let Some(threshold) = dust_threshold {
+            let remaining = *new_total.amount();
+            if remaining > Decimal::new(0, 0) && remaining < threshold {
+                log::warn!(
+                    "Zeroing {} pool dust of {}, costs: {}",
+                    self.currency.code,
+                    display_amount(&new_total),
+                    display_amount(&new_costs)
+                );
+                costs = costs + new_costs;
+                new_total = Money::from_major(0, &self.currency);
+                new_costs = Money::from_major(0, self.costs.currency());
+            }
+        }
         self.total = new_total;
         self.costs = new_costs;
         log::debug!(
@@ -256,7 +799,22 @@ impl<'a> Pool<'a> {
         costs
     }
 
-    fn cost_basis(&self) -> Decimal {
+    pub fn currency(&self) -> &'a Currency {
+        self.currency
+    }
+
+    /// The quantity of the asset held in the pool
+    pub fn total(&self) -> &Money<'a> {
+        &self.total
+    }
+
+    /// The pooled allowable costs of the asset
+    pub fn costs(&self) -> &Money<'a> {
+        &self.costs
+    }
+
+    /// The average cost of one unit of the asset
+    pub fn cost_basis(&self) -> Decimal {
         use rust_decimal::prelude::Zero;
         self.costs
             .amount()
@@ -277,195 +835,875 @@ impl<'a> fmt::Debug for Pool<'a> {
     }
 }
 
-pub fn calculate<'a>(
+/// Which acquisitions disposals are matched with before the Section 104 pool
+#[derive(Clone, Debug, PartialEq)]
+pub struct MatchingConfig {
+    /// Match acquisitions on the same day as the disposal
+    pub same_day: bool,
+    /// Match acquisitions in this many days after the disposal, the "bed and breakfast" rule
+    pub bnb_days: u32,
+}
+
+impl MatchingConfig {
+    /// Match every disposal against the pool, skipping the same day and 30 day rules.
+    ///
+    /// This is not HMRC compliant if an asset is reacquired within 30 days of a disposal, but can
+    /// be useful for comparison and debugging.
+    pub fn pool_only() -> Self {
+        MatchingConfig {
+            same_day: false,
+            bnb_days: 0,
+        }
+    }
+
+    /// Whether an acquisition on the given date is in the bed and breakfast window of a disposal
+    fn in_bnb_window(&self, disposal: NaiveDate, acquisition: NaiveDate) -> bool {
+        acquisition > disposal && acquisition <= disposal + Duration::days(self.bnb_days.into())
+    }
+}
+
+impl Default for MatchingConfig {
+    /// The UK rules, same day then within 30 days
+    fn default() -> Self {
+        MatchingConfig {
+            same_day: true,
+            bnb_days: 30,
+        }
+    }
+}
+
+/// How the fee of a trade between two assets is allowed
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FeeAllocation {
+    /// The whole fee is an incidental cost of the disposal
+    Disposal,
+    /// The fee is split between the disposal and the cost of the acquisition, in proportion to
+    /// the value of each leg
+    Split,
+}
+
+/// Options controlling how disposals are matched with acquisitions
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// The acquisitions disposals are matched with before the pool
+    pub matching: MatchingConfig,
+    /// Keep a separate pool of each asset for each exchange, instead of a single Section 104
+    /// pool. Opening pools and trades without an exchange share a pool with no account.
+    ///
+    /// HMRC requires a single pool per asset, but this can help to reconcile with exchange
+    /// balances, or for regimes which segregate pools.
+    pub pool_by_account: bool,
+    /// The currency in which costs, proceeds and gains are calculated. Trades with this currency
+    /// are acquisitions or disposals at their face value, all other amounts are converted with
+    /// prices quoted in it.
+    pub reporting_currency: &'static Currency,
+    /// How the fees of trades between two assets are allowed. Fees of buying an asset with fiat
+    /// are always added to its cost, and of selling one for fiat allowed against the disposal.
+    pub fee_allocation: FeeAllocation,
+    /// Pools left with less than this quantity after a disposal are treated as fully disposed
+    /// of, to avoid tiny remainders with a phantom cost basis.
+    pub dust_threshold: Option<Decimal>,
+    /// Pools held before the first trade, e.g. when migrating from another tool
+    pub opening_pools: Vec<OpeningPool>,
+    /// Treat stablecoins like fiat, valued at this fixed price in the reporting currency, so
+    /// trading in and out of them is not a disposal.
+    ///
+    /// HMRC treats stablecoins as assets like any other, so this should be `None` for a
+    /// compliant report.
+    pub stablecoin_peg: Option<Decimal>,
+    /// The time zone in which the days of trades are taken, for the same day and 30 day rules and
+    /// the tax year. Trade times are in UTC, while HMRC uses the UK day.
+    pub time_zone: TimeZone,
+    /// The date of a snapshot of the pools, given as the opening pools. Trades on or before it
+    /// are already accounted for in the pools, and are only used to match disposals with
+    /// acquisitions after it under the 30 day rule.
+    pub snapshot_date: Option<NaiveDate>,
+    /// How many days after the local day of a trade the price it is valued at may be from,
+    /// before warning that the valuation looks ahead
+    pub look_ahead_tolerance: Duration,
+}
+
+impl Config {
+    /// Whether the currency is treated as fiat, so it is neither pooled nor disposed of. Fiat
+    /// other than the reporting currency is valued at its exchange rate from the prices.
+    fn is_fiat(&self, currency: &Currency) -> bool {
+        currency == self.reporting_currency
+            || FIAT.contains(&currency.code)
+            || (self.stablecoin_peg.is_some() && STABLECOINS.contains(&currency.code))
+    }
+
+    /// The account of the pool of the trade's assets, when pooling by account
+    fn account(&self, trade: &Trade) -> Option<String> {
+        if self.pool_by_account {
+            trade.exchange.clone()
+        } else {
+            None
+        }
+    }
+
+    /// The time of the trade in the time zone
+    fn local_time(&self, trade: &Trade) -> NaiveDateTime {
+        self.time_zone.local_time(trade.date_time)
+    }
+
+    /// The day of the trade in local time
+    fn date(&self, trade: &Trade) -> NaiveDate {
+        self.local_time(trade).date()
+    }
+
+    /// Whether the trade is already accounted for in the snapshot of the opening pools
+    fn is_before_snapshot(&self, trade: &Trade) -> bool {
+        self.snapshot_date
+            .map_or(false, |date| self.date(trade) <= date)
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            matching: MatchingConfig::default(),
+            pool_by_account: false,
+            reporting_currency: GBP,
+            fee_allocation: FeeAllocation::Disposal,
+            dust_threshold: None,
+            opening_pools: Vec::new(),
+            stablecoin_peg: None,
+            time_zone: TimeZone::default(),
+            snapshot_date: None,
+            look_ahead_tolerance: Duration::zero(),
+        }
+    }
+}
+
+/// The time zone in which the days of trades are taken
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TimeZone {
+    /// The same offset from UTC for every trade, whatever the time of year
+    Fixed(FixedOffset),
+    /// UK time, i.e. GMT or British Summer Time depending on the date of the trade
+    Uk,
+}
+
+impl TimeZone {
+    /// The local time in the time zone of a UTC time
+    pub fn local_time(&self, utc: NaiveDateTime) -> NaiveDateTime {
+        match self {
+            TimeZone::Fixed(offset) => utc + *offset,
+            TimeZone::Uk => uk::local_time(utc),
+        }
+    }
+}
+
+impl Default for TimeZone {
+    fn default() -> Self {
+        TimeZone::Fixed(FixedOffset::east(0))
+    }
+}
+
+impl FromStr for TimeZone {
+    type Err = String;
+
+    /// Parses `uk`, or a fixed offset of the form `+HH:MM` or `-HH:MM`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid time zone {}, expected uk or e.g. +01:00", s);
+        if s.eq_ignore_ascii_case("uk") || s == "Europe/London" {
+            return Ok(TimeZone::Uk);
+        }
+        let (sign, offset) = match s.chars().next() {
+            Some('+') => (1, &s[1..]),
+            Some('-') => (-1, &s[1..]),
+            _ => return Err(invalid()),
+        };
+        let mut parts = offset.splitn(2, ':');
+        let (hours, minutes) = match (parts.next(), parts.next()) {
+            (Some(hours), Some(minutes)) => (
+                hours.parse::<i32>().map_err(|_| invalid())?,
+                minutes.parse::<i32>().map_err(|_| invalid())?,
+            ),
+            _ => return Err(invalid()),
+        };
+        FixedOffset::east_opt(sign * (hours * 3600 + minutes * 60))
+            .filter(|_| hours >= 0 && (0..60).contains(&minutes))
+            .map(TimeZone::Fixed)
+            .ok_or_else(invalid)
+    }
+}
+
+/// The balance of a pool before the first trade
+#[derive(Clone, Debug)]
+pub struct OpeningPool {
+    pub currency: &'static Currency,
+    pub quantity: Decimal,
+    /// The pooled allowable costs, in the reporting currency
+    pub cost: Decimal,
+    /// The date from which the pool balance is known
+    pub acquisition_date: NaiveDate,
+}
+
+#[derive(Deserialize)]
+struct OpeningPoolRecord {
+    asset: String,
+    quantity: Decimal,
+    #[serde(alias = "cost_gbp")]
+    cost: Decimal,
+    acquisition_date: String,
+}
+
+impl OpeningPool {
+    /// Reads opening pools from a csv file with the columns `asset,quantity,cost,acquisition_date`,
+    /// the cost in the reporting currency and the date as `YYYY-MM-DD`. The cost column of older
+    /// files named `cost_gbp` is also accepted.
+    pub fn read_csv<R: Read>(reader: R) -> color_eyre::Result<Vec<OpeningPool>> {
+        let mut rdr = csv::Reader::from_reader(reader);
+        rdr.deserialize::<OpeningPoolRecord>()
+            .map(|record| {
+                let record = record?;
+                let currency = crate::currencies::find(&record.asset).ok_or_else(|| {
+                    eyre::eyre!("Unsupported opening pool asset {}", record.asset)
+                })?;
+                let acquisition_date =
+                    NaiveDate::parse_from_str(&record.acquisition_date, "%Y-%m-%d")?;
+                Ok(OpeningPool {
+                    currency,
+                    quantity: record.quantity,
+                    cost: record.cost,
+                    acquisition_date,
+                })
+            })
+            .collect()
+    }
+}
+
+pub fn calculate<'a, P: PriceLookup<'a>>(
+    trades: Vec<Trade<'a>>,
+    prices: &P,
+    config: &Config,
+) -> color_eyre::Result<TaxReport<'a>> {
+    calculate_assets(trades, prices, config, cfg!(feature = "parallel"))
+}
+
+fn calculate_assets<'a, P: PriceLookup<'a>>(
     mut trades: Vec<Trade<'a>>,
-    prices: &'a Prices<'a>,
+    prices: &P,
+    config: &Config,
+    parallel: bool,
 ) -> color_eyre::Result<TaxReport<'a>> {
+    let currency = config.reporting_currency;
     let mut pools = HashMap::new();
+    for opening in config.opening_pools.iter() {
+        if config.snapshot_date.is_none()
+            && trades.iter().any(|t| {
+                config.date(t) < opening.acquisition_date && t.sell.currency() == opening.currency
+            })
+        {
+            log::warn!(
+                "{} disposed of before the opening pool date {}",
+                opening.currency.code,
+                opening.acquisition_date
+            );
+        }
+        let mut pool = Pool::new(opening.currency, currency);
+        pool.buy(
+            &Money::from_decimal(opening.quantity, opening.currency),
+            &Money::from_decimal(opening.cost, currency),
+        );
+        pools.insert(opening.currency.code.to_string(), pool);
+    }
+    let opening_pools = pools.values().cloned().collect::<Vec<_>>();
+    if let Some(warning) = snapshot_window_warning(&trades, config) {
+        log::warn!("{}", warning);
+    }
+
+    // break ties by id, so the matching of trades at the same instant doesn't depend on the
+    // order of the input
+    trades.sort_by_cached_key(|trade| (trade.date_time, trade.id()));
+    let mut unpriced = BTreeMap::new();
+    let mut trades_with_prices = Vec::new();
+    for trade in trades.iter() {
+        match get_price(trade, prices, config) {
+            Some(price) => trades_with_prices.push((trade, price)),
+            None => {
+                let asset = match trade.kind {
+                    TradeKind::Buy => trade.sell.currency().code,
+                    TradeKind::Sell => trade.buy.currency().code,
+                };
+                unpriced.entry(asset).or_insert(config.date(trade));
+            }
+        }
+    }
+    if !unpriced.is_empty() {
+        let assets = unpriced
+            .iter()
+            .map(|(asset, date)| format!("{} from {}", asset, date))
+            .collect::<Vec<_>>();
+        return Err(eyre::eyre!(
+            "No prices in {} for {}",
+            currency.code,
+            assets.join(", ")
+        ));
+    }
 
-    trades.sort_by_key(|trade| trade.date_time);
-    let trades_with_prices = trades
+    // the pool and matching of each asset only depend on the trades of that asset
+    let mut assets = trades
         .iter()
-        .map(|trade| {
-            let price = get_price(trade, &prices).expect(&format!(
-                "Should have price for buy: {} sell: {} at {}",
-                trade.buy, trade.sell, trade.date_time
-            ));
-            (trade, price)
+        .flat_map(|trade| {
+            let account = config.account(trade);
+            vec![
+                (account.clone(), trade.buy.currency()),
+                (account, trade.sell.currency()),
+            ]
         })
+        .chain(opening_pools.iter().map(|pool| (None, pool.currency)))
+        .filter(|(_, asset)| !config.is_fiat(asset))
         .collect::<Vec<_>>();
-
-    let mut special_buys: HashMap<TradeKey, Money> = HashMap::new();
+    assets.sort_by(|(account1, asset1), (account2, asset2)| {
+        (account1, asset1.code).cmp(&(account2, asset2.code))
+    });
+    assets.dedup_by(|(account1, asset1), (account2, asset2)| {
+        account1 == account2 && asset1.code == asset2.code
+    });
+
+    let mut buy_pools = HashMap::new();
+    let mut disposals = HashMap::new();
+    let matches = map_assets(assets, parallel, |(account, asset)| {
+        let opening_pool = match account {
+            None => pools.get(asset.code).cloned(),
+            Some(_) => None,
+        };
+        match_asset(account, asset, &trades_with_prices, opening_pool, config)
+    });
+    for asset_matches in matches {
+        let asset_matches = asset_matches?;
+        buy_pools.extend(asset_matches.buys);
+        disposals.extend(asset_matches.disposals);
+        pools.insert(asset_matches.pool.key(), asset_matches.pool);
+    }
 
     let gains = trades_with_prices
         .iter()
         .cloned()
-        .map(|(trade, price)| {
+        .enumerate()
+        .filter(|(_, (trade, _))| !config.is_before_snapshot(trade))
+        .map(|(index, (trade, price))| {
             let trade_record: TradeRecord = trade.into();
             log::debug!("Trade: {:?}", trade_record);
-            let mut buy_pool: Option<Pool> = None;
-            let mut sell_pool: Option<Pool> = None;
-            let mut allowable_costs = Money::from_major(0, GBP);
-
-            if trade.buy.currency() != GBP {
-                let _zero = Money::from_major(0, trade.buy.currency());
-                let buy_amount = special_buys.get(&trade.key()).unwrap_or(&trade.buy);
-                let costs = convert_to_gbp(buy_amount.clone(), &price, trade.rate)?;
-                let pool = pools
-                    .entry(trade.buy.currency().code.to_string())
-                    .or_insert(Pool::new(trade.buy.currency()));
-                pool.buy(buy_amount, &costs);
-                buy_pool = Some(pool.clone());
-            }
-
-            if trade.sell.currency() != GBP {
-                // find any buys of this asset within the next 30 days
-                let special_rules_buy = trades_with_prices
-                    .iter()
-                    .filter(|(t, _)| {
-                        t.buy.currency() == trade.sell.currency()
-                            && t.date_time.date() >= trade.date_time.date()
-                            && t.date_time < trade.date_time + Duration::days(30)
-                    })
-                    .cloned()
-                    .collect::<Vec<_>>();
-
-                let mut main_pool_sell = trade.sell.clone();
-                let mut special_allowable_costs = Money::from_major(0, GBP);
-
-                for (future_buy, buy_price) in special_rules_buy {
-                    let remaining_buy_amount = special_buys
-                        .entry(future_buy.key())
-                        .or_insert(future_buy.buy.clone());
-
-                    if *remaining_buy_amount > Money::from_major(0, remaining_buy_amount.currency())
-                    {
-                        let (sell, special_buy_amt) = if *remaining_buy_amount <= main_pool_sell {
-                            (
-                                main_pool_sell - remaining_buy_amount.clone(),
-                                remaining_buy_amount.clone(),
-                            )
-                        } else {
-                            (Money::from_major(0, trade.sell.currency()), main_pool_sell)
-                        };
-                        *remaining_buy_amount =
-                            remaining_buy_amount.clone() - special_buy_amt.clone();
-                        let costs =
-                            convert_to_gbp(special_buy_amt.clone(), &buy_price, future_buy.rate)?;
-                        log::debug!(
-                            "Deducting SELL of {} from future BUY at {}, cost: {}",
-                            display_amount(&special_buy_amt),
-                            future_buy.date_time,
-                            display_amount(&costs)
-                        );
-                        main_pool_sell = sell;
-                        special_allowable_costs = special_allowable_costs + costs;
-                    }
-                }
 
-                let pool = pools
-                    .entry(trade.sell.currency().code.to_string())
-                    .or_insert(Pool::new(trade.sell.currency()));
-                let main_pool_costs = pool.sell(main_pool_sell);
-                allowable_costs = main_pool_costs + special_allowable_costs;
-                sell_pool = Some(pool.clone());
+            let buy_pool = buy_pools.remove(&index);
+            let (allowable_costs, matching, mut warnings, sell_pool) =
+                match disposals.remove(&index) {
+                    Some(disposal) => (
+                        disposal.allowable_costs,
+                        disposal.matching,
+                        disposal.warnings,
+                        Some(disposal.pool),
+                    ),
+                    None => (Money::from_major(0, currency), Vec::new(), Vec::new(), None),
+                };
+            let price_warnings = look_ahead_warning(trade, &price, config)
+                .into_iter()
+                .chain(price_gap_warning(&price));
+            for warning in price_warnings {
+                log::warn!("{} at {}: {}", trade.id(), trade.date_time, warning);
+                warnings.push(warning);
             }
 
-            let sell_value = if trade.sell.currency() == GBP {
+            let sell_value = if trade.sell.currency() == currency {
                 trade.sell.clone()
             } else {
-                convert_to_gbp(trade.sell.clone(), &price, trade.rate)?
+                convert_to_reporting(trade.sell.clone(), &price, trade.rate, currency)?
             };
 
-            let buy_value = if trade.buy.currency() == GBP {
+            let buy_value = if trade.buy.currency() == currency {
                 trade.buy.clone()
             } else {
-                convert_to_gbp(trade.buy.clone(), &price, trade.rate)?
+                convert_to_reporting(trade.buy.clone(), &price, trade.rate, currency)?
             };
 
-            let fee_value = if trade.fee.currency() == GBP {
-                trade.fee.clone()
-            } else {
-                convert_to_gbp(trade.fee.clone(), &price, trade.rate)?
-            };
+            let (fee_value, _) = allocate_fee(trade, &price, config)?;
 
-            let tax_year = uk_tax_year(trade.date_time);
+            let tax_year = uk_tax_year(config.local_time(trade));
 
             Ok(TaxEvent {
                 trade: trade.clone(),
+                date: config.date(trade),
                 buy_value,
                 sell_value,
                 fee_value,
                 price: price.clone(),
                 allowable_costs,
+                matching,
+                warnings,
                 tax_year,
                 sell_pool,
                 buy_pool,
             })
         })
         .collect::<color_eyre::Result<Vec<_>>>()?;
-    let report = TaxReport::new(trades, gains, pools);
+    let report = TaxReport::new(trades, gains, pools, opening_pools, currency);
     Ok(report)
 }
 
-fn convert_to_gbp<'a>(
-    money: Money<'a>,
-    price: &Price<'a>,
-    trade_rate: Decimal,
-) -> color_eyre::Result<Money<'a>> {
-    if money.currency() == GBP {
-        return Ok(money);
-    }
-    if money.currency() == price.pair.base {
-        let quote_rate =
-            rusty_money::ExchangeRate::new(money.currency(), GBP, price.rate).expect(&format!(
-                "Creating quote rate exchange pair from {} price",
-                price.pair
-            ));
-        let gbp = quote_rate.convert(money)?;
-        Ok(gbp)
-    } else {
-        let base_rate =
-            rusty_money::ExchangeRate::new(money.currency(), price.pair.base, trade_rate).expect(
-                &format!("Creating base rate exchange pair from {} price", price.pair),
-            );
-        let gbp_rate =
-            rusty_money::ExchangeRate::new(price.pair.base, GBP, price.rate).expect(&format!(
-                "Creating quote rate exchange pair from {} price",
-                price.pair
-            ));
-        let base = base_rate.convert(money)?;
-        let gbp = gbp_rate.convert(base)?;
-        Ok(gbp)
-    }
+/// The acquisitions and disposals of a single asset, keyed by the index of their trade
+struct AssetMatches<'a> {
+    pool: Pool<'a>,
+    buys: Vec<(usize, Pool<'a>)>,
+    disposals: Vec<(usize, Disposal<'a>)>,
 }
 
-fn get_price<'a>(trade: &Trade<'a>, prices: &'a Prices<'a>) -> Option<Price<'a>> {
-    // todo - extract and dedup this logic
-    let (quote, base) = match trade.kind {
-        TradeKind::Buy => (trade.sell.currency(), trade.buy.currency()),
-        TradeKind::Sell => (trade.buy.currency(), trade.sell.currency()),
-    };
+/// How the sold amount of a trade was matched with acquisitions
+struct Disposal<'a> {
+    allowable_costs: Money<'a>,
+    matching: Vec<MatchingComponent<'a>>,
+    warnings: Vec<String>,
+    pool: Pool<'a>,
+}
 
-    if quote == GBP {
-        return Some(Price {
-            pair: CurrencyPair { base, quote: GBP },
-            date_time: trade.date_time,
-            rate: trade.rate,
-        });
+#[cfg(feature = "parallel")]
+fn map_assets<'a, F>(
+    assets: Vec<(Option<String>, &'a Currency)>,
+    parallel: bool,
+    f: F,
+) -> Vec<color_eyre::Result<AssetMatches<'a>>>
+where
+    F: Fn((Option<String>, &'a Currency)) -> color_eyre::Result<AssetMatches<'a>> + Sync + Send,
+{
+    use rayon::prelude::*;
+    if parallel {
+        // collecting an indexed parallel iterator preserves the order of the assets
+        assets.into_par_iter().map(f).collect()
+    } else {
+        assets.into_iter().map(f).collect()
     }
+}
 
-    let pair = CurrencyPair {
-        base: &quote,
-        quote: GBP,
-    };
-    prices.get(pair, trade.date_time.date())
+#[cfg(not(feature = "parallel"))]
+fn map_assets<'a, F>(
+    assets: Vec<(Option<String>, &'a Currency)>,
+    _parallel: bool,
+    f: F,
+) -> Vec<color_eyre::Result<AssetMatches<'a>>>
+where
+    F: Fn((Option<String>, &'a Currency)) -> color_eyre::Result<AssetMatches<'a>>,
+{
+    assets.into_iter().map(f).collect()
 }
 
-fn uk_tax_year(date_time: NaiveDateTime) -> Year {
-    let date = date_time.date();
-    let year = date.year();
-    if date > ymd(year, 4, 5) && date <= ymd(year, 12, 31) {
+/// Adds the acquisitions of the asset to its pool, and matches its disposals with acquisitions
+/// on the same day, within the next 30 days, or from the pool.
+///
+/// When pooling by account, only the trades of the given account are included.
+fn match_asset<'a>(
+    account: Option<String>,
+    asset: &'a Currency,
+    trades_with_prices: &[(&Trade<'a>, Price<'a>)],
+    opening_pool: Option<Pool<'a>>,
+    config: &Config,
+) -> color_eyre::Result<AssetMatches<'a>> {
+    let currency = config.reporting_currency;
+    let trades_with_prices = trades_with_prices
+        .iter()
+        .enumerate()
+        .filter(|(_, (trade, _))| {
+            (trade.buy.currency() == asset || trade.sell.currency() == asset)
+                && config.account(trade) == account
+        })
+        .collect::<Vec<_>>();
+
+    let mut pool = opening_pool.unwrap_or_else(|| Pool {
+        account,
+        ..Pool::new(asset, currency)
+    });
+    let mut special_buys: HashMap<TradeKey, Money> = HashMap::new();
+    let mut buys = Vec::new();
+    let mut disposals = Vec::new();
+    // whether any of the asset has been acquired yet, to detect gaps in the history
+    let mut acquired = pool.total.is_positive();
+
+    // the same day rule takes priority over the 30 day rule, so the acquisitions of each day are
+    // reserved for the disposals of that day before any are added to the pool
+    let mut same_day_matches = HashMap::new();
+    if config.matching.same_day {
+        let sells = trades_with_prices
+            .iter()
+            .filter(|(_, (trade, _))| trade.sell.currency() == asset);
+        for (index, (sell, _)) in sells {
+            let mut remaining = sell.sell.clone();
+            let mut matches = Vec::new();
+            let same_day_buys = trades_with_prices.iter().filter(|(_, (trade, _))| {
+                trade.buy.currency() == asset && config.date(trade) == config.date(sell)
+            });
+            for (_, (buy, buy_price)) in same_day_buys {
+                let available = special_buys.entry(buy.key()).or_insert(buy.buy.clone());
+                let quantity = if *available < remaining {
+                    available.clone()
+                } else {
+                    remaining.clone()
+                };
+                if !quantity.is_positive() {
+                    continue;
+                }
+                *available = available.clone() - quantity.clone();
+                remaining = remaining - quantity.clone();
+                matches.push((*buy, buy_price.clone(), quantity));
+            }
+            same_day_matches.insert(*index, matches);
+        }
+    }
+
+    for (index, (trade, price)) in trades_with_prices.iter().cloned() {
+        let before_snapshot = config.is_before_snapshot(trade);
+        acquired |= trade.buy.currency() == asset;
+
+        if trade.buy.currency() == asset && !before_snapshot {
+            let buy_amount = special_buys.get(&trade.key()).unwrap_or(&trade.buy);
+            let costs = acquisition_cost(trade, buy_amount, &price, config)?;
+            pool.buy(buy_amount, &costs);
+            buys.push((index, pool.clone()));
+        }
+
+        if trade.sell.currency() == asset {
+            let mut matching = Vec::new();
+            let mut warnings = Vec::new();
+
+            let mut main_pool_sell = trade.sell.clone();
+            let mut special_allowable_costs = Money::from_major(0, currency);
+
+            for (same_day_buy, buy_price, quantity) in
+                same_day_matches.remove(&index).unwrap_or_default()
+            {
+                let costs = acquisition_cost(same_day_buy, &quantity, &buy_price, config)?;
+                log::debug!(
+                    "Deducting SELL of {} from same day BUY at {}, cost: {}",
+                    display_amount(&quantity),
+                    same_day_buy.date_time,
+                    display_amount(&costs)
+                );
+                matching.push(MatchingComponent {
+                    rule: MatchingRule::SameDay,
+                    quantity: quantity.clone(),
+                    cost: costs.clone(),
+                    matched_date: Some(config.date(same_day_buy)),
+                    matched_id: Some(same_day_buy.id()),
+                });
+                main_pool_sell = main_pool_sell - quantity;
+                special_allowable_costs = special_allowable_costs + costs;
+            }
+
+            // find any buys of this asset within the next 30 days
+            let special_rules_buy = trades_with_prices
+                .iter()
+                .map(|(_, trade_with_price)| trade_with_price)
+                .filter(|(t, _)| {
+                    t.buy.currency() == asset
+                        && config
+                            .matching
+                            .in_bnb_window(config.date(trade), config.date(t))
+                })
+                .cloned()
+                .collect::<Vec<_>>();
+
+            for (future_buy, buy_price) in special_rules_buy {
+                let remaining_buy_amount = special_buys
+                    .entry(future_buy.key())
+                    .or_insert(future_buy.buy.clone());
+
+                if *remaining_buy_amount > Money::from_major(0, remaining_buy_amount.currency()) {
+                    let (sell, special_buy_amt) = if *remaining_buy_amount <= main_pool_sell {
+                        (
+                            main_pool_sell - remaining_buy_amount.clone(),
+                            remaining_buy_amount.clone(),
+                        )
+                    } else {
+                        (Money::from_major(0, trade.sell.currency()), main_pool_sell)
+                    };
+                    *remaining_buy_amount = remaining_buy_amount.clone() - special_buy_amt.clone();
+                    let costs = acquisition_cost(future_buy, &special_buy_amt, &buy_price, config)?;
+                    log::debug!(
+                        "Deducting SELL of {} from future BUY at {}, cost: {}",
+                        display_amount(&special_buy_amt),
+                        future_buy.date_time,
+                        display_amount(&costs)
+                    );
+                    matching.push(MatchingComponent {
+                        rule: MatchingRule::BedAndBreakfast,
+                        quantity: special_buy_amt,
+                        cost: costs.clone(),
+                        matched_date: Some(config.date(future_buy)),
+                        matched_id: Some(future_buy.id()),
+                    });
+                    main_pool_sell = sell;
+                    special_allowable_costs = special_allowable_costs + costs;
+                }
+            }
+
+            if before_snapshot {
+                // the disposal is already accounted for in the snapshot pool
+                continue;
+            }
+
+            if !acquired && matching.is_empty() {
+                let warning = format!(
+                    "Disposed of {} before any acquisition of {}",
+                    quantity(&main_pool_sell),
+                    asset.code
+                );
+                log::warn!("{} at {}", warning, trade.date_time);
+                warnings.push(warning);
+            } else if main_pool_sell > pool.total {
+                let warning = format!(
+                    "Disposed of {} but only {} in the pool",
+                    quantity(&main_pool_sell),
+                    quantity(&pool.total)
+                );
+                log::warn!("{} at {}", warning, trade.date_time);
+                warnings.push(warning);
+            }
+            let pool_sell = main_pool_sell.clone();
+            let main_pool_costs = pool.sell(main_pool_sell, config.dust_threshold);
+            if *pool_sell.amount() > Decimal::new(0, 0) {
+                matching.push(MatchingComponent {
+                    rule: MatchingRule::Pool,
+                    quantity: pool_sell,
+                    cost: main_pool_costs.clone(),
+                    matched_date: None,
+                    matched_id: None,
+                });
+            }
+            disposals.push((
+                index,
+                Disposal {
+                    allowable_costs: main_pool_costs + special_allowable_costs,
+                    matching,
+                    warnings,
+                    pool: pool.clone(),
+                },
+            ));
+        }
+    }
+    Ok(AssetMatches {
+        pool,
+        buys,
+        disposals,
+    })
+}
+
+/// The cost in the reporting currency of a quantity of the asset bought by the trade, including
+/// the same proportion of the fee allowed against the acquisition.
+///
+/// When the trade is paid for in the reporting currency, the cost is the same proportion of the
+/// amount paid, so the costs of the parts of an acquisition add up to exactly what was paid even
+/// if the rate of the trade is rounded. Otherwise the quantity is valued at the price.
+fn acquisition_cost<'a>(
+    trade: &Trade<'a>,
+    quantity: &Money<'a>,
+    price: &Price<'a>,
+    config: &Config,
+) -> color_eyre::Result<Money<'a>> {
+    let currency = config.reporting_currency;
+    let (_, fee) = allocate_fee(trade, price, config)?;
+    if *quantity == trade.buy {
+        let cost = if trade.sell.currency() == currency {
+            trade.sell.clone()
+        } else {
+            convert_to_reporting(quantity.clone(), price, trade.rate, currency)?
+        };
+        return Ok(cost + fee);
+    }
+    let proportion = *quantity.amount() / *trade.buy.amount();
+    let cost = if trade.sell.currency() == currency {
+        trade.sell.clone() * proportion
+    } else {
+        convert_to_reporting(quantity.clone(), price, trade.rate, currency)?
+    };
+    Ok(cost + fee * proportion)
+}
+
+/// Converts the amount to the reporting currency, via the base currency of the price if the
+/// amount is in another currency.
+fn convert_to_reporting<'a>(
+    money: Money<'a>,
+    price: &Price<'a>,
+    trade_rate: Decimal,
+    currency: &'a Currency,
+) -> color_eyre::Result<Money<'a>> {
+    if money.currency() == currency {
+        return Ok(money);
+    }
+    if money.currency() == price.pair.base {
+        let quote_rate = rusty_money::ExchangeRate::new(money.currency(), currency, price.rate)
+            .expect(&format!(
+                "Creating quote rate exchange pair from {} price",
+                price.pair
+            ));
+        let value = quote_rate.convert(money)?;
+        Ok(value)
+    } else {
+        let base_rate =
+            rusty_money::ExchangeRate::new(money.currency(), price.pair.base, trade_rate).expect(
+                &format!("Creating base rate exchange pair from {} price", price.pair),
+            );
+        let quote_rate = rusty_money::ExchangeRate::new(price.pair.base, currency, price.rate)
+            .expect(&format!(
+                "Creating quote rate exchange pair from {} price",
+                price.pair
+            ));
+        let base = base_rate.convert(money)?;
+        let value = quote_rate.convert(base)?;
+        Ok(value)
+    }
+}
+
+/// Warns if the trades after the snapshot date start with acquisitions which could be matched
+/// with disposals before it under the 30 day rule, but there are no trades from those days.
+fn snapshot_window_warning(trades: &[Trade], config: &Config) -> Option<String> {
+    let snapshot_date = config.snapshot_date?;
+    let bnb_days = Duration::days(config.matching.bnb_days.into());
+    let window_start = snapshot_date - bnb_days;
+    let has_window_trades = trades.iter().any(|t| {
+        let date = config.date(t);
+        date > window_start && date <= snapshot_date
+    });
+    let has_matchable_acquisitions = trades.iter().any(|t| {
+        let date = config.date(t);
+        date > snapshot_date
+            && date <= snapshot_date + bnb_days
+            && !config.is_fiat(t.buy.currency())
+    });
+    if has_window_trades || !has_matchable_acquisitions {
+        return None;
+    }
+    Some(format!(
+        "There are no trades from the {} days before the snapshot date {}, which are needed to \
+         match their disposals with the acquisitions after it",
+        bnb_days.num_days(),
+        snapshot_date
+    ))
+}
+
+/// Splits the fee of the trade in the reporting currency between the disposal and the
+/// acquisition. The fee of buying an asset with fiat is an acquisition cost, of selling one for
+/// fiat a disposal cost, and of a trade between two assets is split according to the configured
+/// [`FeeAllocation`].
+///
+/// The disposal's share is rounded to the reporting currency, and the acquisition gets the rest
+/// so that the whole fee is allowed exactly once.
+fn allocate_fee<'a>(
+    trade: &Trade<'a>,
+    price: &Price<'a>,
+    config: &Config,
+) -> color_eyre::Result<(Money<'a>, Money<'a>)> {
+    let currency = config.reporting_currency;
+    let to_reporting = |money: &Money<'a>| {
+        if money.currency() == currency {
+            Ok(money.clone())
+        } else {
+            convert_to_reporting(money.clone(), price, trade.rate, currency)
+        }
+    };
+    let fee = to_reporting(&trade.fee)?;
+    let zero = Money::from_major(0, currency);
+    let buys_asset = !config.is_fiat(trade.buy.currency());
+    let sells_asset = !config.is_fiat(trade.sell.currency());
+    if buys_asset && !sells_asset {
+        return Ok((zero, fee));
+    }
+    if config.fee_allocation == FeeAllocation::Disposal || !buys_asset {
+        return Ok((fee, zero));
+    }
+
+    let sell_value = to_reporting(&trade.sell)?;
+    let total_value = sell_value.clone() + to_reporting(&trade.buy)?;
+    if !total_value.is_positive() {
+        return Ok((fee, zero));
+    }
+    let disposal_fee =
+        (*fee.amount() * *sell_value.amount() / *total_value.amount()).round_dp(currency.exponent);
+    let disposal_fee = Money::from_decimal(disposal_fee, currency);
+    let acquisition_fee = fee - disposal_fee.clone();
+    Ok((disposal_fee, acquisition_fee))
+}
+
+/// Warns if the trade is valued at a price from a later day than the local day of the trade,
+/// beyond the tolerance, which would be using information that wasn't available at the time, e.g.
+/// because of a time zone error in a lookup.
+fn look_ahead_warning(trade: &Trade, price: &Price, config: &Config) -> Option<String> {
+    if price.date_time.date() > config.date(trade) + config.look_ahead_tolerance {
+        Some(format!(
+            "Valued with the {} price from {}, after the trade",
+            price.pair, price.date_time
+        ))
+    } else {
+        None
+    }
+}
+
+/// Warns if the trade is valued at a price carried forward or interpolated for a day without one
+fn price_gap_warning(price: &Price) -> Option<String> {
+    match price.gap? {
+        PriceGapPolicy::Carry => Some(format!(
+            "Valued with the {} price from {}, carried forward",
+            price.pair,
+            price.date_time.date()
+        )),
+        PriceGapPolicy::Interpolate => Some(format!(
+            "Valued with a {} price interpolated for {}",
+            price.pair,
+            price.date_time.date()
+        )),
+        PriceGapPolicy::Error => None,
+    }
+}
+
+fn get_price<'a, P: PriceLookup<'a>>(
+    trade: &Trade<'a>,
+    prices: &P,
+    config: &Config,
+) -> Option<Price<'a>> {
+    let currency = config.reporting_currency;
+    // todo - extract and dedup this logic
+    let (quote, base) = match trade.kind {
+        TradeKind::Buy => (trade.sell.currency(), trade.buy.currency()),
+        TradeKind::Sell => (trade.buy.currency(), trade.sell.currency()),
+    };
+
+    if quote == currency {
+        return Some(Price {
+            pair: CurrencyPair {
+                base,
+                quote: currency,
+            },
+            // in local time, like the days of the prices looked up for other trades
+            date_time: config.local_time(trade),
+            rate: trade.rate,
+            gap: None,
+        });
+    }
+
+    if let Some(peg) = config.stablecoin_peg {
+        if STABLECOINS.contains(&quote.code) {
+            return Some(Price {
+                pair: CurrencyPair {
+                    base: quote,
+                    quote: currency,
+                },
+                date_time: config.local_time(trade),
+                rate: peg,
+                gap: None,
+            });
+        }
+    }
+
+    let pair = CurrencyPair {
+        base: &quote,
+        quote: currency,
+    };
+    prices.price(pair, config.date(trade))
+}
+
+fn uk_tax_year(date_time: NaiveDateTime) -> Year {
+    let date = date_time.date();
+    let year = date.year();
+    if date > ymd(year, 4, 5) && date <= ymd(year, 12, 31) {
         year + 1
     } else {
         year
@@ -527,6 +1765,7 @@ mod tests {
             rate,
             fee: gbp!(0),
             exchange: None,
+            source_id: None,
         }
     }
 
@@ -544,7 +1783,7 @@ mod tests {
 
         let trades = vec![acq1, acq2, disp];
         let prices = Prices::default();
-        let report = calculate(trades, &prices).unwrap();
+        let report = calculate(trades, &prices, &Config::default()).unwrap();
 
         let gains_2018 = report.gains(Some(2018));
 
@@ -567,7 +1806,7 @@ mod tests {
 
         let trades = vec![disp, acq2, acq1];
         let prices = Prices::default();
-        let report = calculate(trades, &prices).unwrap();
+        let report = calculate(trades, &prices, &Config::default()).unwrap();
 
         let gains_2018 = report.gains(Some(2018));
 
@@ -590,7 +1829,7 @@ mod tests {
 
         let trades = vec![buy1, sell, buy2];
         let prices = Prices::default();
-        let report = calculate(trades, &prices).unwrap();
+        let report = calculate(trades, &prices, &Config::default()).unwrap();
 
         let gains_2019 = report.gains(Some(2019));
         let gain = gains_2019.gains.get(0).unwrap();
@@ -609,6 +1848,33 @@ mod tests {
         );
     }
 
+    #[test]
+    fn bnb_match_has_id_of_acquisition() {
+        let buy1 = trade("2020-01-01", TradeKind::Buy, gbp!(10_000), btc!(2), 5000);
+        let sell = trade("2020-03-01", TradeKind::Sell, btc!(1), gbp!(6000), 6000);
+        let buy2 = trade("2020-03-10", TradeKind::Buy, gbp!(5500), btc!(1), 5500);
+        let buy2_id = buy2.id();
+
+        let trades = vec![buy1, sell, buy2];
+        let prices = Prices::default();
+        let report = calculate(trades, &prices, &Config::default()).unwrap();
+
+        let disposal = report
+            .gains(None)
+            .into_iter()
+            .find(TaxEvent::is_disposal)
+            .unwrap();
+        let matched = disposal
+            .matching()
+            .iter()
+            .map(|c| (c.rule, c.matched_id.clone()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            matched,
+            vec![(MatchingRule::BedAndBreakfast, Some(buy2_id))]
+        );
+    }
+
     #[test]
     fn multiple_acquisitions_within_30_days() {
         let buy1 = trade(
@@ -624,7 +1890,7 @@ mod tests {
 
         let trades = vec![buy1, sell, buy2, buy3];
         let prices = Prices::default();
-        let report = calculate(trades, &prices).unwrap();
+        let report = calculate(trades, &prices, &Config::default()).unwrap();
 
         let gains_2019 = report.gains(Some(2019));
         let gain = gains_2019.gains.get(0).unwrap();
@@ -652,7 +1918,7 @@ mod tests {
 
         let trades = vec![buy1, sell1, sell2, buy2];
         let prices = Prices::default();
-        let report = calculate(trades, &prices).unwrap();
+        let report = calculate(trades, &prices, &Config::default()).unwrap();
 
         let gains_2019 = report.gains(Some(2019));
         let gain1 = gains_2019.gains.get(0).unwrap();
@@ -681,7 +1947,7 @@ mod tests {
 
         let trades = vec![buy1, sell, buy2];
         let prices = Prices::default();
-        let report = calculate(trades, &prices).unwrap();
+        let report = calculate(trades, &prices, &Config::default()).unwrap();
 
         let gains_2019 = report.gains(Some(2019));
         println!(
@@ -716,7 +1982,7 @@ mod tests {
 
         let trades = vec![acq1, disp];
         let prices = Prices::default();
-        let report = calculate(trades, &prices).unwrap();
+        let report = calculate(trades, &prices, &Config::default()).unwrap();
 
         let gains_2018 = report.gains(Some(2018));
 
@@ -725,6 +1991,1424 @@ mod tests {
         assert_money_eq!(gains_2018.total_gain(), gbp!(1000));
     }
 
+    #[test]
+    fn gains_and_losses_are_totalled_separately() {
+        let buy = trade("2018-01-01", TradeKind::Buy, gbp!(10_000), btc!(10), 1000);
+        let gain = trade("2018-05-01", TradeKind::Sell, btc!(2), gbp!(3000), 1500);
+        let loss = trade("2018-07-01", TradeKind::Sell, btc!(3), gbp!(1500), 500);
+
+        let trades = vec![buy, gain, loss];
+        let prices = Prices::default();
+        let report = calculate(trades, &prices, &Config::default()).unwrap();
+
+        let gains_2019 = report.gains(Some(2019));
+
+        assert_money_eq!(gains_2019.total_gains_only(), gbp!(1000), "Gains");
+        assert_money_eq!(gains_2019.total_losses_only(), gbp!(1500), "Losses");
+        assert_money_eq!(gains_2019.total_gain(), gbp!(-500), "Net gain");
+    }
+
+    #[test]
+    fn gains_between_dates_include_both_ends_of_the_range() {
+        let trades = vec![
+            trade("2020-01-01", TradeKind::Buy, gbp!(10000), btc!(2), 5000),
+            trade("2020-03-31", TradeKind::Sell, btc!(0.25), gbp!(2000), 8000),
+            trade("2020-04-01", TradeKind::Sell, btc!(0.25), gbp!(2500), 10000),
+            trade("2020-06-30", TradeKind::Sell, btc!(0.25), gbp!(3000), 12000),
+            trade("2020-07-01", TradeKind::Sell, btc!(0.25), gbp!(3500), 14000),
+        ];
+        let prices = Prices::default();
+        let report = calculate(trades, &prices, &Config::default()).unwrap();
+        let date = |s| NaiveDate::parse_from_str(s, "%Y-%m-%d").unwrap();
+
+        let gains = report.gains_between(Some(date("2020-04-01")), Some(date("2020-06-30")));
+
+        assert_eq!(
+            gains.len(),
+            2,
+            "The range spans the end of the 2019/20 tax year"
+        );
+        assert_money_eq!(gains.total_proceeds(), gbp!(5500));
+        assert_money_eq!(gains.total_gain(), gbp!(3000));
+
+        let gains = report.gains_between(Some(date("2020-06-30")), None);
+        assert_eq!(gains.len(), 2);
+        assert_money_eq!(gains.total_proceeds(), gbp!(6500));
+    }
+
+    #[test]
+    fn warnings_are_errors_when_strict() {
+        let buy = trade("2020-01-01", TradeKind::Buy, gbp!(5000), btc!(1), 5000);
+        let sell1 = trade("2020-03-01", TradeKind::Sell, btc!(1), gbp!(6000), 6000);
+        let sell2 = trade("2021-03-01", TradeKind::Sell, btc!(1), gbp!(7000), 7000);
+        let sell2_id = sell2.id();
+
+        let trades = vec![buy, sell1, sell2];
+        let prices = Prices::default();
+        let report = calculate(trades, &prices, &Config::default()).unwrap();
+
+        assert!(report.gains(Some(2020)).ensure_no_warnings().is_ok());
+        let err = report
+            .gains(Some(2021))
+            .ensure_no_warnings()
+            .unwrap_err()
+            .to_string();
+        assert_eq!(
+            err,
+            format!(
+                "Found 1 warnings:\n2021-03-01 23:59:59 BTC ({}): Disposed of 1 but only 0 in the pool",
+                sell2_id
+            )
+        );
+    }
+
+    #[test]
+    fn disposal_before_any_acquisition_is_a_report_warning() {
+        let sell = trade("2020-03-01", TradeKind::Sell, btc!(1), gbp!(6000), 6000);
+        let sell_id = sell.id();
+        let buy = trade("2020-06-01", TradeKind::Buy, gbp!(5000), btc!(1), 5000);
+
+        let trades = vec![sell, buy];
+        let prices = Prices::default();
+        let report = calculate(trades, &prices, &Config::default()).unwrap();
+
+        assert_eq!(
+            report.warnings(),
+            vec![Warning {
+                date_time: NaiveDate::from_ymd(2020, 3, 1).and_hms(23, 59, 59),
+                asset: "BTC".into(),
+                trade_id: sell_id,
+                message: "Disposed of 1 before any acquisition of BTC".into(),
+            }]
+        );
+        let disposal = report.gains(None).into_iter().next().unwrap();
+        assert_eq!(
+            disposal.warnings(),
+            &["Disposed of 1 before any acquisition of BTC".to_string()]
+        );
+    }
+
+    #[test]
+    fn losses_are_carried_forward_to_later_years() {
+        let buy = trade(
+            "2016-06-01",
+            TradeKind::Buy,
+            gbp!(100_000),
+            btc!(10),
+            10_000,
+        );
+        let loss = trade("2016-07-01", TradeKind::Sell, btc!(5), gbp!(40_000), 8000);
+        let gain1 = trade("2017-06-01", TradeKind::Sell, btc!(2), gbp!(35_000), 17_500);
+        let gain2 = trade("2018-06-01", TradeKind::Sell, btc!(2), gbp!(40_000), 20_000);
+
+        let trades = vec![buy, loss, gain1, gain2];
+        let prices = Prices::default();
+        let report = calculate(trades, &prices, &Config::default()).unwrap();
+
+        let (taxable_2017, losses_2017) = report.net_gain_after_losses(2017, gbp!(0));
+        assert_money_eq!(taxable_2017, gbp!(0), "No taxable gain in loss year");
+        assert_money_eq!(losses_2017, gbp!(10_000), "Loss carried forward");
+
+        let (taxable_2018, losses_2018) = report.net_gain_after_losses(2018, gbp!(0));
+        assert_money_eq!(taxable_2018, gbp!(0), "Gain above AEA covered by losses");
+        assert_money_eq!(losses_2018, gbp!(6_300), "Losses only used down to AEA");
+
+        let (taxable_2019, losses_2019) = report.net_gain_after_losses(2019, gbp!(0));
+        assert_money_eq!(taxable_2019, gbp!(2_000), "Remaining losses used up");
+        assert_money_eq!(losses_2019, gbp!(0), "No losses remaining");
+    }
+
+    #[test]
+    fn pool_only_matches_quick_rebuy_from_pool() {
+        let buy1 = trade("2018-01-01", TradeKind::Buy, gbp!(100_000), btc!(100), 1000);
+        let sell = trade("2018-08-30", TradeKind::Sell, btc!(20), gbp!(40_000), 2000);
+        let buy2 = trade("2018-09-11", TradeKind::Buy, gbp!(15_000), btc!(10), 1500);
+
+        let trades = vec![buy1, sell, buy2];
+        let prices = Prices::default();
+        let config = Config {
+            matching: MatchingConfig::pool_only(),
+            ..Default::default()
+        };
+        let report = calculate(trades, &prices, &config).unwrap();
+
+        let gains_2019 = report.gains(Some(2019));
+        let gain = gains_2019.gains.get(0).unwrap();
+
+        assert_money_eq!(gain.proceeds(), gbp!(40_000), "Consideration");
+        assert_money_eq!(gain.allowable_costs, gbp!(20_000.00), "Allowable costs");
+        assert_money_eq!(gain.gain(), gbp!(20_000.00), "Gain");
+
+        let btc_pool = report.pools.get("BTC").expect("BTC should have a Pool");
+
+        assert_money_eq!(btc_pool.total, btc!(90), "Remaining in pool");
+        assert_money_eq!(btc_pool.costs, gbp!(95_000.00), "Remaining allowable costs");
+    }
+
+    #[test]
+    fn acquisition_fees_are_added_to_cost_and_disposal_fees_reported_separately() {
+        let acq = Trade {
+            fee: gbp!(10),
+            ..trade("2016-01-01", TradeKind::Buy, gbp!(1000), btc!(10), 100)
+        };
+        let disp = Trade {
+            fee: gbp!(20),
+            ..trade("2016-02-01", TradeKind::Sell, btc!(5), gbp!(1000), 200)
+        };
+
+        let trades = vec![acq, disp];
+        let prices = Prices::default();
+        let report = calculate(trades, &prices, &Config::default()).unwrap();
+
+        let gains_2016 = report.gains(Some(2016));
+
+        assert_eq!(gains_2016.len(), 1, "Only the disposal should be counted");
+        assert_money_eq!(gains_2016.total_proceeds(), gbp!(1000));
+        assert_money_eq!(gains_2016.total_acquisition_costs(), gbp!(505));
+        assert_money_eq!(gains_2016.total_fees(), gbp!(20));
+        assert_money_eq!(gains_2016.total_allowable_costs(), gbp!(525));
+        assert_money_eq!(gains_2016.total_gain(), gbp!(475));
+    }
+
+    #[test]
+    fn canonical_output_matches_snapshot() {
+        let acq1 = trade("2016-01-01", TradeKind::Buy, gbp!(1000.00), btc!(100.), 10);
+        let acq2 = trade("2017-01-01", TradeKind::Buy, gbp!(125_000), btc!(50.), 2500);
+        let disp = trade(
+            "2018-01-01",
+            TradeKind::Sell,
+            btc!(50.00),
+            gbp!(300_000),
+            6000,
+        );
+
+        let trades = vec![disp, acq2, acq1];
+        let prices = Prices::default();
+        let report = calculate(trades, &prices, &Config::default()).unwrap();
+
+        let mut output = Vec::new();
+        report.write_canonical(None, &mut output).unwrap();
+
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            include_str!("testdata/hmrc_pooling_example.txt")
+        );
+    }
+
+    #[test]
+    fn gains_are_calculated_in_reporting_currency() {
+        use crate::currencies::{ETH, EUR};
+        let eur = |amount| Money::from_decimal(amount, EUR);
+        let with_eur_fee = |trade: Trade<'static>| Trade {
+            fee: eur(dec!(0)),
+            ..trade
+        };
+
+        let acq = with_eur_fee(trade(
+            "2020-01-01",
+            TradeKind::Buy,
+            eur(dec!(5000)),
+            btc!(1),
+            5000,
+        ));
+        let swap = with_eur_fee(trade(
+            "2020-06-01",
+            TradeKind::Sell,
+            btc!(0.5),
+            Money::from_decimal(dec!(20), ETH),
+            40,
+        ));
+        let disp = with_eur_fee(trade(
+            "2020-07-01",
+            TradeKind::Sell,
+            btc!(0.5),
+            eur(dec!(3000)),
+            6000,
+        ));
+
+        let prices = Prices::read_csv(
+            "base_currency,quote_currency,date_time,rate\nETH,EUR,2020-06-01T00:00:00Z,200\n"
+                .as_bytes(),
+        )
+        .unwrap();
+        let config = Config {
+            reporting_currency: EUR,
+            ..Config::default()
+        };
+        let report = calculate(vec![acq, swap, disp], &prices, &config).unwrap();
+
+        let gains = report.gains(None);
+
+        assert_eq!(gains.len(), 2);
+        assert_money_eq!(gains.total_proceeds(), eur(dec!(7000)));
+        assert_money_eq!(gains.total_allowable_costs(), eur(dec!(5000)));
+        assert_money_eq!(gains.total_gain(), eur(dec!(2000)));
+        assert_money_eq!(report.pools["ETH"].costs, eur(dec!(4000)));
+    }
+
+    #[test]
+    fn split_fee_is_conserved_to_the_penny() {
+        use crate::currencies::ETH;
+        let eth = |amount| Money::from_decimal(amount, ETH);
+
+        let acq = trade("2020-01-01", TradeKind::Buy, gbp!(5000), btc!(1), 5000);
+        let swap = Trade {
+            fee: gbp!(10.01),
+            ..trade("2020-06-01", TradeKind::Sell, btc!(0.5), eth(dec!(20)), 40)
+        };
+        let prices = Prices::read_csv(
+            "base_currency,quote_currency,date_time,rate\nETH,GBP,2020-06-01T00:00:00Z,200\n"
+                .as_bytes(),
+        )
+        .unwrap();
+
+        let report =
+            calculate(vec![acq.clone(), swap.clone()], &prices, &Config::default()).unwrap();
+        assert_money_eq!(report.gains(None).total_fees(), gbp!(10.01));
+        assert_money_eq!(report.pools["ETH"].costs, gbp!(4000));
+
+        let config = Config {
+            fee_allocation: FeeAllocation::Split,
+            ..Config::default()
+        };
+        let report = calculate(vec![acq, swap], &prices, &config).unwrap();
+        let gains = report.gains(None);
+        assert_money_eq!(gains.total_fees(), gbp!(5.00));
+        assert_money_eq!(report.pools["ETH"].costs, gbp!(4005.01));
+        assert_money_eq!(gains.total_gain(), gbp!(1495.00));
+    }
+
+    #[test]
+    fn split_fee_is_apportioned_to_bed_and_breakfast_match() {
+        use crate::currencies::ETH;
+        let eth = |amount| Money::from_decimal(amount, ETH);
+
+        let trades = vec![
+            trade("2020-01-01", TradeKind::Buy, gbp!(5000), btc!(1), 5000),
+            trade("2020-01-01", TradeKind::Buy, gbp!(2000), eth(dec!(20)), 100),
+            trade(
+                "2020-05-20",
+                TradeKind::Sell,
+                eth(dec!(10)),
+                gbp!(2500),
+                250,
+            ),
+            Trade {
+                fee: gbp!(10.01),
+                ..trade("2020-06-01", TradeKind::Sell, btc!(0.5), eth(dec!(20)), 40)
+            },
+        ];
+        let prices = Prices::read_csv(
+            "base_currency,quote_currency,date_time,rate\nETH,GBP,2020-06-01T00:00:00Z,200\n"
+                .as_bytes(),
+        )
+        .unwrap();
+        let config = Config {
+            fee_allocation: FeeAllocation::Split,
+            ..Config::default()
+        };
+
+        let report = calculate(trades, &prices, &config).unwrap();
+
+        // half of the ETH and of its £5.01 share of the fee are matched with the disposal
+        let gains = report.gains(None);
+        assert_eq!(*gains.total_acquisition_costs().amount(), dec!(4502.505));
+        assert_eq!(*report.pools["ETH"].costs.amount(), dec!(4002.505));
+    }
+
+    #[test]
+    fn pool_keeps_full_precision_of_18_decimal_token() {
+        use crate::currencies::ETH;
+        let eth = |amount| Money::from_decimal(amount, ETH);
+
+        let mut trades = vec![trade(
+            "2020-01-01",
+            TradeKind::Buy,
+            gbp!(1000),
+            eth(dec!(1)),
+            1000,
+        )];
+        let small_disposal = dec!(0.123456789012345678);
+        let dates = [
+            "2020-02-01",
+            "2020-03-01",
+            "2020-04-01",
+            "2020-05-01",
+            "2020-06-01",
+            "2020-07-01",
+            "2020-08-01",
+            "2020-09-01",
+        ];
+        for date in dates.iter() {
+            trades.push(trade(
+                date,
+                TradeKind::Sell,
+                eth(small_disposal),
+                gbp!(100),
+                1000,
+            ));
+        }
+        let remainder = dec!(1) - small_disposal * Decimal::from(8);
+        trades.push(trade(
+            "2020-10-01",
+            TradeKind::Sell,
+            eth(remainder),
+            gbp!(10),
+            1000,
+        ));
+
+        let prices = Prices::default();
+        let report = calculate(trades, &prices, &Config::default()).unwrap();
+        let gains = report.gains(None);
+
+        assert_eq!(remainder, dec!(0.012345687901234576));
+        assert_eq!(gains.len(), 9);
+        assert_money_eq!(gains.total_allowable_costs(), gbp!(1000));
+        assert_eq!(*report.pools["ETH"].total.amount(), dec!(0));
+        assert_eq!(*report.pools["ETH"].costs.amount(), dec!(0));
+    }
+
+    #[test]
+    fn dust_left_in_pool_is_zeroed() {
+        let buy = trade("2020-01-01", TradeKind::Buy, gbp!(1000), btc!(1), 1000);
+        let sell = trade(
+            "2020-02-01",
+            TradeKind::Sell,
+            btc!(0.99999999),
+            gbp!(2000),
+            2000,
+        );
+        let prices = Prices::default();
+
+        let report =
+            calculate(vec![buy.clone(), sell.clone()], &prices, &Config::default()).unwrap();
+        assert_money_eq!(report.pools["BTC"].total, btc!(0.00000001));
+
+        let config = Config {
+            dust_threshold: Some(dec!(0.000001)),
+            ..Default::default()
+        };
+        let report = calculate(vec![buy, sell], &prices, &config).unwrap();
+        let gains = report.gains(None);
+
+        assert_eq!(*report.pools["BTC"].total.amount(), dec!(0));
+        assert_eq!(*report.pools["BTC"].costs.amount(), dec!(0));
+        assert_money_eq!(gains.total_allowable_costs(), gbp!(1000));
+    }
+
+    #[test]
+    fn json_output_includes_matching_components() {
+        let buy1 = trade("2020-01-01", TradeKind::Buy, gbp!(10_000), btc!(10), 1000);
+        let sell = trade("2020-03-01", TradeKind::Sell, btc!(6), gbp!(12_000), 2000);
+        let buy2 = trade("2020-03-01", TradeKind::Buy, gbp!(3000), btc!(2), 1500);
+        let buy2_id = buy2.id();
+
+        let trades = vec![buy1, sell, buy2];
+        let prices = Prices::default();
+        let report = calculate(trades, &prices, &Config::default()).unwrap();
+
+        let mut output = Vec::new();
+        report.gains(None).write_json(&mut output).unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&output).unwrap();
+
+        assert_eq!(
+            json,
+            serde_json::json!([{
+                "id": json[0]["id"],
+                "date_time": "2020-03-01T23:59:59",
+                "tax_year": 2020,
+                "account": "",
+                "source_id": null,
+                "asset": "BTC",
+                "quantity": "6.00000000",
+                "proceeds": "12000.00",
+                "allowable_costs": "7000.00",
+                "fees": "0.00",
+                "gain": "5000.00",
+                "matching": [
+                    {
+                        "rule": "same_day",
+                        "quantity": "2.00000000",
+                        "cost": "3000.00",
+                        "matched_date": "2020-03-01",
+                        "matched_id": buy2_id
+                    },
+                    {
+                        "rule": "pool",
+                        "quantity": "4.00000000",
+                        "cost": "4000.00",
+                        "matched_date": null,
+                        "matched_id": null
+                    }
+                ],
+                "warnings": []
+            }])
+        );
+    }
+
+    #[test]
+    fn disposals_csv_round_trip() {
+        let buy1 = trade("2020-01-01", TradeKind::Buy, gbp!(10_000), btc!(10), 1000);
+        let sell1 = trade("2020-03-01", TradeKind::Sell, btc!(6), gbp!(12_000), 2000);
+        let buy2 = trade("2020-03-01", TradeKind::Buy, gbp!(3000), btc!(2), 1500);
+        let sell2 = trade("2020-05-01", TradeKind::Sell, btc!(7), gbp!(7000), 1000);
+
+        let trades = vec![buy1, sell1, buy2, sell2];
+        let prices = Prices::default();
+        let report = calculate(trades, &prices, &Config::default()).unwrap();
+
+        let mut output = Vec::new();
+        report.gains(None).write_disposals_csv(&mut output).unwrap();
+        let mut rdr = csv::Reader::from_reader(output.as_slice());
+        let records = rdr
+            .deserialize()
+            .collect::<Result<Vec<DisposalCsvRecord>, _>>()
+            .unwrap();
+
+        assert_eq!(
+            records,
+            vec![
+                DisposalCsvRecord {
+                    date: "2020-03-01".into(),
+                    account: "".into(),
+                    source_id: "".into(),
+                    asset: "BTC".into(),
+                    quantity: "6".into(),
+                    proceeds: "12000.00".into(),
+                    allowable_cost: "7000.00".into(),
+                    fees: "0.00".into(),
+                    gain: "5000.00".into(),
+                    matching_rule_summary: "same_day 2; pool 4".into(),
+                    warnings: "".into(),
+                },
+                DisposalCsvRecord {
+                    date: "2020-05-01".into(),
+                    account: "".into(),
+                    source_id: "".into(),
+                    asset: "BTC".into(),
+                    quantity: "7".into(),
+                    proceeds: "7000.00".into(),
+                    allowable_cost: "6000.00".into(),
+                    fees: "0.00".into(),
+                    gain: "1000.00".into(),
+                    matching_rule_summary: "pool 7".into(),
+                    warnings: "Disposed of 7 but only 6 in the pool".into(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn disposal_account_is_the_exchange_of_the_trade() {
+        let buy = Trade {
+            exchange: Some("Coinbase".into()),
+            ..trade("2020-01-01", TradeKind::Buy, gbp!(10_000), btc!(1), 10_000)
+        };
+        let sell = Trade {
+            exchange: Some("Kraken".into()),
+            ..trade("2020-03-01", TradeKind::Sell, btc!(1), gbp!(12_000), 12_000)
+        };
+        let prices = Prices::default();
+        let report = calculate(vec![buy, sell], &prices, &Config::default()).unwrap();
+
+        let mut output = Vec::new();
+        report.gains(None).write_disposals_csv(&mut output).unwrap();
+        let mut rdr = csv::Reader::from_reader(output.as_slice());
+        let accounts = rdr
+            .deserialize()
+            .map(|record: Result<DisposalCsvRecord, _>| record.unwrap().account)
+            .collect::<Vec<_>>();
+
+        assert_eq!(accounts, vec!["Kraken".to_string()]);
+    }
+
+    #[test]
+    fn gains_are_totalled_by_asset() {
+        use crate::currencies::ETH;
+        let eth = |amount| Money::from_decimal(amount, ETH);
+
+        let trades = vec![
+            trade("2020-01-01", TradeKind::Buy, gbp!(10_000), btc!(2), 5000),
+            trade("2020-01-01", TradeKind::Buy, gbp!(1000), eth(dec!(10)), 100),
+            trade("2020-02-01", TradeKind::Sell, eth(dec!(5)), gbp!(400), 80),
+            trade("2020-03-01", TradeKind::Sell, btc!(1), gbp!(8000), 8000),
+            trade("2020-04-01", TradeKind::Sell, btc!(2), gbp!(9000), 4500),
+        ];
+        let prices = Prices::default();
+        let report = calculate(trades, &prices, &Config::default()).unwrap();
+
+        let by_asset = report.gains(None).by_asset();
+
+        let totals = by_asset
+            .iter()
+            .map(|a| {
+                (
+                    a.asset.code,
+                    a.disposals,
+                    a.proceeds.to_string(),
+                    a.costs.to_string(),
+                    a.gain.to_string(),
+                    a.has_warnings,
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            totals,
+            vec![
+                (
+                    "BTC",
+                    2,
+                    gbp!(17_000).to_string(),
+                    gbp!(10_000).to_string(),
+                    gbp!(7000).to_string(),
+                    true
+                ),
+                (
+                    "ETH",
+                    1,
+                    gbp!(400).to_string(),
+                    gbp!(500).to_string(),
+                    gbp!(-100).to_string(),
+                    false
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn pools_at_end_of_tax_year() {
+        use crate::currencies::ETH;
+        let eth = |amount| Money::from_decimal(amount, ETH);
+
+        let trades = vec![
+            trade("2019-01-01", TradeKind::Buy, gbp!(10_000), btc!(10), 1000),
+            trade("2019-02-01", TradeKind::Buy, gbp!(1000), eth(dec!(10)), 100),
+            trade("2019-03-01", TradeKind::Sell, btc!(4), gbp!(8000), 2000),
+            trade("2019-04-05", TradeKind::Buy, gbp!(3000), btc!(2), 1500),
+            trade("2019-04-06", TradeKind::Sell, btc!(8), gbp!(16_000), 2000),
+            trade(
+                "2019-05-01",
+                TradeKind::Sell,
+                eth(dec!(10)),
+                gbp!(2000),
+                200,
+            ),
+        ];
+        let prices = Prices::default();
+        let report = calculate(trades, &prices, &Config::default()).unwrap();
+
+        let pools = report.pools_at_end_of(2019);
+
+        assert_eq!(pools.len(), 2);
+        assert_eq!(pools[0].currency().code, "BTC");
+        assert_money_eq!(pools[0].total(), btc!(8));
+        assert_money_eq!(pools[0].costs(), gbp!(9000));
+        assert_eq!(pools[1].currency().code, "ETH");
+        assert_money_eq!(pools[1].total(), eth(dec!(10)));
+
+        let pools = report.pools_at_end_of(2020);
+        assert_eq!(*pools[0].total().amount(), dec!(0));
+        assert_eq!(*pools[1].total().amount(), dec!(0));
+    }
+
+    #[test]
+    fn bnb_match_across_tax_year_end_is_gain_of_disposal_year() {
+        let trades = vec![
+            trade("2019-06-01", TradeKind::Buy, gbp!(10_000), btc!(10), 1000),
+            trade("2020-04-02", TradeKind::Sell, btc!(5), gbp!(10_000), 2000),
+            trade("2020-04-20", TradeKind::Buy, gbp!(7500), btc!(5), 1500),
+        ];
+        let prices = Prices::default();
+        let report = calculate(trades, &prices, &Config::default()).unwrap();
+
+        let gains_2020 = report.gains(Some(2020));
+        assert_eq!(gains_2020.len(), 1);
+        assert_money_eq!(gains_2020.total_allowable_costs(), gbp!(7500));
+        assert_money_eq!(gains_2020.total_gain(), gbp!(2500));
+        assert_eq!(report.gains(Some(2021)).len(), 0);
+
+        // the pool is untouched by the disposal, and the acquisition is not added to it
+        let pools = report.pools_at_end_of(2020);
+        assert_money_eq!(pools[0].total(), btc!(10));
+        assert_money_eq!(pools[0].costs(), gbp!(10_000));
+        let pools = report.pools_at_end_of(2021);
+        assert_money_eq!(pools[0].total(), btc!(10));
+        assert_money_eq!(pools[0].costs(), gbp!(10_000));
+    }
+
+    #[test]
+    fn same_day_acquisitions_pool_at_exactly_what_was_paid() {
+        // the rates are rounded, as in exchange exports, so quantity * rate is not what was paid
+        let trades = vec![
+            trade(
+                "2020-01-01",
+                TradeKind::Buy,
+                gbp!(1000),
+                btc!(0.3),
+                dec!(3333.33),
+            ),
+            trade(
+                "2020-01-01",
+                TradeKind::Buy,
+                gbp!(250.01),
+                btc!(0.07),
+                dec!(3571.57),
+            ),
+            trade(
+                "2020-01-01",
+                TradeKind::Buy,
+                gbp!(99.99),
+                btc!(0.029),
+                dec!(3447.93),
+            ),
+            trade(
+                "2020-01-01",
+                TradeKind::Buy,
+                gbp!(1234.56),
+                btc!(0.333),
+                dec!(3707.39),
+            ),
+            trade(
+                "2020-01-01",
+                TradeKind::Buy,
+                gbp!(10),
+                btc!(0.003),
+                dec!(3333.33),
+            ),
+            trade(
+                "2020-03-01",
+                TradeKind::Sell,
+                btc!(0.735),
+                gbp!(3000),
+                dec!(4081.63),
+            ),
+        ];
+        let prices = Prices::default();
+        let report = calculate(trades, &prices, &Config::default()).unwrap();
+
+        let gains = report.gains(None);
+        assert_eq!(*gains.total_allowable_costs().amount(), dec!(2594.56));
+        assert_eq!(*report.pools["BTC"].total().amount(), dec!(0));
+        assert_eq!(*report.pools["BTC"].costs().amount(), dec!(0));
+    }
+
+    #[test]
+    fn opening_pool_covers_early_disposal() {
+        let opening_pools = OpeningPool::read_csv(
+            "asset,quantity,cost_gbp,acquisition_date\nBTC,10,5000,2019-12-31\n".as_bytes(),
+        )
+        .unwrap();
+        let config = Config {
+            opening_pools,
+            ..Default::default()
+        };
+        let trades = vec![
+            trade("2020-01-01", TradeKind::Sell, btc!(4), gbp!(8000), 2000),
+            trade("2020-06-01", TradeKind::Buy, gbp!(3000), btc!(1), 3000),
+        ];
+        let prices = Prices::default();
+        let report = calculate(trades, &prices, &config).unwrap();
+
+        let gains = report.gains(None);
+
+        assert!(gains.gains[0].warnings().is_empty());
+        assert_money_eq!(gains.total_allowable_costs(), gbp!(2000));
+        assert_money_eq!(gains.total_gain(), gbp!(6000));
+        assert_money_eq!(report.pools["BTC"].total, btc!(7));
+        assert_money_eq!(report.pools["BTC"].costs, gbp!(6000));
+
+        let pools = report.pools_at_end_of(2019);
+        assert_money_eq!(pools[0].total(), btc!(10));
+    }
+
+    #[test]
+    fn reward_acquired_at_market_value_is_allowable_cost() {
+        use crate::currencies::DOT;
+        let dot = |amount| Money::from_decimal(amount, DOT);
+
+        // staking rewards are imported as a buy at their market value when received
+        let reward = trade("2020-09-01", TradeKind::Buy, gbp!(800), dot(dec!(100)), 8);
+        let disposal = trade(
+            "2021-02-01",
+            TradeKind::Sell,
+            dot(dec!(100)),
+            gbp!(1000),
+            10,
+        );
+
+        let prices = Prices::default();
+        let report = calculate(vec![reward, disposal], &prices, &Config::default()).unwrap();
+        let gains = report.gains(None);
+
+        assert_eq!(gains.gains[1].matching()[0].rule, MatchingRule::Pool);
+        assert_money_eq!(gains.total_allowable_costs(), gbp!(800));
+        assert_money_eq!(gains.total_gain(), gbp!(200));
+    }
+
+    #[test]
+    fn disposal_for_other_fiat_is_valued_at_exchange_rate() {
+        use crate::currencies::EUR;
+        let eur = |amount| Money::from_decimal(amount, EUR);
+        let trades = vec![
+            trade("2020-01-01", TradeKind::Buy, gbp!(10000), btc!(1), 10000),
+            trade(
+                "2020-02-01",
+                TradeKind::Sell,
+                btc!(1),
+                eur(dec!(30000)),
+                30000,
+            ),
+        ];
+        let prices = Prices::read_csv(
+            "base_currency,quote_currency,date_time,rate\nEUR,GBP,2020-02-01T00:00:00Z,0.85\n"
+                .as_bytes(),
+        )
+        .unwrap();
+
+        let report = calculate(trades, &prices, &Config::default()).unwrap();
+        let gains = report.gains(None);
+
+        assert_eq!(gains.len(), 1);
+        assert_money_eq!(gains.total_proceeds(), gbp!(25500));
+        assert_money_eq!(gains.total_gain(), gbp!(15500));
+        assert!(report.warnings().is_empty());
+        assert!(!report.pools.contains_key("EUR"), "EUR is not an asset");
+    }
+
+    #[test]
+    fn missing_prices_are_an_error_naming_the_assets() {
+        use crate::currencies::{ETH, USDC};
+        let trades = vec![
+            trade("2020-01-01", TradeKind::Buy, gbp!(10000), btc!(1), 10000),
+            trade(
+                "2020-02-01",
+                TradeKind::Sell,
+                btc!(0.5),
+                Money::from_decimal(dec!(20), ETH),
+                40,
+            ),
+            trade(
+                "2020-03-01",
+                TradeKind::Sell,
+                btc!(0.5),
+                Money::from_decimal(dec!(7000), USDC),
+                14000,
+            ),
+        ];
+        let prices = Prices::read_csv(
+            "base_currency,quote_currency,date_time,rate\nUSDC,GBP,2020-03-01T00:00:00Z,0.8\n"
+                .as_bytes(),
+        )
+        .unwrap();
+
+        let err = calculate(trades, &prices, &Config::default())
+            .err()
+            .unwrap();
+
+        assert_eq!(err.to_string(), "No prices in GBP for ETH from 2020-02-01");
+    }
+
+    #[test]
+    fn trades_valued_at_carried_prices_are_warned() {
+        use crate::currencies::USDC;
+        let trades = vec![
+            trade("2020-01-01", TradeKind::Buy, gbp!(10000), btc!(1), 10000),
+            trade(
+                "2020-03-01",
+                TradeKind::Sell,
+                btc!(0.5),
+                Money::from_decimal(dec!(7000), USDC),
+                14000,
+            ),
+        ];
+        let prices = Prices::read_csv(
+            "base_currency,quote_currency,date_time,rate\nUSDC,GBP,2020-02-28T00:00:00Z,0.8\n"
+                .as_bytes(),
+        )
+        .unwrap()
+        .with_gap_policy(PriceGapPolicy::Carry);
+
+        let report = calculate(trades, &prices, &Config::default()).unwrap();
+
+        let messages = report
+            .warnings()
+            .into_iter()
+            .map(|warning| warning.message)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            messages,
+            vec!["Valued with the USDC/GBP price from 2020-02-28, carried forward"]
+        );
+    }
+
+    #[test]
+    fn prices_are_looked_up_on_the_local_day() {
+        use crate::currencies::{ETH, USDC};
+        let trades = vec![
+            trade("2020-01-01", TradeKind::Buy, gbp!(10000), btc!(1), 10000),
+            trade(
+                "2020-02-01",
+                TradeKind::Sell,
+                btc!(0.5),
+                Money::from_decimal(dec!(20), ETH),
+                40,
+            ),
+            trade(
+                "2020-03-01",
+                TradeKind::Sell,
+                btc!(0.5),
+                Money::from_decimal(dec!(7000), USDC),
+                14000,
+            ),
+        ];
+        // the trades are at 23:59:59 UTC, so on the next day at +01:00
+        let prices = Prices::read_csv(
+            "base_currency,quote_currency,date_time,rate\nUSDC,GBP,2020-03-02T00:00:00Z,0.8\n"
+                .as_bytes(),
+        )
+        .unwrap();
+        let config = Config {
+            time_zone: TimeZone::Fixed(FixedOffset::east(3600)),
+            ..Config::default()
+        };
+
+        let err = calculate(trades, &prices, &config).err().unwrap();
+
+        assert_eq!(err.to_string(), "No prices in GBP for ETH from 2020-02-02");
+    }
+
+    #[test]
+    fn price_after_the_trade_is_a_warning() {
+        use crate::currencies::USDC;
+        // looks up the price of the day after, as a lookup with a time zone error might
+        struct NextDayPrices<'a>(Prices<'a>);
+        impl<'a> PriceLookup<'a> for NextDayPrices<'a> {
+            fn price(&self, pair: CurrencyPair<'a>, at: NaiveDate) -> Option<Price<'a>> {
+                self.0.get(pair, at.succ())
+            }
+        }
+        let trades = vec![
+            trade("2020-01-01", TradeKind::Buy, gbp!(10000), btc!(1), 10000),
+            trade(
+                "2020-03-01",
+                TradeKind::Sell,
+                btc!(0.5),
+                Money::from_decimal(dec!(7000), USDC),
+                14000,
+            ),
+        ];
+        let prices = || {
+            Prices::read_csv(
+                "base_currency,quote_currency,date_time,rate\n\
+                 USDC,GBP,2020-03-01T00:00:00Z,0.8\n\
+                 USDC,GBP,2020-03-02T00:00:00Z,0.81\n"
+                    .as_bytes(),
+            )
+            .unwrap()
+        };
+        let messages = |report: TaxReport| {
+            report
+                .warnings()
+                .into_iter()
+                .map(|warning| warning.message)
+                .collect::<Vec<_>>()
+        };
+
+        let report = calculate(trades.clone(), &prices(), &Config::default()).unwrap();
+        assert!(messages(report).is_empty());
+
+        let mispriced = NextDayPrices(prices());
+        let report = calculate(trades.clone(), &mispriced, &Config::default()).unwrap();
+        assert_eq!(
+            messages(report),
+            vec!["Valued with the USDC/GBP price from 2020-03-02 00:00:00, after the trade"]
+        );
+
+        // the trade at 23:59:59 UTC is on 2 March at +01:00
+        let plus_one = Config {
+            time_zone: TimeZone::Fixed(FixedOffset::east(3600)),
+            ..Config::default()
+        };
+        let report = calculate(trades.clone(), &prices(), &plus_one).unwrap();
+        assert!(messages(report).is_empty());
+
+        let tolerant = Config {
+            look_ahead_tolerance: Duration::days(1),
+            ..Config::default()
+        };
+        let report = calculate(trades, &mispriced, &tolerant).unwrap();
+        assert!(messages(report).is_empty());
+    }
+
+    #[test]
+    fn trade_rate_is_not_after_the_trade_west_of_utc() {
+        use crate::currencies::USDC;
+        // 00:30 UTC on 2 March is 1 March at -05:00
+        let early = |trade: Trade<'static>| Trade {
+            date_time: NaiveDate::from_ymd(2020, 3, 2).and_hms(0, 30, 0),
+            ..trade
+        };
+        let trades = vec![
+            trade("2020-01-01", TradeKind::Buy, gbp!(10000), btc!(1), 10000),
+            early(trade(
+                "2020-03-02",
+                TradeKind::Sell,
+                btc!(0.25),
+                gbp!(3500),
+                14000,
+            )),
+            early(trade(
+                "2020-03-02",
+                TradeKind::Sell,
+                btc!(0.25),
+                Money::from_decimal(dec!(3500), USDC),
+                14000,
+            )),
+        ];
+        let config = Config {
+            time_zone: TimeZone::Fixed(FixedOffset::west(5 * 3600)),
+            stablecoin_peg: Some(dec!(1)),
+            ..Config::default()
+        };
+
+        let report = calculate(trades, &Prices::default(), &config).unwrap();
+
+        assert!(report.warnings().is_empty(), "{:?}", report.warnings());
+    }
+
+    #[test]
+    fn stablecoins_treated_as_fiat_only_when_pegged() {
+        use crate::currencies::{ETH, USDC};
+        let usdc = |amount| Money::from_decimal(amount, USDC);
+        let trades = || {
+            vec![
+                trade("2020-01-01", TradeKind::Buy, gbp!(10000), btc!(1), 10000),
+                trade(
+                    "2020-02-01",
+                    TradeKind::Sell,
+                    btc!(1),
+                    usdc(dec!(15000)),
+                    15000,
+                ),
+                trade(
+                    "2020-03-01",
+                    TradeKind::Buy,
+                    usdc(dec!(15000)),
+                    Money::from_decimal(dec!(100), ETH),
+                    150,
+                ),
+            ]
+        };
+        let prices = Prices::read_csv(
+            "base_currency,quote_currency,date_time,rate\n\
+             USDC,GBP,2020-02-01T00:00:00Z,0.8\n\
+             USDC,GBP,2020-03-01T00:00:00Z,0.75\n"
+                .as_bytes(),
+        )
+        .unwrap();
+
+        let report = calculate(trades(), &prices, &Config::default()).unwrap();
+        let gains = report.gains(None);
+
+        assert_eq!(gains.len(), 2, "Selling USDC is a disposal by default");
+        assert_money_eq!(gains.total_gain(), gbp!(1250));
+        assert_money_eq!(report.pools["ETH"].costs, gbp!(11250));
+
+        let config = Config {
+            stablecoin_peg: Some(dec!(0.8)),
+            ..Config::default()
+        };
+        let report = calculate(trades(), &prices, &config).unwrap();
+        let gains = report.gains(None);
+
+        assert_eq!(gains.len(), 1, "Only the BTC should be disposed of");
+        assert_money_eq!(gains.total_proceeds(), gbp!(12000));
+        assert_money_eq!(gains.total_gain(), gbp!(2000));
+        assert_money_eq!(report.pools["ETH"].costs, gbp!(12000));
+        assert!(!report.pools.contains_key("USDC"));
+    }
+
+    #[test]
+    fn same_instant_trades_are_matched_regardless_of_input_order() {
+        let acq = trade("2020-01-01", TradeKind::Buy, gbp!(10000), btc!(2), 5000);
+        let same_day = vec![
+            trade("2020-02-01", TradeKind::Sell, btc!(1), gbp!(8000), 8000),
+            trade("2020-02-01", TradeKind::Sell, btc!(0.5), gbp!(5000), 10000),
+            trade("2020-02-01", TradeKind::Buy, gbp!(9000), btc!(1), 9000),
+        ];
+        let orders = [
+            [0, 1, 2],
+            [0, 2, 1],
+            [1, 0, 2],
+            [1, 2, 0],
+            [2, 0, 1],
+            [2, 1, 0],
+        ];
+
+        let outputs = orders
+            .iter()
+            .map(|order| {
+                let mut trades = vec![acq.clone()];
+                trades.extend(order.iter().map(|i| same_day[*i].clone()));
+                let prices = Prices::default();
+                let report = calculate(trades, &prices, &Config::default()).unwrap();
+                let mut output = Vec::new();
+                report.gains(None).write_disposals_csv(&mut output).unwrap();
+                String::from_utf8(output).unwrap()
+            })
+            .collect::<Vec<_>>();
+
+        for output in &outputs[1..] {
+            assert_eq!(output, &outputs[0]);
+        }
+    }
+
+    #[test]
+    fn average_cost_basis_is_weighted_by_quantity() {
+        let trades = vec![
+            trade("2020-01-01", TradeKind::Buy, gbp!(10000), btc!(1), 10000),
+            trade("2020-02-01", TradeKind::Buy, gbp!(60000), btc!(3), 20000),
+            trade("2020-03-01", TradeKind::Sell, btc!(2), gbp!(50000), 25000),
+        ];
+        let prices = Prices::default();
+        let report = calculate(trades, &prices, &Config::default()).unwrap();
+
+        assert_eq!(report.average_cost_basis("BTC"), Some(dec!(17500)));
+        assert_eq!(report.average_cost_basis("ETH"), None);
+
+        let trades = vec![
+            trade("2020-01-01", TradeKind::Buy, gbp!(10000), btc!(1), 10000),
+            trade("2020-02-01", TradeKind::Sell, btc!(1), gbp!(20000), 20000),
+        ];
+        let report = calculate(trades, &prices, &Config::default()).unwrap();
+        assert_eq!(
+            report.average_cost_basis("BTC"),
+            None,
+            "Empty pool has no cost basis"
+        );
+    }
+
+    #[cfg(feature = "parallel")]
+    #[test]
+    fn parallel_matching_is_identical_to_serial() {
+        use crate::currencies::{ETH, USDC};
+        let eth = |amount| Money::from_decimal(amount, ETH);
+        let usdc = |amount| Money::from_decimal(amount, USDC);
+        let trades = vec![
+            trade("2020-01-01", TradeKind::Buy, gbp!(10000), btc!(1), 10000),
+            trade("2020-01-02", TradeKind::Buy, gbp!(2000), eth(dec!(10)), 200),
+            trade("2020-02-01", TradeKind::Sell, btc!(0.5), eth(dec!(25)), 50),
+            trade("2020-02-10", TradeKind::Buy, gbp!(4000), btc!(0.25), 16000),
+            trade(
+                "2020-03-01",
+                TradeKind::Sell,
+                eth(dec!(20)),
+                usdc(dec!(6000)),
+                300,
+            ),
+            trade("2020-03-05", TradeKind::Buy, gbp!(1000), eth(dec!(4)), 250),
+            trade(
+                "2020-04-01",
+                TradeKind::Sell,
+                btc!(0.75),
+                gbp!(12000),
+                16000,
+            ),
+        ];
+        let prices = Prices::read_csv(
+            "base_currency,quote_currency,date_time,rate\n\
+             ETH,GBP,2020-02-01T00:00:00Z,220\n\
+             USDC,GBP,2020-03-01T00:00:00Z,0.8\n"
+                .as_bytes(),
+        )
+        .unwrap();
+
+        let canonical = |parallel| {
+            let report =
+                calculate_assets(trades.clone(), &prices, &Config::default(), parallel).unwrap();
+            let mut output = Vec::new();
+            report.write_canonical(None, &mut output).unwrap();
+            String::from_utf8(output).unwrap()
+        };
+
+        assert_eq!(canonical(true), canonical(false));
+    }
+
+    #[test]
+    fn snapshot_window_warning_only_without_trades_before_the_snapshot() {
+        let config = Config {
+            snapshot_date: Some(NaiveDate::from_ymd(2020, 4, 5)),
+            ..Config::default()
+        };
+        let window_disposal = trade("2020-03-20", TradeKind::Sell, btc!(1), gbp!(6000), 6000);
+        let acquisition = trade("2020-04-10", TradeKind::Buy, gbp!(3500), btc!(0.5), 7000);
+        let later_acquisition = trade("2020-06-20", TradeKind::Buy, gbp!(4000), btc!(0.4), 10000);
+
+        let minimal = vec![window_disposal, acquisition.clone()];
+        assert!(snapshot_window_warning(&minimal, &config).is_none());
+        let without_matches = vec![later_acquisition];
+        assert!(snapshot_window_warning(&without_matches, &config).is_none());
+        let missing_window = vec![acquisition];
+        assert!(snapshot_window_warning(&missing_window, &config).is_some());
+    }
+
+    #[test]
+    fn same_day_is_taken_in_local_time() {
+        let at = |date_time: &str, trade| Trade {
+            date_time: NaiveDateTime::parse_from_str(date_time, "%Y-%m-%d %H:%M").unwrap(),
+            ..trade
+        };
+        let trades = vec![
+            at(
+                "2020-01-01 12:00",
+                trade("2020-01-01", TradeKind::Buy, gbp!(1000), btc!(1), 1000),
+            ),
+            // 00:30 on 2 June at +01:00
+            at(
+                "2020-06-01 23:30",
+                trade("2020-06-01", TradeKind::Sell, btc!(1), gbp!(5000), 5000),
+            ),
+            at(
+                "2020-06-02 09:00",
+                trade("2020-06-02", TradeKind::Buy, gbp!(6000), btc!(1), 6000),
+            ),
+        ];
+        let prices = Prices::default();
+        let rules = |config: &Config| {
+            let report = calculate(trades.clone(), &prices, config).unwrap();
+            let disposal = report.gains(None).into_iter().find(TaxEvent::is_disposal);
+            disposal
+                .unwrap()
+                .matching()
+                .iter()
+                .map(|c| (c.rule, c.matched_date.unwrap().to_string()))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            rules(&Config::default()),
+            vec![(MatchingRule::BedAndBreakfast, "2020-06-02".to_string())]
+        );
+        let plus_one = Config {
+            time_zone: TimeZone::Fixed(FixedOffset::east(3600)),
+            ..Config::default()
+        };
+        assert_eq!(
+            rules(&plus_one),
+            vec![(MatchingRule::SameDay, "2020-06-02".to_string())]
+        );
+    }
+
+    #[test]
+    fn uk_day_follows_british_summer_time() {
+        let at = |date_time: &str, trade| Trade {
+            date_time: NaiveDateTime::parse_from_str(date_time, "%Y-%m-%d %H:%M").unwrap(),
+            ..trade
+        };
+        let trades = vec![
+            trade("2020-01-01", TradeKind::Buy, gbp!(2000), btc!(2), 1000),
+            // 00:30 on 2 June in British Summer Time
+            at(
+                "2020-06-01 23:30",
+                trade("2020-06-01", TradeKind::Sell, btc!(1), gbp!(5000), 5000),
+            ),
+            at(
+                "2020-06-02 09:00",
+                trade("2020-06-02", TradeKind::Buy, gbp!(6000), btc!(1), 6000),
+            ),
+            // still 1 December in GMT
+            at(
+                "2020-12-01 23:30",
+                trade("2020-12-01", TradeKind::Sell, btc!(1), gbp!(5000), 5000),
+            ),
+            at(
+                "2020-12-02 09:00",
+                trade("2020-12-02", TradeKind::Buy, gbp!(6000), btc!(1), 6000),
+            ),
+        ];
+        let prices = Prices::default();
+        let config = Config {
+            time_zone: TimeZone::Uk,
+            ..Config::default()
+        };
+
+        let report = calculate(trades, &prices, &config).unwrap();
+
+        let rules = report
+            .gains(None)
+            .into_iter()
+            .filter(TaxEvent::is_disposal)
+            .map(|disposal| disposal.matching()[0].rule)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            rules,
+            vec![MatchingRule::SameDay, MatchingRule::BedAndBreakfast]
+        );
+    }
+
+    #[test]
+    fn no_bnb_days_matches_same_day_only() {
+        let trades = vec![
+            trade("2020-01-01", TradeKind::Buy, gbp!(10000), btc!(2), 5000),
+            Trade {
+                date_time: NaiveDate::from_ymd(2020, 2, 1).and_hms(9, 0, 0),
+                ..trade("2020-02-01", TradeKind::Sell, btc!(1), gbp!(8000), 8000)
+            },
+            trade("2020-02-01", TradeKind::Buy, gbp!(3500), btc!(0.5), 7000),
+            trade("2020-02-02", TradeKind::Buy, gbp!(6000), btc!(1), 6000),
+        ];
+        let prices = Prices::default();
+        let config = Config {
+            matching: MatchingConfig {
+                same_day: true,
+                bnb_days: 0,
+            },
+            ..Default::default()
+        };
+        let report = calculate(trades, &prices, &config).unwrap();
+        let gains = report.gains(None);
+
+        let rules = gains.gains[1]
+            .matching()
+            .iter()
+            .map(|m| m.rule)
+            .collect::<Vec<_>>();
+        assert_eq!(rules, vec![MatchingRule::SameDay, MatchingRule::Pool]);
+        assert_money_eq!(gains.total_allowable_costs(), gbp!(6000));
+    }
+
+    #[test]
+    fn bnb_window_is_configurable() {
+        let sell = trade("2020-02-01", TradeKind::Sell, btc!(1), gbp!(8000), 8000);
+        let buy_in = |date| trade(date, TradeKind::Buy, gbp!(3000), btc!(0.5), 6000);
+        let prices = Prices::default();
+        let config = Config {
+            matching: MatchingConfig {
+                same_day: true,
+                bnb_days: 14,
+            },
+            ..Default::default()
+        };
+        let rules = |trades| {
+            let report = calculate(trades, &prices, &config).unwrap();
+            let gains = report.gains(None);
+            gains.gains[1]
+                .matching()
+                .iter()
+                .map(|m| m.rule)
+                .collect::<Vec<_>>()
+        };
+        let acq = trade("2020-01-01", TradeKind::Buy, gbp!(10000), btc!(2), 5000);
+
+        assert_eq!(
+            rules(vec![acq.clone(), sell.clone(), buy_in("2020-02-15")]),
+            vec![MatchingRule::BedAndBreakfast, MatchingRule::Pool]
+        );
+        assert_eq!(
+            rules(vec![acq, sell, buy_in("2020-02-16")]),
+            vec![MatchingRule::Pool]
+        );
+    }
+
+    #[test]
+    fn same_day_acquisition_is_split_between_disposals() {
+        let at = |hour, trade| Trade {
+            date_time: NaiveDate::from_ymd(2020, 6, 1).and_hms(hour, 0, 0),
+            ..trade
+        };
+        let trades = vec![
+            trade("2020-01-01", TradeKind::Buy, gbp!(5000), btc!(10), 500),
+            at(
+                9,
+                trade("2020-06-01", TradeKind::Buy, gbp!(10000), btc!(10), 1000),
+            ),
+            at(
+                10,
+                trade("2020-06-01", TradeKind::Sell, btc!(6), gbp!(7200), 1200),
+            ),
+            at(
+                11,
+                trade("2020-06-01", TradeKind::Sell, btc!(6), gbp!(7800), 1300),
+            ),
+        ];
+        let prices = Prices::default();
+        let report = calculate(trades, &prices, &Config::default()).unwrap();
+        let gains = report.gains(None);
+        let disposals = gains.disposals().collect::<Vec<_>>();
+
+        let components = |disposal: &TaxEvent| {
+            disposal
+                .matching()
+                .iter()
+                .map(|m| (m.rule, m.quantity.to_string(), m.cost.to_string()))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            components(disposals[0]),
+            vec![(
+                MatchingRule::SameDay,
+                btc!(6).to_string(),
+                gbp!(6000).to_string()
+            )]
+        );
+        assert_eq!(
+            components(disposals[1]),
+            vec![
+                (
+                    MatchingRule::SameDay,
+                    btc!(4).to_string(),
+                    gbp!(4000).to_string()
+                ),
+                (
+                    MatchingRule::Pool,
+                    btc!(2).to_string(),
+                    gbp!(1000).to_string()
+                ),
+            ]
+        );
+        assert_money_eq!(gains.total_allowable_costs(), gbp!(11000));
+        assert_money_eq!(report.pools["BTC"].total, btc!(8));
+        assert_money_eq!(report.pools["BTC"].costs, gbp!(4000));
+    }
+
+    #[test]
+    fn pooling_by_account_keeps_exchange_costs_apart() {
+        let on = |exchange: &str, trade| Trade {
+            exchange: Some(exchange.into()),
+            ..trade
+        };
+        let trades = vec![
+            on(
+                "Binance",
+                trade("2020-01-01", TradeKind::Buy, gbp!(10000), btc!(1), 10000),
+            ),
+            on(
+                "Kraken",
+                trade("2020-03-01", TradeKind::Buy, gbp!(20000), btc!(1), 20000),
+            ),
+            on(
+                "Kraken",
+                trade("2020-06-01", TradeKind::Sell, btc!(1), gbp!(25000), 25000),
+            ),
+        ];
+        let prices = Prices::default();
+
+        let global = calculate(trades.clone(), &prices, &Config::default()).unwrap();
+        assert_money_eq!(global.gains(None).total_gain(), gbp!(10000));
+        assert_money_eq!(global.pools["BTC"].costs, gbp!(15000));
+
+        let config = Config {
+            pool_by_account: true,
+            ..Config::default()
+        };
+        let by_account = calculate(trades, &prices, &config).unwrap();
+        assert_money_eq!(by_account.gains(None).total_gain(), gbp!(5000));
+        assert_money_eq!(by_account.pools["Binance:BTC"].total, btc!(1));
+        assert_money_eq!(by_account.pools["Binance:BTC"].costs, gbp!(10000));
+        assert_money_eq!(by_account.pools["Kraken:BTC"].total, btc!(0));
+        assert_eq!(by_account.pools["Kraken:BTC"].account(), Some("Kraken"));
+    }
+
     // todo: test crypto -> crypto trade, should be both a sale and a purchase and require a price
 
     // todo: test 30 days with multiple buys
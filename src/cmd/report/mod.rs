@@ -1,47 +1,422 @@
-use crate::{cmd::prices::Prices, currencies::GBP, trades, Money};
+use crate::{
+    cmd::{
+        pools::PoolsCommand,
+        prices::{self, CoingeckoCache, CoingeckoIds, PriceGapPolicy, Prices},
+        summary::SummaryCommand,
+    },
+    currencies::{self, Currency, GBP},
+    trades::{self, Trade},
+    Money,
+};
 use argh::FromArgs;
+use chrono::NaiveDate;
+use color_eyre::eyre;
 use rust_decimal::Decimal;
-use std::{fs::File, io, path::PathBuf};
+use std::{fs::File, io, path::PathBuf, str::FromStr, time::Duration};
 
-mod cgt;
+pub(crate) mod cgt;
+mod sa108;
+mod table;
+mod uk;
 
 #[derive(FromArgs, PartialEq, Debug)]
 #[argh(subcommand, name = "report")]
 /// Run a report to calculate CGT
 pub struct ReportCommand {
-    /// the csv file containing the transactions
+    /// the csv files containing the transactions, e.g. `--txs binance.csv --txs kraken.csv`
     #[argh(option)]
-    txs: PathBuf,
-    /// optional csv file with prices in GBP for ETH and BTC, instead of fetching from Coingecko.
+    txs: Vec<PathBuf>,
+    /// optional csv file of pools held before the first trade, with the columns
+    /// asset,quantity,cost,acquisition_date and the cost in the reporting currency
+    #[argh(option)]
+    opening_pools: Option<PathBuf>,
+    /// optional csv file of the pools at the end of a tax year, as written by `report pools
+    /// snapshot --csv` with the same options, to report on later trades without recalculating
+    /// earlier ones. The trades from the 30 days before the snapshot are still needed for the 30
+    /// day rule.
+    #[argh(option)]
+    from_snapshot: Option<PathBuf>,
+    /// optional csv file with prices in the reporting currency of the traded assets, instead of
+    /// fetching from Coingecko. Required for trades in EUR or USD other than the reporting
+    /// currency, which are valued at their exchange rate in the file rather than pooled.
     #[argh(option)]
     prices: Option<PathBuf>,
+    /// how prices are found for days without one: error (default), carry the last known price,
+    /// or interpolate between the known prices either side, over gaps of up to 7 days
+    #[argh(option, default = "PriceGapPolicy::Error")]
+    price_gap_policy: PriceGapPolicy,
+    /// optional csv file with the Coingecko ids of assets, with the columns asset,coingecko_id.
+    /// Needed for assets whose ticker is the symbol of more than one coin.
+    #[argh(option)]
+    coingecko_ids: Option<PathBuf>,
+    /// always fetch prices from Coingecko, instead of using prices cached in ~/.cache/taxc
+    #[argh(switch)]
+    no_cache: bool,
+    /// the number of hours before cached Coingecko prices are refetched, defaults to 24
+    #[argh(option, default = "24")]
+    cache_ttl: u64,
+    /// the currency in which gains are calculated, defaults to GBP. The tax liability and SA108
+    /// summary are only available for GBP.
+    #[argh(option, default = "String::from(\"GBP\")")]
+    currency: String,
     /// the tax year for which to produce the report
     #[argh(option)]
     year: Option<i32>,
+    /// only report the disposals on or after this date, e.g. 2021-01-01, instead of those of a
+    /// whole tax year. The annual exempt amount isn't applied to a range of dates.
+    #[argh(option)]
+    from: Option<NaiveDate>,
+    /// only report the disposals on or before this date, e.g. 2021-04-05
+    #[argh(option)]
+    to: Option<NaiveDate>,
+    /// print a summary of every tax year with disposals and their total, instead of a single
+    /// report. Requires the table format and GBP.
+    #[argh(switch)]
+    all_years: bool,
+    /// the output format: table (default), csv, disposals, canonical or json. The default was
+    /// csv of every tax event in earlier versions, pass `--format csv` when piping the report.
+    #[argh(option, default = "ReportFormat::Table")]
+    format: ReportFormat,
+    /// match all disposals against the Section 104 pool, ignoring the same day and 30 day rules.
+    /// Not HMRC compliant if assets were reacquired within 30 days of a disposal.
+    #[argh(switch)]
+    pool_only: bool,
+    /// match disposals with acquisitions in this many days after them, instead of the 30 days
+    /// of the UK rules. Ignored with --pool-only.
+    #[argh(option, default = "30")]
+    bnb_days: u32,
+    /// don't match disposals with acquisitions on the same day
+    #[argh(switch)]
+    no_same_day: bool,
+    /// the time zone in which the days of trades are taken: uk for the UK day, following British
+    /// Summer Time, or a fixed offset from UTC applied to every trade e.g. +01:00. Defaults to UTC.
+    #[argh(option, default = "cgt::TimeZone::default()")]
+    time_zone: cgt::TimeZone,
+    /// keep a separate pool of each asset for each exchange. Not HMRC compliant.
+    #[argh(switch)]
+    pool_by_account: bool,
+    /// split the fees of trades between two assets between the disposal and the cost of the
+    /// acquisition, instead of allowing them all against the disposal
+    #[argh(switch)]
+    split_fees: bool,
+    /// pool quantities left below this amount after a disposal are zeroed, with their remaining
+    /// costs allowed against the disposal
+    #[argh(option)]
+    dust_threshold: Option<Decimal>,
+    /// treat USDC and USDT like fiat, valued at this fixed price in the reporting currency, so
+    /// trades in and out of them are not disposals. Not HMRC compliant.
+    #[argh(option)]
+    stablecoin_peg: Option<Decimal>,
+    /// fail if any disposal has warnings, e.g. if it was not covered by earlier acquisitions
+    #[argh(switch)]
+    strict: bool,
+    /// the number of days after the local day of a trade that the price it is valued at may be
+    /// from before warning that it looks ahead, defaults to 0
+    #[argh(option, default = "0")]
+    look_ahead_tolerance: u32,
+    /// capital losses in GBP brought forward from tax years before the first trade
+    #[argh(option, default = "Decimal::new(0, 0)")]
+    losses_brought_forward: Decimal,
+    /// taxable income in GBP for the tax year, after the personal allowance. Used to apportion
+    /// gains between the basic and higher CGT rates, otherwise the higher rate is assumed.
+    #[argh(option)]
+    income: Option<Decimal>,
+    #[argh(subcommand)]
+    sub: Option<ReportSubCommand>,
+}
+
+/// Other views of the report, calculated with the same options
+#[derive(FromArgs, PartialEq, Debug)]
+#[argh(subcommand)]
+pub enum ReportSubCommand {
+    Pools(PoolsCommand),
+    Sa108(sa108::Sa108Command),
+    Summary(SummaryCommand),
+}
+
+/// The output format of the report
+#[derive(PartialEq, Debug)]
+pub enum ReportFormat {
+    /// A table of the totals, and the estimated tax for a single year
+    Table,
+    /// Every tax event as a csv record
+    Csv,
+    /// Every disposal as a csv record, with a summary of how it was matched
+    Disposals,
+    /// Stable, sorted plain text of the disposals and pools, for diffing between versions
+    Canonical,
+    /// The disposals with how they were matched with acquisitions, as an audit trail
+    Json,
+}
+
+impl FromStr for ReportFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "table" => Ok(Self::Table),
+            "csv" => Ok(Self::Csv),
+            "disposals" => Ok(Self::Disposals),
+            "canonical" => Ok(Self::Canonical),
+            "json" => Ok(Self::Json),
+            f => Err(format!("Unsupported report format {}", f)),
+        }
+    }
 }
 
 impl ReportCommand {
     pub fn exec(&self) -> color_eyre::Result<()> {
-        // todo: in the future support other quote currencies
-        let quote_currency = GBP;
+        let quote_currency = currencies::find(&self.currency)
+            .ok_or_else(|| eyre::eyre!("Unsupported currency {}", self.currency))?;
 
-        let trades = trades::read_csv(File::open(&self.txs)?)?;
-        let prices = match self.prices {
-            None => Prices::from_coingecko_api(quote_currency)?,
-            Some(ref path) => Prices::read_csv(File::open(path)?)?,
-        };
-        let report = cgt::calculate(trades, &prices)?;
-        let gains = report.gains(self.year);
+        let date_range = self.from.is_some() || self.to.is_some();
+        if date_range && (self.year.is_some() || self.all_years) {
+            return Err(eyre::eyre!(
+                "--from and --to can't be combined with --year or --all-years"
+            ));
+        }
+        if date_range && self.format == ReportFormat::Canonical {
+            return Err(eyre::eyre!(
+                "--from and --to can't be combined with the canonical format"
+            ));
+        }
+        if let Some(ref sub) = self.sub {
+            if date_range || self.year.is_some() || self.all_years {
+                return Err(eyre::eyre!(
+                    "--year, --from, --to and --all-years can't be combined with a subcommand"
+                ));
+            }
+            if quote_currency != GBP && matches!(sub, ReportSubCommand::Sa108(_)) {
+                return Err(eyre::eyre!("sa108 requires the GBP currency"));
+            }
+        }
 
-        let estimated_liability =
-            (gains.total_gain() - Money::from_major(11_300, GBP)) * Decimal::new(20, 2);
+        let trades = trades::read_csv_files(&self.txs)?;
+        let prices = self.read_prices(quote_currency, &trades)?;
+        let config = self.config(quote_currency)?;
+        let report = cgt::calculate(trades, &prices, &config)?;
+        match self.sub {
+            Some(ReportSubCommand::Pools(ref pools)) => return pools.exec(&report, &prices),
+            Some(ReportSubCommand::Sa108(ref sa108)) => return sa108.exec(&report, self.strict),
+            Some(ReportSubCommand::Summary(ref summary)) => return summary.exec(&report),
+            None => (),
+        }
+        let gains = if date_range {
+            report.gains_between(self.from, self.to)
+        } else {
+            report.gains(self.year)
+        };
 
         log::info!("Disposals {}", gains.len());
         log::info!("Proceeds {}", gains.total_proceeds());
-        log::info!("Allowable Costs {}", gains.total_allowable_costs());
+        log::info!("Acquisition Costs {}", gains.total_acquisition_costs());
+        log::info!("Fees {}", gains.total_fees());
         log::info!("Gains {}", gains.total_gain());
-        log::info!("Estimated Liability {}", estimated_liability);
+        log::info!("Gains (excluding losses) {}", gains.total_gains_only());
+        log::info!("Losses {}", gains.total_losses_only());
+
+        let brought_forward = Money::from_decimal(self.losses_brought_forward, GBP);
+        let income = self.income.map(|income| Money::from_decimal(income, GBP));
+        let mut tax = None;
+        if quote_currency != GBP {
+            // the annual exempt amount and rates are only known in GBP
+            if self.all_years {
+                return Err(eyre::eyre!("--all-years requires the GBP currency"));
+            }
+        } else if let Some(year) = self.year {
+            if let Some(ref income) = income {
+                for (band, amount) in uk::income_bands(year, income) {
+                    log::info!("Income in {:?} Rate Band {}", band, amount);
+                }
+            }
+            let summary = table::TaxSummary::calculate(
+                &report,
+                year,
+                brought_forward.clone(),
+                income.as_ref(),
+            );
+
+            log::info!("Taxable Gain {}", summary.taxable_gain);
+            log::info!("Losses Carried Forward {}", summary.losses_carried_forward);
+            log::info!("Estimated Liability {}", summary.estimated_liability);
+            tax = Some(summary);
+        }
+
+        if self.strict {
+            gains.ensure_no_warnings()?;
+        }
+
+        if self.all_years {
+            if self.year.is_some() || self.format != ReportFormat::Table {
+                return Err(eyre::eyre!(
+                    "--all-years can't be combined with --year or a --format other than table"
+                ));
+            }
+            table::write_all_years(
+                &report,
+                &brought_forward,
+                income.as_ref(),
+                &mut io::stdout(),
+            )?;
+            return Ok(());
+        }
+
+        match self.format {
+            ReportFormat::Table => {
+                let summary = table::ReportSummary::new(&gains, tax);
+                summary.write_table(&mut io::stdout())?;
+                Ok(())
+            }
+            ReportFormat::Csv => cgt::TaxEvent::write_csv(gains, io::stdout()),
+            ReportFormat::Disposals => gains.write_disposals_csv(io::stdout()),
+            ReportFormat::Canonical => report.write_canonical(self.year, io::stdout()),
+            ReportFormat::Json => gains.write_json(io::stdout()),
+        }
+    }
+
+    /// The prices of the traded assets in the reporting currency, from the prices file or
+    /// Coingecko
+    fn read_prices(
+        &self,
+        quote_currency: &'static Currency,
+        trades: &[Trade<'static>],
+    ) -> color_eyre::Result<Prices<'static>> {
+        let prices = match self.prices {
+            None => {
+                let fiat = prices::unpriced_fiat(trades, quote_currency);
+                if !fiat.is_empty() {
+                    let codes = fiat.iter().map(|c| c.code).collect::<Vec<_>>();
+                    return Err(eyre::eyre!(
+                        "Exchange rates of {} to {} aren't fetched from Coingecko, pass them in \
+                         a --prices file",
+                        codes.join(", "),
+                        quote_currency.code
+                    ));
+                }
+                let cache = if self.no_cache {
+                    None
+                } else {
+                    CoingeckoCache::default_dir().map(|dir| {
+                        CoingeckoCache::new(dir, Duration::from_secs(self.cache_ttl * 3600))
+                    })
+                };
+                let ids = match self.coingecko_ids {
+                    Some(ref path) => CoingeckoIds::read_csv(File::open(path)?)?,
+                    None => CoingeckoIds::default(),
+                };
+                let assets = prices::coingecko_assets(trades);
+                Prices::from_coingecko_api(quote_currency, &assets, &ids, cache.as_ref())?
+            }
+            Some(ref path) => Prices::read_csv(File::open(path)?)?,
+        }
+        .with_gap_policy(self.price_gap_policy);
+        Ok(prices)
+    }
+
+    /// The config of the calculation, shared by the report and its subcommands
+    fn config(&self, quote_currency: &'static Currency) -> color_eyre::Result<cgt::Config> {
+        let (opening_pools, snapshot_date) = match (&self.opening_pools, &self.from_snapshot) {
+            (Some(_), Some(_)) => {
+                return Err(eyre::eyre!(
+                    "--opening-pools can't be combined with --from-snapshot"
+                ))
+            }
+            (Some(path), None) => (cgt::OpeningPool::read_csv(File::open(path)?)?, None),
+            (None, Some(path)) => {
+                let pools = cgt::OpeningPool::read_csv(File::open(path)?)?;
+                let snapshot_date = pools
+                    .iter()
+                    .map(|pool| pool.acquisition_date)
+                    .max()
+                    .ok_or_else(|| eyre::eyre!("The snapshot has no pools"))?;
+                (pools, Some(snapshot_date))
+            }
+            (None, None) => (Vec::new(), None),
+        };
+        Ok(cgt::Config {
+            matching: if self.pool_only {
+                cgt::MatchingConfig::pool_only()
+            } else {
+                cgt::MatchingConfig {
+                    same_day: !self.no_same_day,
+                    bnb_days: self.bnb_days,
+                }
+            },
+            reporting_currency: quote_currency,
+            dust_threshold: self.dust_threshold,
+            fee_allocation: if self.split_fees {
+                cgt::FeeAllocation::Split
+            } else {
+                cgt::FeeAllocation::Disposal
+            },
+            pool_by_account: self.pool_by_account,
+            stablecoin_peg: self.stablecoin_peg,
+            opening_pools,
+            time_zone: self.time_zone,
+            snapshot_date,
+            look_ahead_tolerance: chrono::Duration::days(self.look_ahead_tolerance.into()),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn subcommand_is_optional() {
+        let report = ReportCommand::from_args(&["report"], &["--txs", "a.csv"]).unwrap();
+        assert_eq!(report.sub, None);
+
+        let report = ReportCommand::from_args(
+            &["report"],
+            &[
+                "--txs",
+                "a.csv",
+                "--currency",
+                "GBP",
+                "sa108",
+                "--year",
+                "2021",
+            ],
+        )
+        .unwrap();
+        assert_eq!(report.currency, "GBP");
+        assert!(matches!(report.sub, Some(ReportSubCommand::Sa108(_))));
+        assert!(
+            ReportCommand::from_args(&["report"], &["--txs", "a.csv", "sa108"]).is_err(),
+            "sa108 requires a --year"
+        );
+
+        let report = ReportCommand::from_args(
+            &["report"],
+            &[
+                "--txs",
+                "a.csv",
+                "--pool-only",
+                "pools",
+                "snapshot",
+                "--year",
+                "2021",
+            ],
+        )
+        .unwrap();
+        assert!(report.pool_only, "pools uses the options of the report");
+        assert!(matches!(report.sub, Some(ReportSubCommand::Pools(_))));
 
-        cgt::TaxEvent::write_csv(gains, io::stdout())
+        let report = ReportCommand::from_args(
+            &["report"],
+            &[
+                "--txs",
+                "a.csv",
+                "--split-fees",
+                "summary",
+                "--year",
+                "2021",
+            ],
+        )
+        .unwrap();
+        assert!(report.split_fees, "summary uses the options of the report");
+        assert!(matches!(report.sub, Some(ReportSubCommand::Summary(_))));
     }
 }
@@ -0,0 +1,168 @@
+use super::{
+    cgt::{Gains, TaxReport, Year},
+    uk,
+};
+use crate::{currencies::GBP, Money};
+use argh::FromArgs;
+use std::fmt;
+
+/// Print the values for the SA108 Capital Gains summary of a tax year
+#[derive(FromArgs, PartialEq, Debug)]
+#[argh(subcommand, name = "sa108")]
+pub struct Sa108Command {
+    /// the tax year e.g. 2021 for 2020/21
+    #[argh(option)]
+    year: Year,
+}
+
+impl Sa108Command {
+    pub fn exec(&self, report: &TaxReport, strict: bool) -> color_eyre::Result<()> {
+        let gains = report.gains(Some(self.year));
+        if strict {
+            gains.ensure_no_warnings()?;
+        }
+        let summary = build_sa108(&gains, self.year, uk::annual_exempt_amount(self.year));
+        print!("{}", summary);
+        Ok(())
+    }
+}
+
+/// The values to enter in the "Other property, assets and gains" section of the SA108 Capital
+/// Gains summary, which is where gains on crypto assets are reported.
+pub struct Sa108Summary<'a> {
+    pub year: Year,
+    /// Number of disposals
+    pub disposals: usize,
+    /// Disposal proceeds
+    pub proceeds: Money<'a>,
+    /// Allowable costs, including incidental costs such as fees
+    pub allowable_costs: Money<'a>,
+    /// Gains in the year, before losses
+    pub gains: Money<'a>,
+    /// Losses in the year
+    pub losses: Money<'a>,
+    /// Net gain after setting off losses of the same year
+    pub net_gain: Money<'a>,
+    /// Annual exempt amount for the year
+    pub annual_exempt_amount: Money<'a>,
+    /// Net gain remaining after the annual exempt amount
+    pub chargeable_gain: Money<'a>,
+}
+
+/// Builds the SA108 summary from the gains of a single tax year
+pub fn build_sa108<'a>(
+    gains: &Gains<'a>,
+    year: Year,
+    annual_exempt_amount: Money<'a>,
+) -> Sa108Summary<'a> {
+    let zero = Money::from_major(0, GBP);
+    let net_gain = gains.total_gains_only() - gains.total_losses_only();
+    let chargeable_gain = if net_gain > annual_exempt_amount {
+        net_gain.clone() - annual_exempt_amount.clone()
+    } else {
+        zero
+    };
+    Sa108Summary {
+        year,
+        disposals: gains.len(),
+        proceeds: gains.total_proceeds(),
+        allowable_costs: gains.total_allowable_costs(),
+        gains: gains.total_gains_only(),
+        losses: gains.total_losses_only(),
+        net_gain,
+        annual_exempt_amount,
+        chargeable_gain,
+    }
+}
+
+impl<'a> fmt::Display for Sa108Summary<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "SA108 Capital Gains summary {}/{:02}",
+            self.year - 1,
+            self.year % 100
+        )?;
+        writeln!(f, "Other property, assets and gains")?;
+        writeln!(f, "  Number of disposals: {}", self.disposals)?;
+        writeln!(f, "  Disposal proceeds: {}", self.proceeds)?;
+        writeln!(
+            f,
+            "  Allowable costs (including purchase price): {}",
+            self.allowable_costs
+        )?;
+        writeln!(f, "  Gains in the year, before losses: {}", self.gains)?;
+        writeln!(f, "  Losses in the year: {}", self.losses)?;
+        writeln!(f, "Net gain: {}", self.net_gain)?;
+        writeln!(f, "Annual exempt amount: {}", self.annual_exempt_amount)?;
+        writeln!(f, "Chargeable gain: {}", self.chargeable_gain)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        cmd::{prices::Prices, report::cgt},
+        money::amount,
+        trades::{fixtures::trade, Trade, TradeKind},
+    };
+    use rust_decimal_macros::dec;
+
+    #[test]
+    fn sa108_boxes_for_worked_example() {
+        let buy = trade(
+            "2018-01-01",
+            TradeKind::Buy,
+            amount("GBP", dec!(10_000)),
+            amount("BTC", dec!(10)),
+        );
+        let gain = Trade {
+            fee: amount("GBP", dec!(100)),
+            ..trade(
+                "2018-05-01",
+                TradeKind::Sell,
+                amount("BTC", dec!(4)),
+                amount("GBP", dec!(8_000)),
+            )
+        };
+        let loss = trade(
+            "2018-07-01",
+            TradeKind::Sell,
+            amount("BTC", dec!(2)),
+            amount("GBP", dec!(1_000)),
+        );
+
+        let prices = Prices::default();
+        let config = cgt::Config::default();
+        let report = cgt::calculate(vec![buy, gain, loss], &prices, &config).unwrap();
+        let gains = report.gains(Some(2019));
+        let sa108 = build_sa108(&gains, 2019, Money::from_major(11_700, GBP));
+
+        assert_eq!(sa108.disposals, 2);
+        assert_eq!(
+            sa108.proceeds.to_string(),
+            Money::from_major(9_000, GBP).to_string()
+        );
+        assert_eq!(
+            sa108.allowable_costs.to_string(),
+            Money::from_major(6_100, GBP).to_string()
+        );
+        assert_eq!(
+            sa108.gains.to_string(),
+            Money::from_major(3_900, GBP).to_string()
+        );
+        assert_eq!(
+            sa108.losses.to_string(),
+            Money::from_major(1_000, GBP).to_string()
+        );
+        assert_eq!(
+            sa108.net_gain.to_string(),
+            Money::from_major(2_900, GBP).to_string()
+        );
+        assert_eq!(
+            sa108.chargeable_gain.to_string(),
+            Money::from_major(0, GBP).to_string()
+        );
+    }
+}
@@ -0,0 +1,312 @@
+use super::{
+    cgt::{Gains, TaxReport, Year},
+    uk,
+};
+use crate::{currencies::GBP, Money};
+use chrono::NaiveDate;
+use std::io::{self, Write};
+
+/// The totals of the disposals in a report, printed as a table by default
+pub struct ReportSummary<'a> {
+    /// Number of disposals
+    pub disposals: usize,
+    pub proceeds: Money<'a>,
+    /// Acquisition costs including their fees, excluding the fees of disposing
+    pub acquisition_costs: Money<'a>,
+    pub fees: Money<'a>,
+    /// Net gain after setting off losses of the same year
+    pub gain: Money<'a>,
+    /// Number of warnings which may make the report incorrect
+    pub warnings: usize,
+    /// The tax due, only known for a single tax year in GBP
+    pub tax: Option<TaxSummary<'a>>,
+}
+
+/// The estimated tax due on the gains of a tax year
+pub struct TaxSummary<'a> {
+    pub year: Year,
+    pub annual_exempt_amount: Money<'a>,
+    /// Gain after losses and the annual exempt amount
+    pub taxable_gain: Money<'a>,
+    pub losses_carried_forward: Money<'a>,
+    pub estimated_liability: Money<'a>,
+    /// The day the CGT rates changed, if some disposals of the year were made on or after it.
+    /// The estimate uses the earlier rates for the whole year.
+    pub rates_changed: Option<NaiveDate>,
+}
+
+impl<'a> TaxSummary<'a> {
+    /// Estimates the tax on the gains of the year in GBP, after setting off the losses of earlier
+    /// years. Without the taxable income the higher rate is assumed.
+    pub fn calculate(
+        report: &TaxReport<'a>,
+        year: Year,
+        losses_brought_forward: Money<'a>,
+        income: Option<&Money<'a>>,
+    ) -> Self {
+        let (taxable_gain, losses_carried_forward) =
+            report.net_gain_after_losses(year, losses_brought_forward);
+        let estimated_liability = match income {
+            Some(income) => uk::cgt_liability(year, income, &taxable_gain),
+            None => taxable_gain.clone() * uk::cgt_rates(year).higher,
+        };
+        let rates_changed = uk::cgt_rates_change(year)
+            .filter(|date| report.gains(Some(year)).any_disposed_from(*date));
+        if let Some(date) = rates_changed {
+            log::warn!(
+                "Disposals on or after {} are taxed at higher rates not in the estimate",
+                date
+            );
+        }
+        TaxSummary {
+            year,
+            annual_exempt_amount: uk::annual_exempt_amount(year),
+            taxable_gain,
+            losses_carried_forward,
+            estimated_liability,
+            rates_changed,
+        }
+    }
+}
+
+impl<'a> ReportSummary<'a> {
+    pub fn new(gains: &Gains<'a>, tax: Option<TaxSummary<'a>>) -> Self {
+        ReportSummary {
+            disposals: gains.len(),
+            proceeds: gains.total_proceeds(),
+            acquisition_costs: gains.total_acquisition_costs(),
+            fees: gains.total_fees(),
+            gain: gains.total_gain(),
+            warnings: gains.warnings().len(),
+            tax,
+        }
+    }
+
+    /// Writes the summary as a two column table, with the tax year as the heading if known
+    pub fn write_table<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        if let Some(ref tax) = self.tax {
+            writeln!(writer, "{}/{:02}", tax.year - 1, tax.year % 100)?;
+        }
+        write_rows(writer, &self.rows())?;
+        if let Some(date) = self.tax.as_ref().and_then(|tax| tax.rates_changed) {
+            writeln!(
+                writer,
+                "The CGT rates rose for disposals on or after {}, the estimate uses the earlier \
+                 rates for the whole year",
+                date
+            )?;
+        }
+        Ok(())
+    }
+
+    fn rows(&self) -> Vec<(&'static str, String)> {
+        let mut rows = vec![
+            ("Disposals", self.disposals.to_string()),
+            ("Proceeds", self.proceeds.to_string()),
+            ("Acquisition Costs", self.acquisition_costs.to_string()),
+            ("Fees", self.fees.to_string()),
+            ("Gain", self.gain.to_string()),
+        ];
+        if let Some(ref tax) = self.tax {
+            rows.push(("Annual Exempt Amount", tax.annual_exempt_amount.to_string()));
+            rows.push(("Taxable Gain", tax.taxable_gain.to_string()));
+            rows.push((
+                "Losses Carried Forward",
+                tax.losses_carried_forward.to_string(),
+            ));
+            rows.push(("Estimated Liability", tax.estimated_liability.to_string()));
+        }
+        rows.push(("Warnings", self.warnings.to_string()));
+        rows
+    }
+}
+
+/// Writes a summary of each tax year with disposals, followed by the totals of all the years.
+///
+/// The losses brought forward are set off against the first years, and the same taxable income
+/// is assumed for every year.
+pub fn write_all_years<W: Write>(
+    report: &TaxReport,
+    losses_brought_forward: &Money,
+    income: Option<&Money>,
+    writer: &mut W,
+) -> io::Result<()> {
+    let mut total_liability = Money::from_major(0, GBP);
+    for year in report.disposal_years() {
+        let tax = TaxSummary::calculate(report, year, losses_brought_forward.clone(), income);
+        total_liability += tax.estimated_liability.clone();
+        ReportSummary::new(&report.gains(Some(year)), Some(tax)).write_table(writer)?;
+        writeln!(writer)?;
+    }
+    writeln!(writer, "Total")?;
+    let mut rows = ReportSummary::new(&report.gains(None), None).rows();
+    rows.insert(
+        rows.len() - 1,
+        ("Estimated Liability", total_liability.to_string()),
+    );
+    write_rows(writer, &rows)
+}
+
+fn write_rows<W: Write>(writer: &mut W, rows: &[(&str, String)]) -> io::Result<()> {
+    let label_width = rows.iter().map(|(label, _)| label.len()).max().unwrap_or(0);
+    let value_width = rows
+        .iter()
+        .map(|(_, value)| value.chars().count())
+        .max()
+        .unwrap_or(0);
+    for (label, value) in rows {
+        writeln!(
+            writer,
+            "{:<label_width$}  {:>value_width$}",
+            label,
+            value,
+            label_width = label_width,
+            value_width = value_width
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        cmd::{prices::Prices, report::cgt},
+        money::amount,
+        trades::{fixtures::trade, TradeKind},
+    };
+    use rust_decimal_macros::dec;
+
+    #[test]
+    fn table_has_totals_and_tax() {
+        let trades = vec![
+            trade(
+                "2020-05-01",
+                TradeKind::Buy,
+                amount("GBP", dec!(10_000)),
+                amount("BTC", dec!(1)),
+            ),
+            trade(
+                "2020-09-01",
+                TradeKind::Sell,
+                amount("BTC", dec!(1)),
+                amount("GBP", dec!(30_000)),
+            ),
+        ];
+        let prices = Prices::default();
+        let report = cgt::calculate(trades, &prices, &cgt::Config::default()).unwrap();
+        let gains = report.gains(Some(2021));
+        let tax = TaxSummary::calculate(&report, 2021, Money::from_major(0, GBP), None);
+
+        let mut output = Vec::new();
+        ReportSummary::new(&gains, Some(tax))
+            .write_table(&mut output)
+            .unwrap();
+        let output = String::from_utf8(output).unwrap();
+
+        for expected in &[
+            "2020/21\n",
+            "Proceeds                £30,000.00\n",
+            "£10,000.00",
+            "£20,000.00",
+            "Annual Exempt Amount    £12,300.00\n",
+            "£7,700.00",
+            "£1,540.00",
+        ] {
+            assert!(output.contains(expected), "{} in\n{}", expected, output);
+        }
+    }
+
+    #[test]
+    fn disposals_after_rates_rose_are_noted() {
+        let trades = vec![
+            trade(
+                "2024-05-01",
+                TradeKind::Buy,
+                amount("GBP", dec!(10_000)),
+                amount("BTC", dec!(1)),
+            ),
+            trade(
+                "2024-11-01",
+                TradeKind::Sell,
+                amount("BTC", dec!(1)),
+                amount("GBP", dec!(30_000)),
+            ),
+        ];
+        let prices = Prices::default();
+        let report = cgt::calculate(trades, &prices, &cgt::Config::default()).unwrap();
+        let tax = TaxSummary::calculate(&report, 2025, Money::from_major(0, GBP), None);
+
+        assert_eq!(tax.rates_changed, Some(NaiveDate::from_ymd(2024, 10, 30)));
+        let mut output = Vec::new();
+        ReportSummary::new(&report.gains(Some(2025)), Some(tax))
+            .write_table(&mut output)
+            .unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert!(
+            output.contains("rose for disposals on or after 2024-10-30"),
+            "{}",
+            output
+        );
+    }
+
+    #[test]
+    fn all_years_has_summary_of_each_year_with_disposals() {
+        let buy = |date, asset, cost, quantity| {
+            trade(
+                date,
+                TradeKind::Buy,
+                amount("GBP", cost),
+                amount(asset, quantity),
+            )
+        };
+        let sell = |date, quantity, proceeds| {
+            trade(
+                date,
+                TradeKind::Sell,
+                amount("BTC", quantity),
+                amount("GBP", proceeds),
+            )
+        };
+        let trades = vec![
+            buy("2019-05-01", "BTC", dec!(10_000), dec!(1)),
+            sell("2019-09-01", dec!(0.5), dec!(15_000)),
+            buy("2020-06-01", "ETH", dec!(1_000), dec!(1)),
+            sell("2021-06-01", dec!(0.25), dec!(20_000)),
+            sell("2022-06-01", dec!(0.25), dec!(5_000)),
+        ];
+        let prices = Prices::default();
+        let report = cgt::calculate(trades, &prices, &cgt::Config::default()).unwrap();
+
+        let mut output = Vec::new();
+        write_all_years(&report, &Money::from_major(0, GBP), None, &mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+
+        let blocks = output.split("\n\n").collect::<Vec<_>>();
+        assert_eq!(blocks.len(), 4, "2020/21 has no disposals:\n{}", output);
+        let value = |block: &str, label: &str| {
+            block
+                .lines()
+                .find(|line| line.starts_with(label))
+                .map(|line| line[label.len()..].trim().to_string())
+        };
+        let expected = [
+            ("2019/20", "Gain", "£10,000.00", "£0.00"),
+            ("2021/22", "Taxable Gain", "£5,200.00", "£1,040.00"),
+            ("2022/23", "Gain", "£2,500.00", "£0.00"),
+            ("Total", "Gain", "£30,000.00", "£1,040.00"),
+        ];
+        for (block, (heading, label, amount, liability)) in blocks.iter().zip(expected.iter()) {
+            assert_eq!(block.lines().next(), Some(*heading));
+            assert_eq!(value(block, label).as_deref(), Some(*amount), "{}", block);
+            assert_eq!(
+                value(block, "Estimated Liability").as_deref(),
+                Some(*liability),
+                "{}",
+                block
+            );
+        }
+        assert_eq!(value(blocks[3], "Disposals").as_deref(), Some("3"));
+        assert_eq!(value(blocks[3], "Proceeds").as_deref(), Some("£40,000.00"));
+    }
+}
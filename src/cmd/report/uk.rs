@@ -0,0 +1,334 @@
+use super::cgt::Year;
+use crate::{currencies::GBP, Money};
+use chrono::{Datelike, Duration, NaiveDate, NaiveDateTime};
+use rust_decimal::Decimal;
+use std::fmt::Debug;
+
+/// CGT annual exempt amounts in GBP, keyed by the calendar year in which the tax year ends e.g.
+/// 2018 is the 2017/18 tax year.
+const ANNUAL_EXEMPT_AMOUNTS: &[(Year, i64)] = &[
+    (2005, 8_200),
+    (2006, 8_500),
+    (2007, 8_800),
+    (2008, 9_200),
+    (2009, 9_600),
+    (2010, 10_100),
+    (2011, 10_100),
+    (2012, 10_600),
+    (2013, 10_600),
+    (2014, 10_900),
+    (2015, 11_000),
+    (2016, 11_100),
+    (2017, 11_100),
+    (2018, 11_300),
+    (2019, 11_700),
+    (2020, 12_000),
+    (2021, 12_300),
+    (2022, 12_300),
+    (2023, 12_300),
+    (2024, 6_000),
+    (2025, 3_000),
+    (2026, 3_000),
+];
+
+/// Size of the basic rate income tax band in GBP, keyed by the calendar year in which the tax
+/// year ends.
+const BASIC_RATE_BANDS: &[(Year, i64)] = &[
+    (2009, 34_800),
+    (2010, 37_400),
+    (2011, 37_400),
+    (2012, 35_000),
+    (2013, 34_370),
+    (2014, 32_010),
+    (2015, 31_865),
+    (2016, 31_785),
+    (2017, 32_000),
+    (2018, 33_500),
+    (2019, 34_500),
+    (2020, 37_500),
+    (2021, 37_500),
+    (2022, 37_700),
+    (2023, 37_700),
+    (2024, 37_700),
+    (2025, 37_700),
+    (2026, 37_700),
+];
+
+/// The taxable income, after the personal allowance, above which the additional rate of income
+/// tax applies, keyed by the calendar year in which the tax year ends. There was no additional
+/// rate before 2010/11.
+const ADDITIONAL_RATE_THRESHOLDS: &[(Year, Option<i64>)] = &[
+    (2009, None),
+    (2010, None),
+    (2011, Some(150_000)),
+    (2012, Some(150_000)),
+    (2013, Some(150_000)),
+    (2014, Some(150_000)),
+    (2015, Some(150_000)),
+    (2016, Some(150_000)),
+    (2017, Some(150_000)),
+    (2018, Some(150_000)),
+    (2019, Some(150_000)),
+    (2020, Some(150_000)),
+    (2021, Some(150_000)),
+    (2022, Some(150_000)),
+    (2023, Some(150_000)),
+    (2024, Some(125_140)),
+    (2025, Some(125_140)),
+    (2026, Some(125_140)),
+];
+
+/// CGT rates in percent for gains within and above the basic rate band, keyed by the calendar
+/// year in which the tax year ends.
+///
+/// The rates changed part way through the 2024/25 tax year, on 30 October 2024. The earlier rates
+/// are used for that whole year, see [`cgt_rates_change`].
+const CGT_RATES: &[(Year, (i64, i64))] = &[
+    (2009, (18, 18)),
+    (2010, (18, 18)),
+    (2011, (18, 28)),
+    (2012, (18, 28)),
+    (2013, (18, 28)),
+    (2014, (18, 28)),
+    (2015, (18, 28)),
+    (2016, (18, 28)),
+    (2017, (10, 20)),
+    (2018, (10, 20)),
+    (2019, (10, 20)),
+    (2020, (10, 20)),
+    (2021, (10, 20)),
+    (2022, (10, 20)),
+    (2023, (10, 20)),
+    (2024, (10, 20)),
+    (2025, (10, 20)),
+    (2026, (18, 24)),
+];
+
+/// The CGT annual exempt amount for the given UK tax year.
+///
+/// Years outside of the known range use the nearest known value, logging a warning that the
+/// figure may be incorrect.
+pub fn annual_exempt_amount(year: Year) -> Money<'static> {
+    let amount = for_year(ANNUAL_EXEMPT_AMOUNTS, year, "annual exempt amount");
+    Money::from_major(amount, GBP)
+}
+
+/// The size of the basic rate income tax band for the given UK tax year
+pub fn basic_rate_band(year: Year) -> Money<'static> {
+    let amount = for_year(BASIC_RATE_BANDS, year, "basic rate band");
+    Money::from_major(amount, GBP)
+}
+
+/// A band of income tax, outside of Scotland
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TaxBand {
+    Basic,
+    Higher,
+    Additional,
+}
+
+/// Splits taxable income, i.e. after the personal allowance, into the amount falling in each
+/// income tax band of the year, in band order. Bands the income doesn't reach have zero amounts.
+pub fn income_bands<'a>(year: Year, taxable_income: &Money<'a>) -> Vec<(TaxBand, Money<'a>)> {
+    use rust_decimal::prelude::Zero;
+
+    let income = (*taxable_income.amount()).max(Decimal::zero());
+    let basic_rate_band = *basic_rate_band(year).amount();
+    let additional_rate_threshold = for_year(
+        ADDITIONAL_RATE_THRESHOLDS,
+        year,
+        "additional rate threshold",
+    )
+    .map(Decimal::from);
+
+    let basic = income.min(basic_rate_band);
+    let higher = match additional_rate_threshold {
+        Some(threshold) => income.min(threshold) - basic,
+        None => income - basic,
+    }
+    .max(Decimal::zero());
+    let additional = income - basic - higher;
+    vec![
+        (TaxBand::Basic, Money::from_decimal(basic, GBP)),
+        (TaxBand::Higher, Money::from_decimal(higher, GBP)),
+        (TaxBand::Additional, Money::from_decimal(additional, GBP)),
+    ]
+}
+
+/// The CGT rates applying to gains within and above the basic rate band
+#[derive(Clone, Debug, PartialEq)]
+pub struct CgtRates {
+    pub basic: Decimal,
+    pub higher: Decimal,
+}
+
+/// The CGT rates for the given UK tax year
+pub fn cgt_rates(year: Year) -> CgtRates {
+    let (basic, higher) = for_year(CGT_RATES, year, "CGT rates");
+    CgtRates {
+        basic: Decimal::new(basic, 2),
+        higher: Decimal::new(higher, 2),
+    }
+}
+
+/// The day within the tax year from which disposals are taxed at the rates of the next year, if the
+/// rates changed part way through it. Only the earlier rates are applied to the year.
+pub fn cgt_rates_change(year: Year) -> Option<NaiveDate> {
+    if year == 2025 {
+        Some(NaiveDate::from_ymd(2024, 10, 30))
+    } else {
+        None
+    }
+}
+
+/// Estimates the CGT due on a taxable gain, i.e. after losses and the annual exempt amount.
+///
+/// The part of the gain which fits into the basic rate band left unused by the taxable income
+/// (after the personal allowance) is charged at the basic rate, the rest at the higher rate.
+pub fn cgt_liability<'a>(
+    year: Year,
+    taxable_income: &Money<'a>,
+    taxable_gain: &Money<'a>,
+) -> Money<'a> {
+    use rust_decimal::prelude::Zero;
+
+    let rates = cgt_rates(year);
+    let gain = *taxable_gain.amount();
+    let basic_rate_income = income_bands(year, taxable_income)
+        .into_iter()
+        .find(|(band, _)| *band == TaxBand::Basic)
+        .map_or(Decimal::zero(), |(_, income)| *income.amount());
+    let unused_band = *basic_rate_band(year).amount() - basic_rate_income;
+    let basic_rate_gain = gain.min(unused_band).max(Decimal::zero());
+    let higher_rate_gain = (gain - basic_rate_gain).max(Decimal::zero());
+    let liability = basic_rate_gain * rates.basic + higher_rate_gain * rates.higher;
+    Money::from_decimal(liability, GBP)
+}
+
+/// Looks up the value for the tax year, falling back to the nearest known year with a warning
+fn for_year<T>(table: &[(Year, T)], year: Year, name: &str) -> T
+where
+    T: Copy + Debug,
+{
+    let (first_year, first_value) = table[0];
+    let (last_year, last_value) = table[table.len() - 1];
+    if year < first_year {
+        log::warn!(
+            "No {} known for tax year {}, using {:?} from {}",
+            name,
+            year,
+            first_value,
+            first_year
+        );
+        first_value
+    } else if year > last_year {
+        log::warn!(
+            "No {} known for tax year {}, using {:?} from {} which may be stale",
+            name,
+            year,
+            last_value,
+            last_year
+        );
+        last_value
+    } else {
+        table
+            .iter()
+            .find(|(y, _)| *y == year)
+            .map(|(_, value)| *value)
+            .expect("All years within the known range are present")
+    }
+}
+
+/// The UK local time of a UTC time. British Summer Time (UTC+1) runs from 01:00 UTC on the last
+/// Sunday of March until 01:00 UTC on the last Sunday of October, as it has since 1996.
+pub fn local_time(utc: NaiveDateTime) -> NaiveDateTime {
+    let last_sunday = |month: u32| {
+        let last_day = NaiveDate::from_ymd(utc.year(), month + 1, 1).pred();
+        last_day - Duration::days(last_day.weekday().num_days_from_sunday().into())
+    };
+    let bst_start = last_sunday(3).and_hms(1, 0, 0);
+    let bst_end = last_sunday(10).and_hms(1, 0, 0);
+    if utc >= bst_start && utc < bst_end {
+        utc + Duration::hours(1)
+    } else {
+        utc
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn annual_exempt_amount_by_year() {
+        assert_eq!(annual_exempt_amount(2018), Money::from_major(11_300, GBP));
+        assert_eq!(annual_exempt_amount(2023), Money::from_major(12_300, GBP));
+        assert_eq!(annual_exempt_amount(2024), Money::from_major(6_000, GBP));
+        assert_eq!(annual_exempt_amount(2025), Money::from_major(3_000, GBP));
+        assert_eq!(annual_exempt_amount(2040), Money::from_major(3_000, GBP));
+    }
+
+    #[test]
+    fn gain_straddling_basic_rate_band_is_charged_at_blended_rate() {
+        let income = Money::from_major(30_000, GBP);
+        let gain = Money::from_major(10_000, GBP);
+
+        // £7,500 of unused basic rate band at 10%, the remaining £2,500 at 20%
+        let liability = cgt_liability(2020, &income, &gain);
+
+        assert_eq!(liability, Money::from_major(1_250, GBP));
+    }
+
+    #[test]
+    fn gain_above_basic_rate_band_is_charged_at_higher_rate() {
+        let income = Money::from_major(50_000, GBP);
+        let gain = Money::from_major(10_000, GBP);
+
+        let liability = cgt_liability(2020, &income, &gain);
+
+        assert_eq!(liability, Money::from_major(2_000, GBP));
+    }
+
+    #[test]
+    fn income_is_split_between_bands() {
+        let bands = |year, income| {
+            income_bands(year, &Money::from_major(income, GBP))
+                .into_iter()
+                .map(|(band, amount)| (band, amount.to_string()))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            bands(2021, 60_000),
+            vec![
+                (TaxBand::Basic, "£37,500.00".to_string()),
+                (TaxBand::Higher, "£22,500.00".to_string()),
+                (TaxBand::Additional, "£0.00".to_string()),
+            ]
+        );
+        assert_eq!(
+            bands(2024, 130_000),
+            vec![
+                (TaxBand::Basic, "£37,700.00".to_string()),
+                (TaxBand::Higher, "£87,440.00".to_string()),
+                (TaxBand::Additional, "£4,860.00".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn local_time_follows_british_summer_time() {
+        let local = |utc| {
+            local_time(NaiveDateTime::parse_from_str(utc, "%Y-%m-%d %H:%M").unwrap())
+                .format("%Y-%m-%d %H:%M")
+                .to_string()
+        };
+
+        assert_eq!(local("2021-01-15 23:30"), "2021-01-15 23:30");
+        assert_eq!(local("2021-03-28 00:59"), "2021-03-28 00:59");
+        assert_eq!(local("2021-03-28 01:00"), "2021-03-28 02:00");
+        assert_eq!(local("2021-06-01 23:30"), "2021-06-02 00:30");
+        assert_eq!(local("2021-10-31 00:59"), "2021-10-31 01:59");
+        assert_eq!(local("2021-10-31 01:00"), "2021-10-31 01:00");
+    }
+}
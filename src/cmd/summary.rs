@@ -0,0 +1,38 @@
+use crate::cmd::report::cgt;
+use argh::FromArgs;
+use prettytable::{cell, row, Table};
+
+/// Summarise the gains and losses of each asset in a tax year of the report
+#[derive(FromArgs, PartialEq, Debug)]
+#[argh(subcommand, name = "summary")]
+pub struct SummaryCommand {
+    /// the tax year to summarise e.g. 2021 for 2020/21
+    #[argh(option)]
+    year: cgt::Year,
+}
+
+impl SummaryCommand {
+    pub fn exec(&self, report: &cgt::TaxReport) -> color_eyre::Result<()> {
+        let mut table = Table::new();
+        table.set_titles(row![
+            "Asset",
+            "Disposals",
+            "Proceeds",
+            "Costs",
+            "Gain",
+            "Warnings"
+        ]);
+        for asset in report.gains(Some(self.year)).by_asset() {
+            table.add_row(row![
+                asset.asset.code,
+                r->asset.disposals,
+                r->asset.proceeds,
+                r->asset.costs,
+                r->asset.gain,
+                if asset.has_warnings { "yes" } else { "" }
+            ]);
+        }
+        table.printstd();
+        Ok(())
+    }
+}
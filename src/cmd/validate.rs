@@ -0,0 +1,51 @@
+use crate::trades;
+use argh::FromArgs;
+use color_eyre::eyre;
+use std::{fs::File, path::PathBuf};
+
+/// Check transactions csv files, reporting all the invalid trades and any trade in more than one
+/// of the files
+#[derive(FromArgs, PartialEq, Debug)]
+#[argh(subcommand, name = "validate")]
+pub struct ValidateCommand {
+    /// the csv files containing the transactions, e.g. `--txs binance.csv --txs kraken.csv`
+    #[argh(option)]
+    txs: Vec<PathBuf>,
+}
+
+impl ValidateCommand {
+    pub fn exec(&self) -> color_eyre::Result<()> {
+        if self.txs.is_empty() {
+            return Err(eyre::eyre!("At least one transactions file is required"));
+        }
+        let mut invalid_trades = 0;
+        for path in &self.txs {
+            let invalid = trades::validate_csv(File::open(path)?)?;
+            for trade in &invalid {
+                for error in &trade.errors {
+                    if trade.id.is_empty() {
+                        println!("{} line {}: {}", path.display(), trade.line, error);
+                    } else {
+                        println!(
+                            "{} line {} ({}): {}",
+                            path.display(),
+                            trade.line,
+                            trade.id,
+                            error
+                        );
+                    }
+                }
+            }
+            invalid_trades += invalid.len();
+        }
+        if invalid_trades > 0 {
+            return Err(eyre::eyre!("Found {} invalid trades", invalid_trades));
+        }
+        // the same trade in more than one file would be counted twice
+        trades::read_csv_files(&self.txs)?;
+        for path in &self.txs {
+            println!("{} is valid", path.display());
+        }
+        Ok(())
+    }
+}
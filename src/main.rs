@@ -6,7 +6,7 @@ mod trades;
 mod utils;
 
 use argh::FromArgs;
-use cmd::{import::ImportTradesCommand, report::ReportCommand};
+use cmd::{import::ImportTradesCommand, report::ReportCommand, validate::ValidateCommand};
 use money::{currencies, Money};
 
 #[derive(FromArgs, PartialEq, Debug)]
@@ -18,10 +18,12 @@ struct Taxc {
 
 #[derive(FromArgs, PartialEq, Debug)]
 #[argh(subcommand)]
+#[allow(clippy::large_enum_variant)] // parsed once
 /// Calculate UK Capital Gains Tax (CGT)
 enum Command {
     Import(ImportTradesCommand),
     Report(ReportCommand),
+    Validate(ValidateCommand),
 }
 
 impl Command {
@@ -29,6 +31,7 @@ impl Command {
         match self {
             Command::Import(import) => import.exec(),
             Command::Report(report) => report.exec(),
+            Command::Validate(validate) => validate.exec(),
         }
     }
 }
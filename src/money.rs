@@ -130,10 +130,25 @@ define_currency_set!(
             name: "USD Coin",
             symbol: "USDC",
             symbol_first: false,
+        },
+        USDT: {
+            code: "USDT",
+            exponent: 6,
+            locale: EnUs,
+            minor_units: 1_000_000,
+            name: "Tether",
+            symbol: "USDT",
+            symbol_first: false,
         }
     }
 );
 
+/// Government currencies, which are valued with exchange rates rather than pooled as assets
+pub const FIAT: [&str; 3] = ["EUR", "GBP", "USD"];
+
+/// Coins pegged to a fiat currency
+pub const STABLECOINS: [&str; 2] = ["USDC", "USDT"];
+
 // todo: make this return Result instead of panicking
 pub fn amount<'a>(currency: &str, amount: rust_decimal::Decimal) -> crate::Money<'a> {
     let currency =
@@ -161,3 +176,10 @@ pub fn display_amount(amt: &crate::Money) -> String {
     };
     rusty_money::Formatter::money(&amt, params)
 }
+
+/// Formats the amount with exactly the number of decimal places of its currency, without any
+/// symbols or separators.
+pub fn fixed_amount(amt: &crate::Money) -> String {
+    let exponent = amt.currency().exponent;
+    format!("{:.*}", exponent as usize, amt.amount().round_dp(exponent))
+}
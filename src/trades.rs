@@ -1,11 +1,16 @@
 use crate::{
-    money::{currencies::Currency, display_amount, parse_money_parts, zero},
+    money::{
+        currencies::{self, Currency},
+        display_amount, parse_money_parts, zero,
+    },
     Money,
 };
 use chrono::{DateTime, NaiveDate, NaiveDateTime, Utc};
+use color_eyre::eyre;
 use rust_decimal::Decimal;
 use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, io::Read, ops::Add};
+use sha2::{Digest, Sha256};
+use std::{collections::HashMap, convert::TryFrom, fs::File, io::Read, ops::Add, path::Path};
 
 #[derive(Clone)]
 pub struct TradeAmount<'a> {
@@ -33,9 +38,32 @@ pub struct Trade<'a> {
     pub fee: Money<'a>,
     pub rate: Decimal,
     pub exchange: Option<String>,
+    /// The id of the trade in the exchange's export, to trace it back to the source
+    pub source_id: Option<String>,
 }
 
 impl<'a> Trade<'a> {
+    /// Deterministic id derived from the contents of the trade, so re-importing the same data
+    /// always yields the same id.
+    pub fn id(&self) -> String {
+        let kind = match self.kind {
+            TradeKind::Buy => "Buy",
+            TradeKind::Sell => "Sell",
+        };
+        let contents = format!(
+            "{}|{}|{}|{}|{}|{}|{}",
+            self.date_time,
+            kind,
+            self.buy.currency().code,
+            self.buy.amount().normalize(),
+            self.sell.currency().code,
+            self.sell.amount().normalize(),
+            self.exchange.as_deref().unwrap_or(""),
+        );
+        let hash = Sha256::digest(contents.as_bytes());
+        hex::encode(&hash[..8])
+    }
+
     /// Unique key for Trade
     pub fn key(&self) -> TradeKey {
         TradeKey {
@@ -58,39 +86,35 @@ impl<'a> Trade<'a> {
     }
 }
 
-impl<'a> From<TradeRecord> for Trade<'a> {
-    fn from(tr: TradeRecord) -> Self {
-        let date_time = DateTime::parse_from_rfc3339(tr.date_time.as_ref())
-            .expect(format!("Invalid date_time {}", tr.date_time).as_ref())
-            .naive_utc();
-        let exchange = if tr.exchange == "" {
-            None
-        } else {
-            Some(tr.exchange.clone())
-        };
-        let buy = parse_money_parts(&tr.buy_asset, &tr.buy_amount)
-            .expect(format!("BUY amount: {}", tr.buy_amount).as_ref());
-        let sell = parse_money_parts(&tr.sell_asset, &tr.sell_amount)
-            .expect(format!("SELL amount: {}", tr.sell_amount).as_ref());
-        let fee = parse_money_parts(&tr.fee_asset, &tr.fee_amount)
-            .expect(format!("FEE amount: {}", tr.fee_amount).as_ref());
-        let kind = match tr.kind.as_ref() {
-            "Buy" => TradeKind::Buy,
-            "Sell" => TradeKind::Sell,
-            x => panic!("Invalid trade kind {}", x),
-        };
-        Trade {
-            date_time,
-            buy,
-            sell,
-            fee,
-            rate: tr.rate,
-            exchange,
-            kind,
-        }
+impl<'a> TryFrom<TradeRecord> for Trade<'a> {
+    type Error = TradeError;
+
+    fn try_from(tr: TradeRecord) -> Result<Self, Self::Error> {
+        tr.parse().map_err(|mut errors| errors.remove(0))
     }
 }
 
+/// An invalid field in a [`TradeRecord`]
+#[derive(Debug, Clone, PartialEq, derive_more::Display)]
+pub enum TradeError {
+    #[display(fmt = "Invalid record: {}", _0)]
+    InvalidRecord(String),
+    #[display(fmt = "Invalid date_time '{}'", _0)]
+    InvalidDateTime(String),
+    #[display(fmt = "Invalid trade kind '{}', expected Buy or Sell", _0)]
+    InvalidKind(String),
+    #[display(fmt = "Unsupported {} asset '{}'", _0, _1)]
+    UnsupportedAsset(&'static str, String),
+    #[display(fmt = "Invalid {} amount '{}'", _0, _1)]
+    InvalidAmount(&'static str, String),
+    #[display(fmt = "The {} amount must be positive, got '{}'", _0, _1)]
+    NonPositiveAmount(&'static str, String),
+    #[display(fmt = "The fee amount must not be negative, got '{}'", _0)]
+    NegativeFee(String),
+}
+
+impl std::error::Error for TradeError {}
+
 #[derive(Debug, Clone, Eq, PartialEq, Hash)]
 pub enum TradeKind {
     Buy,
@@ -174,6 +198,7 @@ pub fn group_trades_by_day<'a>(trades: &'a [Trade<'a>]) -> Vec<Trade<'a>> {
             Trade {
                 date_time: latest_trade.date_time,
                 exchange: key.exchange.clone(),
+                source_id: None,
                 buy: total_buy,
                 sell: total_sell,
                 fee: total_fee,
@@ -186,6 +211,8 @@ pub fn group_trades_by_day<'a>(trades: &'a [Trade<'a>]) -> Vec<Trade<'a>> {
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TradeRecord {
+    #[serde(default)]
+    pub id: String,
     pub date_time: String,
     pub kind: String,
     pub buy_asset: String,
@@ -196,14 +223,16 @@ pub struct TradeRecord {
     pub fee_amount: String,
     pub rate: Decimal,
     pub exchange: String,
+    #[serde(default)]
+    pub source_id: String,
 }
 
 impl<'a> From<&Trade<'a>> for TradeRecord {
     fn from(trade: &Trade) -> Self {
-        let date_time = DateTime::<Utc>::from_utc(trade.date_time, Utc)
-            .to_rfc3339();
+        let date_time = DateTime::<Utc>::from_utc(trade.date_time, Utc).to_rfc3339();
 
         TradeRecord {
+            id: trade.id(),
             date_time,
             buy_asset: trade.buy.currency().code.to_string(),
             buy_amount: display_amount(&trade.buy),
@@ -213,6 +242,7 @@ impl<'a> From<&Trade<'a>> for TradeRecord {
             fee_amount: display_amount(&trade.fee),
             rate: trade.rate,
             exchange: trade.exchange.clone().unwrap_or(String::new()),
+            source_id: trade.source_id.clone().unwrap_or_default(),
             kind: match &trade.kind {
                 TradeKind::Buy => "Buy",
                 TradeKind::Sell => "Sell",
@@ -222,13 +252,478 @@ impl<'a> From<&Trade<'a>> for TradeRecord {
     }
 }
 
-pub fn read_csv<'a, R>(reader: R) -> color_eyre::Result<Vec<Trade<'a>>>
+impl TradeRecord {
+    /// Parses the record into a trade, checking every field so that all the errors are returned
+    /// rather than just the first.
+    fn parse<'a>(&self) -> Result<Trade<'a>, Vec<TradeError>> {
+        let mut errors = Vec::new();
+        let date_time = DateTime::parse_from_rfc3339(&self.date_time)
+            .map(|date_time| date_time.naive_utc())
+            .map_err(|_| errors.push(TradeError::InvalidDateTime(self.date_time.clone())))
+            .ok();
+        let kind = match self.kind.as_ref() {
+            "Buy" => Some(TradeKind::Buy),
+            "Sell" => Some(TradeKind::Sell),
+            kind => {
+                errors.push(TradeError::InvalidKind(kind.to_string()));
+                None
+            }
+        };
+        let mut parse_amount = |field, asset: &str, amount: &str| {
+            if currencies::find(asset).is_none() {
+                errors.push(TradeError::UnsupportedAsset(field, asset.to_string()));
+                return None;
+            }
+            parse_money_parts(asset, amount)
+                .map_err(|_| errors.push(TradeError::InvalidAmount(field, amount.to_string())))
+                .ok()
+        };
+        let buy = parse_amount("buy", &self.buy_asset, &self.buy_amount);
+        let sell = parse_amount("sell", &self.sell_asset, &self.sell_amount);
+        let fee = parse_amount("fee", &self.fee_asset, &self.fee_amount);
+        let mut check_positive = |field, amount: Option<Money<'a>>, raw: &str| {
+            amount.filter(|amount| {
+                let positive = amount.is_positive();
+                if !positive {
+                    errors.push(TradeError::NonPositiveAmount(field, raw.to_string()));
+                }
+                positive
+            })
+        };
+        let buy = check_positive("buy", buy, &self.buy_amount);
+        let sell = check_positive("sell", sell, &self.sell_amount);
+        // a zero fee is common, e.g. when the fee is taken from the bought amount
+        let fee = fee.filter(|fee| {
+            if fee.is_negative() {
+                errors.push(TradeError::NegativeFee(self.fee_amount.clone()));
+            }
+            !fee.is_negative()
+        });
+        let exchange = if self.exchange == "" {
+            None
+        } else {
+            Some(self.exchange.clone())
+        };
+        let source_id = if self.source_id.is_empty() {
+            None
+        } else {
+            Some(self.source_id.clone())
+        };
+        match (date_time, kind, buy, sell, fee) {
+            (Some(date_time), Some(kind), Some(buy), Some(sell), Some(fee)) => Ok(Trade {
+                date_time,
+                buy,
+                sell,
+                fee,
+                rate: self.rate,
+                exchange,
+                source_id,
+                kind,
+            }),
+            _ => Err(errors),
+        }
+    }
+}
+
+/// A trade which failed validation, with the line of the csv file it was read from
+#[derive(Debug)]
+pub struct InvalidTrade {
+    pub line: u64,
+    pub id: String,
+    pub errors: Vec<TradeError>,
+}
+
+/// Reads each row of the csv file as a trade, returning the errors of all the invalid rows.
+fn read_rows<'a, R>(reader: R) -> color_eyre::Result<Vec<Result<Trade<'a>, InvalidTrade>>>
 where
     R: Read,
 {
     let mut rdr = csv::Reader::from_reader(reader);
-    let records: Result<Vec<TradeRecord>, _> = rdr.deserialize::<TradeRecord>().collect();
-    let mut trades: Vec<Trade> = records?.into_iter().map(Into::into).collect();
+    let headers = rdr.headers()?.clone();
+    let mut rows = Vec::new();
+    for row in rdr.records() {
+        let row = row?;
+        let line = row.position().map_or(0, |pos| pos.line());
+        let result = match row.deserialize::<TradeRecord>(Some(&headers)) {
+            Ok(record) => record.parse().map_err(|errors| InvalidTrade {
+                line,
+                id: record.id.clone(),
+                errors,
+            }),
+            Err(err) => Err(InvalidTrade {
+                line,
+                id: String::new(),
+                errors: vec![TradeError::InvalidRecord(err.to_string())],
+            }),
+        };
+        rows.push(result)
+    }
+    Ok(rows)
+}
+
+pub fn read_csv<'a, R>(reader: R) -> color_eyre::Result<Vec<Trade<'a>>>
+where
+    R: Read,
+{
+    let mut trades = Vec::new();
+    for row in read_rows(reader)? {
+        match row {
+            Ok(trade) => trades.push(trade),
+            Err(invalid) => {
+                return Err(eyre::eyre!(
+                    "Invalid trade {} on line {}: {}",
+                    invalid.id,
+                    invalid.line,
+                    invalid.errors[0]
+                ))
+            }
+        }
+    }
     trades.sort_by(|tx1, tx2| tx1.date_time.cmp(&tx2.date_time));
     Ok(trades)
 }
+
+/// Reads the trades of several csv files, e.g. one per account, sorted by date
+pub fn read_csv_files<'a, P: AsRef<Path>>(paths: &[P]) -> color_eyre::Result<Vec<Trade<'a>>> {
+    if paths.is_empty() {
+        return Err(eyre::eyre!("At least one transactions file is required"));
+    }
+    let files = paths
+        .iter()
+        .map(|path| {
+            let path = path.as_ref();
+            let trades = read_csv(File::open(path)?)?;
+            Ok((path.display().to_string(), trades))
+        })
+        .collect::<color_eyre::Result<Vec<_>>>()?;
+    merge(files)
+}
+
+/// Merges the trades of several files, sorted by date.
+///
+/// The same trade in more than one file is an error, since it would be counted twice. Identical
+/// trades within a file are kept, as exchanges can report several identical fills.
+fn merge<'a>(files: Vec<(String, Vec<Trade<'a>>)>) -> color_eyre::Result<Vec<Trade<'a>>> {
+    let mut sources = HashMap::new();
+    let mut trades = Vec::new();
+    for (file, file_trades) in files {
+        for trade in file_trades {
+            let id = trade.id();
+            match sources.get(&id) {
+                Some(source) if *source != file => {
+                    return Err(eyre::eyre!(
+                        "Trade {} at {} is in both {} and {}",
+                        id,
+                        trade.date_time,
+                        source,
+                        file
+                    ))
+                }
+                _ => {
+                    sources.insert(id, file.clone());
+                }
+            }
+            trades.push(trade)
+        }
+    }
+    trades.sort_by(|tx1, tx2| tx1.date_time.cmp(&tx2.date_time));
+    Ok(trades)
+}
+
+/// Combines batches of trades, e.g. overlapping exports of the same account, keeping each trade
+/// only once across the batches. Identical trades within a batch are all kept, as exchanges can
+/// report several identical fills at the same time, as are those with different source ids.
+///
+/// Returns the trades sorted by date, and the number of duplicates removed.
+pub fn dedup<'a>(batches: Vec<Vec<Trade<'a>>>) -> (Vec<Trade<'a>>, usize) {
+    // the number of each trade kept so far, from the batch with the most of them
+    let mut kept: HashMap<(String, String, Option<String>), usize> = HashMap::new();
+    let mut trades = Vec::new();
+    let mut removed = 0;
+    for batch in batches {
+        let mut in_batch: HashMap<(String, String, Option<String>), usize> = HashMap::new();
+        for trade in batch {
+            let key = (trade.id(), trade.fee.to_string(), trade.source_id.clone());
+            let count = in_batch.entry(key.clone()).or_insert(0);
+            *count += 1;
+            let kept = kept.entry(key).or_insert(0);
+            if *count > *kept {
+                *kept = *count;
+                trades.push(trade);
+            } else {
+                removed += 1;
+            }
+        }
+    }
+    trades.sort_by(|tx1, tx2| tx1.date_time.cmp(&tx2.date_time));
+    (trades, removed)
+}
+
+/// Checks every trade in the csv file, returning all the invalid trades.
+pub fn validate_csv<R>(reader: R) -> color_eyre::Result<Vec<InvalidTrade>>
+where
+    R: Read,
+{
+    Ok(read_rows(reader)?
+        .into_iter()
+        .filter_map(Result::err)
+        .collect())
+}
+
+/// Trades for the tests of other modules
+#[cfg(test)]
+pub(crate) mod fixtures {
+    use super::{Trade, TradeKind};
+    use crate::{money::amount, Money};
+    use chrono::NaiveDate;
+    use rust_decimal::Decimal;
+    use rust_decimal_macros::dec;
+
+    /// A trade at noon on the date, `YYYY-MM-DD`, at the rate of its amounts and without a fee
+    pub fn trade<'a>(date: &str, kind: TradeKind, sell: Money<'a>, buy: Money<'a>) -> Trade<'a> {
+        let rate = match kind {
+            TradeKind::Buy => sell.amount() / buy.amount(),
+            TradeKind::Sell => buy.amount() / sell.amount(),
+        };
+        Trade {
+            date_time: NaiveDate::parse_from_str(date, "%Y-%m-%d")
+                .unwrap()
+                .and_hms(12, 0, 0),
+            kind,
+            sell,
+            buy,
+            fee: amount("GBP", dec!(0)),
+            rate,
+            exchange: None,
+            source_id: None,
+        }
+    }
+
+    /// An acquisition of the asset for a cost in GBP
+    pub fn buy<'a>(date: &str, asset: &str, quantity: Decimal, cost: Decimal) -> Trade<'a> {
+        trade(
+            date,
+            TradeKind::Buy,
+            amount("GBP", cost),
+            amount(asset, quantity),
+        )
+    }
+
+    /// A disposal of the asset for proceeds in GBP
+    pub fn sell<'a>(date: &str, asset: &str, quantity: Decimal, proceeds: Decimal) -> Trade<'a> {
+        trade(
+            date,
+            TradeKind::Sell,
+            amount(asset, quantity),
+            amount("GBP", proceeds),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::money::amount;
+    use chrono::NaiveDate;
+    use rust_decimal_macros::dec;
+
+    fn trade<'a>(sell_amount: Decimal) -> Trade<'a> {
+        Trade {
+            date_time: NaiveDate::from_ymd(2020, 1, 1).and_hms(12, 0, 0),
+            kind: TradeKind::Buy,
+            buy: amount("BTC", dec!(0.5)),
+            sell: amount("GBP", sell_amount),
+            fee: amount("GBP", dec!(1.5)),
+            rate: dec!(10000),
+            exchange: Some("Binance".into()),
+            source_id: None,
+        }
+    }
+
+    #[test]
+    fn same_trade_yields_same_id() {
+        assert_eq!(trade(dec!(5000)).id(), trade(dec!(5000)).id());
+        assert_eq!(trade(dec!(5000)).id(), trade(dec!(5000.00)).id());
+    }
+
+    #[test]
+    fn changed_quantity_yields_different_id() {
+        assert_ne!(trade(dec!(5000)).id(), trade(dec!(5001)).id());
+    }
+
+    #[test]
+    fn id_survives_round_trip_through_record() {
+        let original = trade(dec!(5000));
+        let record = TradeRecord::from(&original);
+        let trade = Trade::try_from(record.clone()).unwrap();
+        assert_eq!(record.id, original.id());
+        assert_eq!(trade.id(), original.id());
+    }
+
+    #[test]
+    fn validate_reports_all_invalid_trades() {
+        let csv = "\
+id,date_time,kind,buy_asset,buy_amount,sell_asset,sell_amount,fee_asset,fee_amount,rate,exchange
+a,2020-01-01T12:00:00+00:00,Buy,BTC,0.5,GBP,5000,GBP,1.5,10000,Binance
+b,2020-01-02,Buy,BTC,0.5,GBP,5000,GBP,1.5,10000,Binance
+c,2020-01-03T12:00:00+00:00,Swap,BTC,0.5,GBP,5000,GBP,1.5,10000,Binance
+d,2020-01-04T12:00:00+00:00,Buy,XYZ,0.5,GBP,5000,GBP,1.5,10000,Binance
+";
+        let invalid = validate_csv(csv.as_bytes()).unwrap();
+
+        let errors = invalid
+            .iter()
+            .map(|i| (i.line, i.id.as_str(), i.errors.clone()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            errors,
+            vec![
+                (
+                    3,
+                    "b",
+                    vec![TradeError::InvalidDateTime("2020-01-02".into())]
+                ),
+                (4, "c", vec![TradeError::InvalidKind("Swap".into())]),
+                (
+                    5,
+                    "d",
+                    vec![TradeError::UnsupportedAsset("buy", "XYZ".into())]
+                ),
+            ]
+        );
+        assert!(read_csv(csv.as_bytes()).is_err());
+    }
+
+    #[test]
+    fn unknown_kind_error_lists_valid_kinds() {
+        let csv = "\
+id,date_time,kind,buy_asset,buy_amount,sell_asset,sell_amount,fee_asset,fee_amount,rate,exchange
+a,2020-01-01T12:00:00+00:00,Transfer,BTC,0.5,GBP,5000,GBP,1.5,10000,Binance
+";
+        let err = read_csv(csv.as_bytes()).err().unwrap();
+
+        assert_eq!(
+            err.to_string(),
+            "Invalid trade a on line 2: Invalid trade kind 'Transfer', expected Buy or Sell"
+        );
+    }
+
+    fn validate_amounts(buy: &str, sell: &str, fee: &str) -> Vec<TradeError> {
+        let csv = format!(
+            "id,date_time,kind,buy_asset,buy_amount,sell_asset,sell_amount,fee_asset,fee_amount,rate,exchange\n\
+             a,2020-01-01T12:00:00+00:00,Buy,BTC,{},GBP,{},GBP,{},10000,Binance\n",
+            buy, sell, fee
+        );
+        validate_csv(csv.as_bytes())
+            .unwrap()
+            .into_iter()
+            .flat_map(|invalid| invalid.errors)
+            .collect()
+    }
+
+    #[test]
+    fn negative_quantity_is_rejected() {
+        assert_eq!(
+            validate_amounts("-0.5", "5000", "1.5"),
+            vec![TradeError::NonPositiveAmount("buy", "-0.5".into())]
+        );
+    }
+
+    #[test]
+    fn zero_quantity_is_rejected() {
+        assert_eq!(
+            validate_amounts("0.5", "0", "1.5"),
+            vec![TradeError::NonPositiveAmount("sell", "0".into())]
+        );
+    }
+
+    #[test]
+    fn negative_fee_is_rejected_but_zero_fee_allowed() {
+        assert_eq!(
+            validate_amounts("0.5", "5000", "-1.5"),
+            vec![TradeError::NegativeFee("-1.5".into())]
+        );
+        assert!(validate_amounts("0.5", "5000", "0").is_empty());
+    }
+
+    #[test]
+    fn merges_files_and_rejects_trades_in_both() {
+        let header = "id,date_time,kind,buy_asset,buy_amount,sell_asset,sell_amount,fee_asset,fee_amount,rate,exchange\n";
+        let btc = "a,2020-01-02T12:00:00+00:00,Buy,BTC,0.5,GBP,5000,GBP,1.5,10000,Binance\n";
+        let eth = "b,2020-01-01T12:00:00+00:00,Buy,ETH,2,GBP,400,GBP,1,200,Kraken\n";
+        let read = |rows: &str| read_csv(format!("{}{}", header, rows).as_bytes()).unwrap();
+
+        let trades = merge(vec![
+            ("binance.csv".into(), read(&btc.repeat(2))),
+            ("kraken.csv".into(), read(eth)),
+        ])
+        .unwrap();
+        let assets = trades
+            .iter()
+            .map(|t| t.buy.currency().code)
+            .collect::<Vec<_>>();
+        assert_eq!(assets, vec!["ETH", "BTC", "BTC"]);
+
+        let result = merge(vec![
+            ("binance.csv".into(), read(btc)),
+            ("export.csv".into(), read(btc)),
+        ]);
+        match result {
+            Err(err) => assert!(err.to_string().contains("binance.csv and export.csv")),
+            Ok(_) => panic!("Trade in both files should be an error"),
+        }
+    }
+
+    #[test]
+    fn dedup_removes_trades_repeated_in_later_batches() {
+        let later = Trade {
+            date_time: NaiveDate::from_ymd(2020, 1, 2).and_hms(12, 0, 0),
+            ..trade(dec!(6000))
+        };
+        let (trades, removed) = dedup(vec![
+            vec![trade(dec!(5000)), later.clone()],
+            vec![later.clone(), trade(dec!(7000))],
+        ]);
+
+        let amounts = trades
+            .iter()
+            .map(|t| t.sell.amount().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(amounts, vec!["5000", "7000", "6000"]);
+        assert_eq!(removed, 1);
+    }
+
+    #[test]
+    fn dedup_keeps_identical_trades_within_a_batch() {
+        let (trades, removed) = dedup(vec![
+            vec![trade(dec!(5000)), trade(dec!(5000))],
+            vec![trade(dec!(5000)), trade(dec!(5000)), trade(dec!(5000))],
+        ]);
+
+        assert_eq!(trades.len(), 3, "The most fills in any one export are kept");
+        assert_eq!(removed, 2);
+
+        let with_other_fee = Trade {
+            fee: amount("GBP", dec!(2)),
+            ..trade(dec!(5000))
+        };
+        let (trades, removed) = dedup(vec![vec![trade(dec!(5000))], vec![with_other_fee]]);
+        assert_eq!(trades.len(), 2);
+        assert_eq!(removed, 0);
+
+        let with_source_id = |id: &str| Trade {
+            source_id: Some(id.into()),
+            ..trade(dec!(5000))
+        };
+        let (trades, removed) = dedup(vec![
+            vec![with_source_id("1")],
+            vec![with_source_id("1"), with_source_id("2")],
+        ]);
+        assert_eq!(
+            trades.len(),
+            2,
+            "Fills with different ids are different trades"
+        );
+        assert_eq!(removed, 1);
+    }
+}